@@ -0,0 +1,132 @@
+//! Derive macros for `kv`, these are re-exported by `kv` when the `derive` feature is enabled
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Index, LitStr};
+
+/// Implement `kv::Value` using a serde codec, selected using `#[value(codec = "json")]`
+///
+/// Supported codecs are `json`, `msgpack`, `bincode`, `lexpr` and `cbor`, the matching `kv`
+/// feature must be enabled. The type must implement `serde::Serialize` and
+/// `serde::Deserialize`.
+#[proc_macro_derive(Value, attributes(value))]
+pub fn derive_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    value(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implement `kv::KeyPart` by encoding each field in order, so a `kv::Composite` key made from
+/// the type sorts field by field
+#[proc_macro_derive(KeyPart)]
+pub fn derive_key_part(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    key_part(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn codec(input: &DeriveInput) -> Result<syn::Ident, Error> {
+    let mut codec = None;
+    for attr in &input.attrs {
+        if !attr.path().is_ident("value") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("codec") {
+                let s: LitStr = meta.value()?.parse()?;
+                codec = Some(s);
+                Ok(())
+            } else {
+                Err(meta.error("expected `codec`"))
+            }
+        })?;
+    }
+
+    let codec = match codec {
+        Some(x) => x,
+        None => {
+            return Err(Error::new(
+                Span::call_site(),
+                "missing `#[value(codec = \"...\")]` attribute",
+            ))
+        }
+    };
+
+    let name = match codec.value().as_str() {
+        "json" => "Json",
+        "msgpack" => "Msgpack",
+        "bincode" => "Bincode",
+        "lexpr" => "Lexpr",
+        "cbor" => "Cbor",
+        _ => return Err(Error::new(codec.span(), "unknown codec")),
+    };
+    Ok(format_ident!("{}", name))
+}
+
+fn value(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let codec = codec(&input)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::kv::Value for #name #ty_generics #where_clause {
+            fn to_raw_value(&self) -> ::std::result::Result<::kv::Raw, ::kv::Error> {
+                ::kv::#codec::<Self>::encode(self)
+            }
+
+            fn from_raw_value(r: ::kv::Raw) -> ::std::result::Result<Self, ::kv::Error> {
+                ::kv::#codec::<Self>::decode(&r)
+            }
+        }
+    })
+}
+
+fn key_part(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = match &input.data {
+        Data::Struct(x) => &x.fields,
+        _ => {
+            return Err(Error::new(
+                Span::call_site(),
+                "KeyPart can only be derived for structs",
+            ))
+        }
+    };
+
+    let (encode, decode) = match fields {
+        Fields::Named(f) => {
+            let names: Vec<_> = f.named.iter().map(|x| &x.ident).collect();
+            let types: Vec<_> = f.named.iter().map(|x| &x.ty).collect();
+            (
+                quote!(#(::kv::KeyPart::encode_key(&self.#names, dst);)*),
+                quote!(Self { #(#names: <#types as ::kv::KeyPart>::decode_key(src)?,)* }),
+            )
+        }
+        Fields::Unnamed(f) => {
+            let index: Vec<_> = (0..f.unnamed.len()).map(Index::from).collect();
+            let types: Vec<_> = f.unnamed.iter().map(|x| &x.ty).collect();
+            (
+                quote!(#(::kv::KeyPart::encode_key(&self.#index, dst);)*),
+                quote!(Self(#(<#types as ::kv::KeyPart>::decode_key(src)?,)*)),
+            )
+        }
+        Fields::Unit => (quote!(), quote!(Self)),
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::kv::KeyPart for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_key(&self, dst: &mut ::std::vec::Vec<u8>) {
+                #encode
+            }
+
+            #[allow(unused_variables)]
+            fn decode_key(src: &mut &[u8]) -> ::std::result::Result<Self, ::kv::Error> {
+                ::std::result::Result::Ok(#decode)
+            }
+        }
+    })
+}
@@ -0,0 +1,293 @@
+//! Async wrappers around `Store` and `Bucket`
+//!
+//! Blocking `sled` calls are run on the `tokio` blocking thread pool using `spawn_blocking`,
+//! so these types must be used from within a `tokio` runtime.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::{Config, Error, Event, Item, Key, Transaction, TransactionError, Value};
+
+/// Number of items buffered by `Iter` and `Watch` streams
+const BUFFER: usize = 64;
+
+async fn blocking<T: Send + 'static, F: FnOnce() -> Result<T, Error> + Send + 'static>(
+    f: F,
+) -> Result<T, Error> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(x) => x,
+        Err(e) => Err(Error::Message(format!("Blocking task failed: {}", e))),
+    }
+}
+
+// `sled::Iter` is not `Send`, so iterators are created on the blocking thread
+fn stream<T: Send + 'static, I: Iterator<Item = T>, F: FnOnce() -> I + Send + 'static>(
+    f: F,
+) -> mpsc::Receiver<T> {
+    let (tx, rx) = mpsc::channel(BUFFER);
+    tokio::task::spawn_blocking(move || {
+        for x in f() {
+            if tx.blocking_send(x).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// `sled::Subscriber` blocks until the next event, so watches use a detached thread instead of
+// the blocking pool to avoid holding up runtime shutdown
+fn watch<K: Send + 'static, V: Send + 'static>(w: crate::Watch<K, V>) -> Watch<K, V>
+where
+    crate::Watch<K, V>: Iterator<Item = Result<Event<K, V>, Error>>,
+{
+    let (tx, rx) = mpsc::channel(BUFFER);
+    std::thread::spawn(move || {
+        for x in w {
+            if tx.blocking_send(x).is_err() {
+                break;
+            }
+        }
+    });
+    Watch(rx)
+}
+
+/// Async version of `kv::Store`
+#[derive(Clone)]
+pub struct Store(Arc<crate::Store>);
+
+impl Store {
+    /// Create a new store from the given config
+    pub async fn new(config: Config) -> Result<Store, Error> {
+        let store = blocking(move || crate::Store::new(config)).await?;
+        Ok(Store(Arc::new(store)))
+    }
+
+    /// Get a reference to the underlying synchronous store
+    pub fn sync(&self) -> &crate::Store {
+        &self.0
+    }
+
+    /// Open a new bucket
+    pub async fn bucket<K: Key<'static> + Send + 'static, V: Value + Send + 'static>(
+        &self,
+        name: Option<&str>,
+    ) -> Result<Bucket<K, V>, Error> {
+        let store = self.0.clone();
+        let name = name.map(String::from);
+        let bucket = blocking(move || store.bucket(name.as_deref())).await?;
+        Ok(Bucket(bucket))
+    }
+
+    /// Remove a bucket and all of its contents from the store, returns true if the bucket existed
+    pub async fn drop_bucket<S: Into<String>>(&self, name: S) -> Result<bool, Error> {
+        let store = self.0.clone();
+        let name = name.into();
+        blocking(move || store.drop_bucket(name)).await
+    }
+
+    /// Flush all pending changes to disk, returns the number of bytes flushed
+    pub async fn flush(&self) -> Result<usize, Error> {
+        self.0.flush_async().await
+    }
+
+    /// Get a list of bucket names
+    pub async fn buckets(&self) -> Result<Vec<String>, Error> {
+        let store = self.0.clone();
+        blocking(move || Ok(store.buckets())).await
+    }
+}
+
+/// Async version of `kv::Bucket`
+pub struct Bucket<K: Key<'static>, V: Value>(crate::Bucket<'static, K, V>);
+
+impl<K: Key<'static>, V: Value> Clone for Bucket<K, V> {
+    fn clone(&self) -> Self {
+        Bucket(self.inner())
+    }
+}
+
+impl<K: Key<'static>, V: Value> Bucket<K, V> {
+    // `kv::Bucket` only implements `Clone` when `K` and `V` do
+    fn inner(&self) -> crate::Bucket<'static, K, V> {
+        self.0.handle()
+    }
+}
+
+impl<K: Key<'static> + Send + 'static, V: Value + Send + 'static> Bucket<K, V> {
+    /// Get a reference to the underlying synchronous bucket
+    pub fn sync(&self) -> &crate::Bucket<'static, K, V> {
+        &self.0
+    }
+
+    /// Returns true if the bucket contains the given key
+    pub async fn contains<X: Into<K> + Send + 'static>(&self, key: X) -> Result<bool, Error> {
+        let b = self.inner();
+        blocking(move || b.contains(key)).await
+    }
+
+    /// Get the value associated with the specified key
+    pub async fn get<X: Into<K> + Send + 'static>(&self, key: X) -> Result<Option<V>, Error> {
+        let b = self.inner();
+        blocking(move || b.get(key)).await
+    }
+
+    /// Set the value associated with the specified key to the provided value
+    pub async fn set<X: Into<K> + Send + 'static, Y: Into<V> + Send + 'static>(
+        &self,
+        key: X,
+        value: Y,
+    ) -> Result<(), Error> {
+        let b = self.inner();
+        blocking(move || b.set(key, value)).await
+    }
+
+    /// Remove the value associated with the specified key from the database
+    pub async fn remove<X: Into<K> + Send + 'static>(&self, key: X) -> Result<(), Error> {
+        let b = self.inner();
+        blocking(move || b.remove(key)).await
+    }
+
+    /// Apply batch update
+    pub async fn batch(&self, batch: crate::Batch<K, V>) -> Result<(), Error> {
+        let b = self.inner();
+        blocking(move || b.batch(batch)).await
+    }
+
+    /// Execute a transaction
+    pub async fn transaction<
+        A: Send + 'static,
+        E: From<Error> + Send + 'static,
+        F: Fn(Transaction<K, V, E>) -> Result<A, TransactionError<E>> + Send + 'static,
+    >(
+        &self,
+        f: F,
+    ) -> Result<A, E> {
+        let b = self.inner();
+        match tokio::task::spawn_blocking(move || b.transaction(f)).await {
+            Ok(x) => x,
+            Err(e) => Err(Error::Message(format!("Blocking task failed: {}", e)).into()),
+        }
+    }
+
+    /// Get a stream of keys/values
+    pub fn iter(&self) -> Iter<K, V> {
+        let b = self.inner();
+        Iter(stream(move || b.iter()))
+    }
+
+    /// Get a stream of keys/values within the given bounds
+    pub fn range<
+        X: Clone + Into<K> + Send + 'static,
+        R: std::ops::RangeBounds<X> + Send + 'static,
+    >(
+        &self,
+        range: R,
+    ) -> Iter<K, V> {
+        let b = self.inner();
+        Iter(stream(move || {
+            let (iter, err) = match b.range(range) {
+                Ok(iter) => (Some(iter), None),
+                Err(e) => (None, Some(Err(e))),
+            };
+            err.into_iter().chain(iter.into_iter().flatten())
+        }))
+    }
+
+    /// Get a stream of keys/values with the specified prefix
+    pub fn iter_prefix<X: Into<K> + Send + 'static>(&self, prefix: X) -> Iter<K, V> {
+        let b = self.inner();
+        Iter(stream(move || b.iter_prefix(prefix)))
+    }
+
+    /// Get a stream of updates when a key with the given prefix is changed
+    ///
+    /// The thread used to receive updates exits after the next update once the stream is dropped
+    pub fn watch_prefix<X: Into<K>>(&self, prefix: X) -> Result<Watch<K, V>, Error> {
+        let w = self.0.watch_prefix(prefix)?;
+        Ok(watch(w))
+    }
+
+    /// Get a stream of updates when any key in the bucket is changed
+    pub fn watch_all(&self) -> Result<Watch<K, V>, Error> {
+        let w = self.0.watch_all()?;
+        Ok(watch(w))
+    }
+
+    /// Wait until the specified key has a value, returning it, or until `timeout` has passed,
+    /// returning `None`
+    ///
+    /// This occupies a thread from the blocking pool until it returns
+    pub async fn wait_for<X: Into<K> + Send + 'static>(
+        &self,
+        key: X,
+        timeout: Duration,
+    ) -> Result<Option<V>, Error> {
+        let b = self.inner();
+        blocking(move || b.wait_for(key, timeout)).await
+    }
+
+    /// Flush to disk
+    pub async fn flush(&self) -> Result<usize, Error> {
+        self.0.flush_async().await
+    }
+
+    /// Get the number of items
+    pub async fn len(&self) -> Result<usize, Error> {
+        let b = self.inner();
+        blocking(move || Ok(b.len())).await
+    }
+
+    /// Returns true when there are no items
+    pub async fn is_empty(&self) -> Result<bool, Error> {
+        let b = self.inner();
+        blocking(move || Ok(b.is_empty())).await
+    }
+
+    /// Remove all items
+    pub async fn clear(&self) -> Result<(), Error> {
+        let b = self.inner();
+        blocking(move || b.clear()).await
+    }
+}
+
+/// Stream over Bucket keys and values
+pub struct Iter<K, V>(mpsc::Receiver<Result<Item<K, V>, Error>>);
+
+impl<K, V> Iter<K, V> {
+    /// Get the next item
+    pub async fn next(&mut self) -> Option<Result<Item<K, V>, Error>> {
+        self.0.recv().await
+    }
+}
+
+impl<K, V> Stream for Iter<K, V> {
+    type Item = Result<Item<K, V>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Stream of updates to a Bucket
+pub struct Watch<K, V>(mpsc::Receiver<Result<Event<K, V>, Error>>);
+
+impl<K, V> Watch<K, V> {
+    /// Get the next event
+    pub async fn next(&mut self) -> Option<Result<Event<K, V>, Error>> {
+        self.0.recv().await
+    }
+}
+
+impl<K, V> Stream for Watch<K, V> {
+    type Item = Result<Event<K, V>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
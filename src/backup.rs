@@ -0,0 +1,103 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::Error;
+
+const MAGIC: &[u8; 8] = b"KVBACKUP";
+const VERSION: u32 = 1;
+
+const TAG_END: u8 = 0;
+const TAG_BUCKET: u8 = 1;
+const TAG_ENTRY: u8 = 2;
+
+fn invalid(msg: &str) -> Error {
+    Error::Message(format!("Invalid backup: {}", msg))
+}
+
+fn write_bytes<W: Write>(w: &mut W, x: &[u8]) -> Result<(), Error> {
+    w.write_all(&(x.len() as u64).to_be_bytes())?;
+    w.write_all(x)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let mut n = [0u8; 8];
+    r.read_exact(&mut n)?;
+    let n = u64::from_be_bytes(n);
+    let mut buf = Vec::new();
+    r.take(n).read_to_end(&mut buf)?;
+    if buf.len() as u64 != n {
+        return Err(invalid("unexpected end of input"));
+    }
+    Ok(buf)
+}
+
+pub(crate) fn export<W: Write>(db: &sled::Db, w: W) -> Result<(), Error> {
+    let mut w = BufWriter::new(w);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_be_bytes())?;
+
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        w.write_all(&[TAG_BUCKET])?;
+        write_bytes(&mut w, &name)?;
+
+        for item in tree.iter() {
+            let (k, v) = item?;
+            w.write_all(&[TAG_ENTRY])?;
+            write_bytes(&mut w, &k)?;
+            write_bytes(&mut w, &v)?;
+        }
+    }
+
+    w.write_all(&[TAG_END])?;
+    w.flush()?;
+    Ok(())
+}
+
+pub(crate) fn import<R: Read>(db: &sled::Db, r: R) -> Result<(), Error> {
+    let mut r = BufReader::new(r);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("bad magic"));
+    }
+
+    let mut version = [0u8; 4];
+    r.read_exact(&mut version)?;
+    if u32::from_be_bytes(version) != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+
+    let mut tree = None;
+    loop {
+        let mut tag = [0u8; 1];
+        match r.read_exact(&mut tag) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(invalid("unexpected end of input"))
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        match tag[0] {
+            TAG_END => break,
+            TAG_BUCKET => {
+                let name = read_bytes(&mut r)?;
+                tree = Some(db.open_tree(name)?);
+            }
+            TAG_ENTRY => {
+                let k = read_bytes(&mut r)?;
+                let v = read_bytes(&mut r)?;
+                match &tree {
+                    Some(t) => t.insert(k, v)?,
+                    None => return Err(invalid("entry without bucket")),
+                };
+            }
+            _ => return Err(invalid("unknown record")),
+        }
+    }
+
+    db.flush()?;
+    Ok(())
+}
@@ -0,0 +1,486 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::crypt::Crypt;
+use crate::{ttl, Bucket, Error, Key, Raw};
+
+/// Name of the tree used to store the chunks of large values
+pub(crate) const TREE_NAME: &str = "__kv__blobs";
+
+/// Ids of values whose chunks are being written, shared by every blob bucket in a store so
+/// `BlobBucket::purge_orphaned` doesn't remove them
+pub(crate) type Writers = Arc<Mutex<HashSet<u64>>>;
+
+// Headers start with one of these, inline headers are followed by the value and chunked headers
+// by the blob id, the length of the value and the chunk size
+const INLINE: u8 = 0;
+const CHUNKED: u8 = 1;
+
+enum Header {
+    Inline(Raw),
+    Chunked { id: u64, len: u64, chunk_size: u64 },
+}
+
+fn invalid() -> Error {
+    Error::Message("Invalid blob header".into())
+}
+
+fn decode_u64(x: &[u8]) -> u64 {
+    let mut n = [0u8; 8];
+    n.copy_from_slice(x);
+    u64::from_be_bytes(n)
+}
+
+impl Header {
+    fn encode(&self) -> Raw {
+        match self {
+            Header::Inline(x) => {
+                let mut dst = Vec::with_capacity(1 + x.len());
+                dst.push(INLINE);
+                dst.extend_from_slice(x);
+                dst.into()
+            }
+            Header::Chunked {
+                id,
+                len,
+                chunk_size,
+            } => {
+                let mut dst = Vec::with_capacity(25);
+                dst.push(CHUNKED);
+                dst.extend_from_slice(&id.to_be_bytes());
+                dst.extend_from_slice(&len.to_be_bytes());
+                dst.extend_from_slice(&chunk_size.to_be_bytes());
+                dst.into()
+            }
+        }
+    }
+
+    fn decode(x: &Raw) -> Result<Header, Error> {
+        match x.first() {
+            Some(&INLINE) => Ok(Header::Inline(x[1..].into())),
+            Some(&CHUNKED) if x.len() == 25 => Ok(Header::Chunked {
+                id: decode_u64(&x[1..9]),
+                len: decode_u64(&x[9..17]),
+                chunk_size: decode_u64(&x[17..25]).max(1),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Chunks are stored under the blob id and chunk index, prefixed by the bucket name like TTL keys
+// so dropping the bucket can remove them
+fn chunk_key(id: u64, index: u64) -> [u8; 16] {
+    let mut dst = [0u8; 16];
+    dst[..8].copy_from_slice(&id.to_be_bytes());
+    dst[8..].copy_from_slice(&index.to_be_bytes());
+    dst
+}
+
+// Shared by writers and readers
+#[derive(Clone)]
+struct Chunks {
+    tree: sled::Tree,
+    crypt: Crypt,
+    bucket: Raw,
+    writers: Writers,
+}
+
+impl Chunks {
+    fn get(&self, id: u64, index: u64) -> Result<Option<Raw>, Error> {
+        let key = chunk_key(id, index);
+        match self.tree.get(ttl::key(&self.bucket, &key))? {
+            Some(x) => Ok(Some(self.crypt.decrypt(&key, x)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, id: u64, index: u64, value: &[u8]) -> Result<(), Error> {
+        let key = chunk_key(id, index);
+        let value = self.crypt.encrypt(&key, value.into())?;
+        self.tree.insert(ttl::key(&self.bucket, &key), value)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) -> Result<(), Error> {
+        let prefix = ttl::key(&self.bucket, &id.to_be_bytes());
+        for k in self.tree.scan_prefix(prefix).keys() {
+            self.tree.remove(k?)?;
+        }
+        Ok(())
+    }
+
+    // Remove the chunks referenced by a header that has been replaced or removed
+    fn release(&self, header: Option<Raw>) -> Result<(), Error> {
+        match header.as_ref().map(Header::decode).transpose()? {
+            Some(Header::Chunked { id, .. }) => self.remove(id),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn io_error(e: Error) -> io::Error {
+    match e {
+        Error::IO(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// A bucket for values that are too large to hold in memory, values are read and written using
+/// `BlobReader` and `BlobWriter`
+///
+/// Created using `Store::blob_bucket`. Values up to the chunk size are stored inline in the
+/// bucket, larger values are split into chunks stored separately and the bucket only holds a
+/// small header describing them. A new value only replaces the old one once
+/// `BlobWriter::finish` is called, so readers never see a partially written value.
+pub struct BlobBucket<'a, K: Key<'a>> {
+    bucket: Bucket<'a, K, Raw>,
+    chunks: Chunks,
+    db: sled::Db,
+    chunk_size: usize,
+}
+
+impl<'a, K: Key<'a>> BlobBucket<'a, K> {
+    pub(crate) fn new(
+        bucket: Bucket<'a, K, Raw>,
+        tree: sled::Tree,
+        writers: Writers,
+        db: sled::Db,
+        chunk_size: usize,
+    ) -> BlobBucket<'a, K> {
+        let chunks = Chunks {
+            tree,
+            crypt: bucket.crypt.clone(),
+            bucket: bucket.tree.name(),
+            writers,
+        };
+        BlobBucket {
+            bucket,
+            chunks,
+            db,
+            chunk_size: chunk_size.max(1),
+        }
+    }
+
+    /// Get the underlying bucket, its values are blob headers
+    pub fn bucket(&self) -> &Bucket<'a, K, Raw> {
+        &self.bucket
+    }
+
+    /// Get the size of the chunks new values are split into
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Returns true if the bucket contains the given key
+    pub fn contains<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        self.bucket.contains(key)
+    }
+
+    /// Get the length of the value associated with the specified key
+    pub fn len<X: Into<K>>(&self, key: X) -> Result<Option<u64>, Error> {
+        Ok(self.reader(key)?.map(|x| x.len()))
+    }
+
+    /// Open a reader for the value associated with the specified key
+    pub fn reader<X: Into<K>>(&self, key: X) -> Result<Option<BlobReader>, Error> {
+        let header = match self.bucket.get(key)? {
+            Some(x) => Header::decode(&x)?,
+            None => return Ok(None),
+        };
+        Ok(Some(BlobReader::new(self.chunks.clone(), header)))
+    }
+
+    /// Open a writer that replaces the value associated with the specified key once it is
+    /// finished, dropping the writer without calling `BlobWriter::finish` discards it
+    pub fn writer<X: Into<K>>(&self, key: X) -> BlobWriter<'a, K> {
+        BlobWriter {
+            bucket: self.bucket.handle(),
+            chunks: self.chunks.clone(),
+            db: self.db.clone(),
+            chunk_size: self.chunk_size,
+            key: Some(key.into()),
+            buf: Vec::new(),
+            id: None,
+            index: 0,
+            len: 0,
+        }
+    }
+
+    /// Get the value associated with the specified key, the whole value is read into memory
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<Vec<u8>>, Error> {
+        match self.reader(key)? {
+            Some(mut r) => {
+                let mut dst = Vec::with_capacity(r.len() as usize);
+                r.read_to_end(&mut dst)?;
+                Ok(Some(dst))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value associated with the specified key
+    pub fn set<X: Into<K>>(&self, key: X, value: &[u8]) -> Result<u64, Error> {
+        let mut w = self.writer(key);
+        w.write_all(value)?;
+        w.finish()
+    }
+
+    /// Remove the value associated with the specified key, returns true if it existed
+    pub fn remove<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        let prev = self.bucket.take(key)?;
+        let existed = prev.is_some();
+        self.chunks.release(prev)?;
+        Ok(existed)
+    }
+
+    /// Remove all values and their chunks
+    pub fn clear(&self) -> Result<(), Error> {
+        self.bucket.clear()?;
+        ttl::clear(&self.chunks.tree, &self.chunks.bucket)?;
+        Ok(())
+    }
+
+    /// Remove chunks that aren't referenced by any value, returns the number of chunks removed
+    ///
+    /// Chunks are written before the header that references them and released after it has
+    /// been replaced, so a crash while a value is being written or replaced can leave chunks
+    /// behind. Writers can't start or finish while this is running.
+    pub fn purge_orphaned(&self) -> Result<usize, Error> {
+        let writers = self.chunks.writers.lock()?;
+        let mut live = HashSet::new();
+        for header in self.bucket.values() {
+            if let Header::Chunked { id, .. } = Header::decode(&header?)? {
+                live.insert(id);
+            }
+        }
+
+        let prefix = ttl::key(&self.chunks.bucket, &[]);
+        let mut n = 0;
+        for k in self.chunks.tree.scan_prefix(&prefix).keys() {
+            let k = k?;
+            let id = match k.get(prefix.len()..prefix.len() + 8) {
+                Some(x) => decode_u64(x),
+                None => continue,
+            };
+            if !live.contains(&id) && !writers.contains(&id) {
+                self.chunks.tree.remove(k)?;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Writes a value to a `BlobBucket`, see `BlobBucket::writer`
+///
+/// Chunks are written as soon as they are full so at most one chunk is held in memory.
+pub struct BlobWriter<'a, K: Key<'a>> {
+    bucket: Bucket<'a, K, Raw>,
+    chunks: Chunks,
+    db: sled::Db,
+    chunk_size: usize,
+    key: Option<K>,
+    buf: Vec<u8>,
+    id: Option<u64>,
+    index: u64,
+    len: u64,
+}
+
+impl<'a, K: Key<'a>> BlobWriter<'a, K> {
+    // Store the full chunk at the start of the buffer
+    fn write_chunk(&mut self) -> Result<(), Error> {
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let id = self.db.generate_id()?;
+                self.chunks.writers.lock()?.insert(id);
+                *self.id.insert(id)
+            }
+        };
+        let n = self.buf.len().min(self.chunk_size);
+        self.chunks.set(id, self.index, &self.buf[..n])?;
+        self.buf.drain(..n);
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Store the value, replacing the previous value for the key, returns the length of the value
+    pub fn finish(mut self) -> Result<u64, Error> {
+        let header = match self.id {
+            // Nothing has been written to chunks yet, so the value is small enough to be inline
+            None => Header::Inline(std::mem::take(&mut self.buf).into()),
+            Some(id) => {
+                if !self.buf.is_empty() {
+                    self.write_chunk()?;
+                }
+                Header::Chunked {
+                    id,
+                    len: self.len,
+                    chunk_size: self.chunk_size as u64,
+                }
+            }
+        };
+
+        let key = match self.key.take() {
+            Some(k) => k,
+            None => return Err(invalid()),
+        };
+
+        // The header is only written once every chunk has been, and the chunks stop being
+        // protected from `purge_orphaned` in the same step
+        let mut writers = self.chunks.writers.lock()?;
+        let prev = self.bucket.swap(key, header.encode())?;
+        if let Some(id) = self.id.take() {
+            writers.remove(&id);
+        }
+        drop(writers);
+        self.chunks.release(prev)?;
+        Ok(self.len)
+    }
+}
+
+impl<'a, K: Key<'a>> Write for BlobWriter<'a, K> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        self.len += buf.len() as u64;
+
+        // A chunk is only written once there is more than a chunk of data, a value that fits
+        // exactly in one chunk is stored inline
+        while self.buf.len() > self.chunk_size {
+            self.write_chunk().map_err(io_error)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, K: Key<'a>> Drop for BlobWriter<'a, K> {
+    fn drop(&mut self) {
+        // Chunks of a writer that was never finished aren't referenced by anything
+        if let Some(id) = self.id {
+            let _ = self.chunks.remove(id);
+            if let Ok(mut writers) = self.chunks.writers.lock() {
+                writers.remove(&id);
+            }
+        }
+    }
+}
+
+/// Reads a value from a `BlobBucket`, see `BlobBucket::reader`
+///
+/// Chunks are loaded as they are needed. If the value is replaced or removed while it is being
+/// read then reading the remaining chunks fails with `io::ErrorKind::NotFound`.
+pub struct BlobReader {
+    chunks: Chunks,
+    id: Option<u64>,
+    len: u64,
+    chunk_size: u64,
+    chunk: Raw,
+    index: u64,
+    pos: u64,
+}
+
+impl BlobReader {
+    fn new(chunks: Chunks, header: Header) -> BlobReader {
+        let (id, len, chunk_size, chunk) = match header {
+            Header::Inline(x) => (None, x.len() as u64, (x.len() as u64).max(1), x),
+            Header::Chunked {
+                id,
+                len,
+                chunk_size,
+            } => (Some(id), len, chunk_size, Raw::from(&[][..])),
+        };
+        BlobReader {
+            chunks,
+            id,
+            len,
+            chunk_size,
+            chunk,
+            index: 0,
+            pos: 0,
+        }
+    }
+
+    /// Get the length of the value
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn load(&mut self, index: u64) -> io::Result<()> {
+        let id = match self.id {
+            Some(id) if self.index != index || self.chunk.is_empty() => id,
+            _ => return Ok(()),
+        };
+        match self.chunks.get(id, index).map_err(io_error)? {
+            Some(x) => {
+                self.chunk = x;
+                self.index = index;
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Blob chunk is missing, the value may have been replaced",
+            )),
+        }
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.load(self.pos / self.chunk_size)?;
+        let offset = (self.pos % self.chunk_size) as usize;
+        let n = buf.len().min(self.chunk.len().saturating_sub(offset));
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Blob chunk is shorter than expected",
+            ));
+        }
+
+        buf[..n].copy_from_slice(&self.chunk[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => offset(self.len, n),
+            SeekFrom::Current(n) => offset(self.pos, n),
+        };
+        match pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+fn offset(base: u64, n: i64) -> Option<u64> {
+    match n.cmp(&0) {
+        Ordering::Less => base.checked_sub(n.unsigned_abs()),
+        _ => base.checked_add(n as u64),
+    }
+}
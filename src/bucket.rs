@@ -1,26 +1,89 @@
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, Instant};
 
-use crate::{Error, Key, Raw, Transaction, TransactionError, Value};
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+
+use crate::bulk::BulkInsert;
+use crate::changes;
+use crate::crypt::Crypt;
+use crate::lock::{self, Lock};
+use crate::observer::{Handle, Observer, Operation};
+use crate::page::{Cursor, Page};
+use crate::queue::Queue;
+use crate::scoped::{prefixed, Scoped};
+use crate::transaction::{run_on, stash_abort, unstash_abort, RetryPolicy};
+use crate::ttl;
+use crate::types::merge_operator;
+use crate::{
+    CompareAndSwapError, Error, Integer, Key, Merge, Raw, Transaction, TransactionError, Value,
+    ValueRef,
+};
+
+// How long `Bucket::wait_for` waits before checking the key again
+const WAIT_RETRY: Duration = Duration::from_millis(10);
+
+/// Number of items written per batch by `Bucket::copy_to`
+const COPY_BATCH_SIZE: usize = 1024;
 
 /// Provides typed access to the key/value store
 #[derive(Clone)]
-pub struct Bucket<'a, K: Key<'a>, V: Value>(
-    pub(crate) sled::Tree,
+pub struct Bucket<'a, K: Key<'a>, V: Value> {
+    pub(crate) tree: sled::Tree,
+    pub(crate) ttl: sled::Tree,
+    pub(crate) crypt: Crypt,
+    pub(crate) observer: Observer,
+    pub(crate) changefeed: Option<sled::Tree>,
+    pub(crate) locks: sled::Tree,
+    _handle: Handle,
+    _marker: PhantomData<(K, V, &'a ())>,
+}
+
+/// Key/value pair
+#[derive(Clone)]
+pub struct Item<K, V>(
+    Raw,
+    Raw,
+    Raw, /* bucket */
     PhantomData<K>,
     PhantomData<V>,
-    PhantomData<&'a ()>,
 );
 
-/// Key/value pair
-#[derive(Clone)]
-pub struct Item<K, V>(Raw, Raw, PhantomData<K>, PhantomData<V>);
+/// Owned key/value pair that can be serialized, see `Item::entry`
+///
+/// `Raw` can't implement serde's traits, use `#[serde(with = "kv::serde_raw")]` on your own types
+/// or convert raw keys and values before serializing them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry<K, V> {
+    /// Key
+    pub key: K,
+
+    /// Value
+    pub value: V,
+}
 
 /// Batch update
 #[derive(Clone)]
-pub struct Batch<K, V>(pub(crate) sled::Batch, PhantomData<K>, PhantomData<V>);
+pub struct Batch<K, V>(
+    pub(crate) Vec<(Raw, Option<Raw>)>,
+    pub(crate) Raw,
+    pub(crate) Vec<(Raw, Condition)>,
+    PhantomData<K>,
+    PhantomData<V>,
+);
+
+/// Condition checked against the stored value of a key before a batch is applied
+#[derive(Clone)]
+pub(crate) enum Condition {
+    Absent,
+    Eq(Raw),
+}
 
 /// Subscribe to key updated
-pub struct Watch<K, V>(sled::Subscriber, PhantomData<K>, PhantomData<V>);
+pub struct Watch<K, V>(sled::Subscriber, Crypt, Raw, PhantomData<K>, PhantomData<V>);
 
 /// Event is used to describe the type of update
 pub enum Event<K, V> {
@@ -38,7 +101,10 @@ impl<'a, K: Key<'a>, V> Iterator for Watch<K, V> {
             None => None,
             Some(sled::Event::Insert(k, v)) => {
                 let k: Raw = k.into();
-                Some(Ok(Event::Set(Item(k, v, PhantomData, PhantomData))))
+                match self.1.decrypt(&k, v) {
+                    Ok(v) => Some(Ok(Event::Set(Item::new(k, v, self.2.clone())))),
+                    Err(e) => Some(Err(decode_error::<V>(&self.2, &k, e))),
+                }
             }
             Some(sled::Event::Remove(k)) => {
                 let k: Raw = k.into();
@@ -82,11 +148,22 @@ impl<'a, K: Key<'a>, V: Value> Event<K, V> {
     }
 }
 
+impl<K, V> Item<K, V> {
+    pub(crate) fn new(k: Raw, v: Raw, bucket: Raw) -> Item<K, V> {
+        Item(k, v, bucket, PhantomData, PhantomData)
+    }
+}
+
+impl<K, V: Value> Item<K, V> {
+    fn decode(&self) -> Result<V, Error> {
+        V::from_raw_value(self.1.clone()).map_err(|e| decode_error::<V>(&self.2, &self.0, e))
+    }
+}
+
 impl<'a, K: Key<'a>, V: Value> Item<K, V> {
     /// Get the value associated with the specified key
     pub fn value<T: From<V>>(&'a self) -> Result<T, Error> {
-        let x = V::from_raw_value(self.1.clone())?;
-        Ok(x.into())
+        Ok(self.decode()?.into())
     }
 
     /// Get the value associated with the specified key
@@ -97,10 +174,108 @@ impl<'a, K: Key<'a>, V: Value> Item<K, V> {
         let k = K::from_raw_key(&self.0)?;
         Ok(k.into())
     }
+
+    /// Get the raw key
+    pub fn raw_key(&self) -> &Raw {
+        &self.0
+    }
+
+    /// Get the raw value, this can be used to inspect values that fail to decode
+    pub fn raw_value(&self) -> &Raw {
+        &self.1
+    }
+
+    /// Decode the key and value into an `Entry`
+    pub fn entry<T, U: From<V>>(&'a self) -> Result<Entry<T, U>, Error>
+    where
+        K: Into<T>,
+    {
+        Ok(Entry {
+            key: self.key()?,
+            value: self.value()?,
+        })
+    }
+}
+
+/// Iterator over Bucket keys
+///
+/// Keys are returned as `Raw` because `Key` implementations may borrow from the encoded key, use
+/// `Key::from_raw_key` to decode them
+pub struct Keys(sled::Iter, ttl::Live);
+
+impl Iterator for Keys {
+    type Item = Result<Raw, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.0;
+        self.1.next(|| iter.next()).map(|x| Ok(x?.0))
+    }
+}
+
+impl DoubleEndedIterator for Keys {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.0;
+        self.1.next(|| iter.next_back()).map(|x| Ok(x?.0))
+    }
+}
+
+/// Iterator over Bucket values
+pub struct Values<V>(sled::Iter, Crypt, Raw, ttl::Live, PhantomData<V>);
+
+impl<V: Value> Values<V> {
+    fn value(&self, item: Result<(Raw, Raw), Error>) -> Result<V, Error> {
+        let (k, v) = item?;
+        decode(&self.1, &self.2, &k, v)
+    }
+}
+
+impl<V: Value> Iterator for Values<V> {
+    type Item = Result<V, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.0;
+        let item = self.3.next(|| iter.next())?;
+        Some(self.value(item))
+    }
+}
+
+impl<V: Value> DoubleEndedIterator for Values<V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.0;
+        let item = self.3.next(|| iter.next_back())?;
+        Some(self.value(item))
+    }
 }
 
 /// Iterator over Bucket keys and values
-pub struct Iter<K, V>(sled::Iter, PhantomData<K>, PhantomData<V>);
+///
+/// `Iter` is double-ended, use `rev` to iterate in descending key order
+pub struct Iter<K, V>(
+    sled::Iter,
+    pub(crate) usize,
+    Crypt,
+    Raw,
+    ttl::Live,
+    PhantomData<K>,
+    PhantomData<V>,
+);
+
+impl<K, V: Value> Iter<K, V> {
+    pub(crate) fn new(iter: sled::Iter, crypt: Crypt, bucket: Raw, live: ttl::Live) -> Iter<K, V> {
+        Iter(iter, 0, crypt, bucket, live, PhantomData, PhantomData)
+    }
+
+    // Strip the prefix added by `Scoped` from the key
+    fn item(&self, item: Result<(Raw, Raw), Error>) -> Result<Item<K, V>, Error> {
+        let (k, v) = item?;
+        let v = self
+            .2
+            .decrypt(&k, v)
+            .map_err(|e| decode_error::<V>(&self.3, &k, e))?;
+        let k = if self.1 == 0 { k } else { k[self.1..].into() };
+        Ok(Item::new(k, v, self.3.clone()))
+    }
+}
 
 impl<'a, K, V> Iterator for Iter<K, V>
 where
@@ -110,11 +285,9 @@ where
     type Item = Result<Item<K, V>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            None => None,
-            Some(Err(e)) => Some(Err(e.into())),
-            Some(Ok((k, v))) => Some(Ok(Item(k, v, PhantomData, PhantomData))),
-        }
+        let iter = &mut self.0;
+        let item = self.4.next(|| iter.next())?;
+        Some(self.item(item))
     }
 }
 
@@ -124,170 +297,1340 @@ where
     V: Value,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
-        match self.0.next_back() {
-            None => None,
-            Some(Err(e)) => Some(Err(e.into())),
-            Some(Ok((k, v))) => Some(Ok(Item(k, v, PhantomData, PhantomData))),
-        }
+        let iter = &mut self.0;
+        let item = self.4.next(|| iter.next_back())?;
+        Some(self.item(item))
     }
 }
 
 impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
-    pub(crate) fn new(t: sled::Tree) -> Bucket<'a, K, V> {
-        Bucket(t, PhantomData, PhantomData, PhantomData)
+    pub(crate) fn new(
+        tree: sled::Tree,
+        ttl: sled::Tree,
+        crypt: Crypt,
+        observer: Observer,
+        changefeed: Option<sled::Tree>,
+        locks: sled::Tree,
+    ) -> Bucket<'a, K, V> {
+        Bucket {
+            tree,
+            ttl,
+            crypt,
+            _handle: observer.handle(),
+            observer,
+            changefeed,
+            locks,
+            _marker: PhantomData,
+        }
+    }
+
+    // The derived `Clone` is only implemented when `K` and `V` are `Clone`
+    pub(crate) fn handle(&self) -> Bucket<'a, K, V> {
+        Bucket::new(
+            self.tree.clone(),
+            self.ttl.clone(),
+            self.crypt.clone(),
+            self.observer.clone(),
+            self.changefeed.clone(),
+            self.locks.clone(),
+        )
+    }
+
+    pub(crate) fn encode(&self, key: &[u8], value: &V) -> Result<Raw, Error> {
+        value
+            .to_raw_value()
+            .and_then(|v| self.crypt.encrypt(key, v))
+            .map_err(|e| encode_error::<V>(&self.tree.name(), key, e))
+    }
+
+    pub(crate) fn decode(&self, key: &[u8], value: Raw) -> Result<V, Error> {
+        decode(&self.crypt, &self.tree.name(), key, value)
+    }
+
+    // Iterate over items from `iter`, skipping expired keys
+    pub(crate) fn iter_over(&self, iter: sled::Iter) -> Iter<K, V> {
+        let name = self.tree.name();
+        let live = ttl::Live::new(&self.ttl, name.clone());
+        Iter::new(iter, self.crypt.clone(), name, live)
+    }
+
+    fn item(&self, item: Option<(Raw, Raw)>) -> Result<Option<Item<K, V>>, Error> {
+        match item {
+            None => Ok(None),
+            Some((k, v)) => {
+                let name = self.tree.name();
+                let v = self
+                    .crypt
+                    .decrypt(&k, v)
+                    .map_err(|e| decode_error::<V>(&name, &k, e))?;
+                Ok(Some(Item::new(k, v, name)))
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&self.tree.name()).into_owned()
+    }
+
+    fn unsupported_if_encrypted(&self, op: &str) -> Result<(), Error> {
+        if self.crypt.is_enabled() {
+            return Err(Error::Message(format!(
+                "{} is not supported for encrypted buckets",
+                op
+            )));
+        }
+        Ok(())
+    }
+
+    // Count an attempt at a transaction, reporting every attempt after the first as a retry
+    fn retried(&self, attempts: &Cell<usize>) {
+        if attempts.get() > 0 {
+            self.observer.transaction_retry(&self.tree);
+        }
+        attempts.set(attempts.get() + 1);
+    }
+
+    // Run `f` in a transaction on this bucket, which also records changes in the changefeed
+    // when it is enabled. The expiration times of keys written by the transaction are cleared
+    // once it commits.
+    pub(crate) fn run_transaction<
+        A,
+        E: From<Error>,
+        F: Fn(Transaction<'a, '_, K, V, E>) -> Result<A, TransactionError<E>>,
+    >(
+        &self,
+        prefix: &Raw,
+        f: F,
+    ) -> Result<A, E> {
+        let written = RefCell::new(Vec::new());
+        let log = match &self.changefeed {
+            Some(log) => log,
+            None => {
+                let name = self.tree.name();
+                let result = self.tree.transaction(|t| {
+                    let txn =
+                        Transaction::scoped(t, name.clone(), prefix.clone(), self.crypt.clone());
+                    f(txn.tracked(&written))
+                });
+                let x = match result {
+                    Ok(x) => x,
+                    Err(sled::TransactionError::Abort(x)) => return Err(x),
+                    Err(sled::TransactionError::Storage(e)) => return Err(Error::from(e).into()),
+                };
+                self.clear_ttl(written.into_inner())?;
+                return Ok(x);
+            }
+        };
+
+        let name = self.tree.name();
+        let error = RefCell::new(None);
+        let result = (&self.tree, log).transaction(|(t, l)| {
+            let txn = Transaction::scoped(t, name.clone(), prefix.clone(), self.crypt.clone());
+            stash_abort(&error, f(txn.tracked(&written).logged(l)))
+        });
+        let x = unstash_abort(error, result)?;
+        self.clear_ttl(written.into_inner())?;
+        Ok(x)
+    }
+
+    // Create a transaction on this bucket from part of a transaction over several trees, `logs`
+    // holds the changefeeds of the buckets that have one in order
+    fn view<'b, E: From<Error>>(
+        &self,
+        t: &'b sled::TransactionalTree,
+        logs: &mut std::slice::Iter<'_, &'b sled::TransactionalTree>,
+        written: &'b RefCell<Vec<Raw>>,
+    ) -> Transaction<'a, 'b, K, V, E> {
+        let txn = Transaction::new(t, self.tree.name(), self.crypt.clone()).tracked(written);
+        match self.changefeed.as_ref().and_then(|_| logs.next()) {
+            Some(log) => txn.logged(log),
+            None => txn,
+        }
+    }
+
+    // Remove the expiration times of keys written by a transaction
+    fn clear_ttl(&self, keys: Vec<Raw>) -> Result<(), Error> {
+        if self.ttl.is_empty() {
+            return Ok(());
+        }
+        let name = self.tree.name();
+        for k in keys {
+            self.ttl.remove(ttl::key(&name, &k))?;
+        }
+        Ok(())
+    }
+
+    // Set or remove an encoded key and clear its expiration time, returning the previous value
+    pub(crate) fn write(&self, key: &Raw, value: Option<Raw>) -> Result<Option<Raw>, Error> {
+        if self.changefeed.is_some() {
+            return self.run_transaction(&Raw::default(), |t| t.write(key, value.clone()));
+        }
+
+        // The deadline is removed first so a new value can't be expired using the old one
+        match value {
+            Some(v) => {
+                ttl::remove(&self.tree, &self.ttl, key)?;
+                Ok(self.tree.insert(key, v)?)
+            }
+            None => {
+                let old = self.tree.remove(key)?;
+                ttl::remove(&self.tree, &self.ttl, key)?;
+                Ok(old)
+            }
+        }
+    }
+
+    // Set or remove encoded keys in a single batch, clearing their expiration times
+    pub(crate) fn write_batch(&self, items: &[(Raw, Option<Raw>)]) -> Result<(), Error> {
+        if self.changefeed.is_some() {
+            return self.run_transaction(&Raw::default(), |t| {
+                for (k, v) in items {
+                    t.write(k, v.clone())?;
+                }
+                Ok(())
+            });
+        }
+
+        let mut batch = sled::Batch::default();
+        for (k, v) in items {
+            ttl::remove(&self.tree, &self.ttl, k)?;
+            match v {
+                Some(v) => batch.insert(k, v),
+                None => batch.remove(k),
+            }
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Returns true if the key has expired, removing it from the bucket
+    pub(crate) fn expired(&self, key: &Raw) -> Result<bool, Error> {
+        if !ttl::check(&self.tree, &self.ttl, key)? {
+            return Ok(false);
+        }
+
+        ttl::expire(
+            &self.tree,
+            &self.ttl,
+            self.changefeed.as_ref(),
+            key,
+            ttl::now()?,
+        )?;
+        Ok(true)
     }
 
     /// Returns true if the bucket contains the given key
-    pub fn contains<X: Into<K>>(&'a self, key: X) -> Result<bool, Error> {
-        let v = self.0.contains_key(key.into().to_raw_key()?)?;
-        Ok(v)
+    pub fn contains<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        let key = key.into().to_raw_key()?;
+        self.observer.time(&self.tree, Operation::Contains, || {
+            let v = self.tree.contains_key(&key)?;
+            Ok(v && !self.expired(&key)?)
+        })
     }
 
     /// Get the value associated with the specified key
-    pub fn get<X: Into<K>>(&'a self, key: X) -> Result<Option<V>, Error> {
-        let v = self.0.get(key.into().to_raw_key()?)?;
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<V>, Error> {
+        let key = key.into().to_raw_key()?;
+        self.observer
+            .time(&self.tree, Operation::Get, || self.get_raw(&key))
+    }
+
+    pub(crate) fn get_raw(&self, key: &Raw) -> Result<Option<V>, Error> {
+        let v = self.tree.get(key)?;
 
         match v {
             None => Ok(None),
-            Some(x) => Ok(Some(V::from_raw_value(x)?)),
+            Some(_) if self.expired(key)? => Ok(None),
+            Some(x) => Ok(Some(self.decode(key, x)?)),
         }
     }
 
+    /// Get the encoded value associated with the specified key without decoding it
+    ///
+    /// The stored bytes are reference counted so no copy is made unless the bucket is
+    /// encrypted, use the `ValueRef` methods to deserialize values that borrow from it
+    pub fn get_ref<X: Into<K>>(&self, key: X) -> Result<Option<ValueRef>, Error> {
+        let key = key.into().to_raw_key()?;
+        let v = self.tree.get(&key)?;
+
+        match v {
+            None => Ok(None),
+            Some(_) if self.expired(&key)? => Ok(None),
+            Some(x) => Ok(Some(ValueRef::new(self.crypt.decrypt(&key, x)?))),
+        }
+    }
+
+    /// Get the value associated with the specified key, taking pending writes in `batch` into
+    /// account
+    pub fn get_batched<X: Into<K>>(&self, batch: &Batch<K, V>, key: X) -> Result<Option<V>, Error> {
+        let key = prefixed(&batch.1, key.into().to_raw_key()?);
+        match batch.get_raw(&key)? {
+            Some(v) => Ok(v),
+            None => self.get_raw(&key),
+        }
+    }
+
+    /// Get the values associated with each of the specified keys, in the same order as `keys`
+    pub fn get_many<X: Into<K>, I: IntoIterator<Item = X>>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<V>>, Error> {
+        keys.into_iter().map(|k| self.get(k)).collect()
+    }
+
     /// Set the value associated with the specified key to the provided value
     pub fn set<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
-        let v = value.into().to_raw_value()?;
-        self.0.insert(key.into().to_raw_key()?, v)?;
+        let key = key.into().to_raw_key()?;
+        let v = self.encode(&key, &value.into())?;
+        self.observer.time(&self.tree, Operation::Set, || {
+            self.write(&key, Some(v))?;
+            Ok(())
+        })
+    }
+
+    /// Acquire an advisory lock on the specified key, waiting until it is available. The lock
+    /// is released when the returned `Lock` is dropped or once `lease` has elapsed.
+    ///
+    /// Key locks are separate for each bucket and don't prevent the key from being written, see
+    /// `Lock`
+    pub fn lock_key<X: Into<K>>(&self, key: X, lease: Duration) -> Result<Lock, Error> {
+        let key = ttl::key(&self.tree.name(), &key.into().to_raw_key()?);
+        lock::acquire(&self.locks, key.into(), lease, self.observer.handle())
+    }
+
+    /// Acquire an advisory lock on the specified key, returning `None` if it is still held once
+    /// `timeout` has passed
+    pub fn lock_key_timeout<X: Into<K>>(
+        &self,
+        key: X,
+        lease: Duration,
+        timeout: Duration,
+    ) -> Result<Option<Lock>, Error> {
+        let key = ttl::key(&self.tree.name(), &key.into().to_raw_key()?);
+        lock::acquire_timeout(
+            &self.locks,
+            key.into(),
+            lease,
+            timeout,
+            self.observer.handle(),
+        )
+    }
+
+    /// Acquire an advisory lock on the specified key, returning `None` if it is already held
+    pub fn try_lock_key<X: Into<K>>(&self, key: X, lease: Duration) -> Result<Option<Lock>, Error> {
+        let key = ttl::key(&self.tree.name(), &key.into().to_raw_key()?);
+        lock::try_acquire(&self.locks, key.into(), lease, self.observer.handle())
+    }
+
+    /// Set the value associated with the specified key to the provided value and flush to disk
+    /// before returning
+    ///
+    /// Flushing writes all pending changes to the store, not just this one
+    pub fn set_durable<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
+        self.set(key, value)?;
+        self.flush()?;
         Ok(())
     }
 
+    /// Set the value associated with the specified key to the provided value, the key will
+    /// be removed once `ttl` has elapsed.
+    ///
+    /// Once `ttl` has elapsed the key is treated as removed by every read from the bucket,
+    /// including `get`, `contains`, iterators, `page`, `first`, `last`, `prev_key`, `next_key`,
+    /// `pop_front`, `pop_back` and `len`. The only exception is reading inside a transaction,
+    /// since expiration times can't be read there. An expired key is deleted when it's accessed
+    /// using `get` or `contains`, or by `Store::purge_expired`.
+    pub fn set_with_ttl<X: Into<K>, Y: Into<V>>(
+        &self,
+        key: X,
+        value: Y,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        let v = self.encode(&key, &value.into())?;
+        let ttl_key = ttl::key(&self.tree.name(), &key);
+        let deadline = ttl::deadline(ttl)?;
+
+        let log = match &self.changefeed {
+            Some(log) => log,
+            None => {
+                let res: sled::TransactionResult<(), ()> =
+                    (&self.tree, &self.ttl).transaction(|(t, ttl)| {
+                        t.insert(&key, &v)?;
+                        ttl.insert(ttl_key.as_slice(), &deadline)?;
+                        Ok(())
+                    });
+
+                return match res {
+                    Ok(()) | Err(sled::TransactionError::Abort(())) => Ok(()),
+                    Err(sled::TransactionError::Storage(e)) => Err(e.into()),
+                };
+            }
+        };
+
+        let name = self.tree.name();
+        let error = RefCell::new(None);
+        let res = (&self.tree, &self.ttl, log).transaction(|(t, ttl, l)| {
+            let old = t.insert(&key, &v)?;
+            ttl.insert(ttl_key.as_slice(), &deadline)?;
+            let res = changes::record(l, &name, &key, old.as_deref(), Some(&v));
+            stash_abort(&error, res)
+        });
+        unstash_abort(error, res)
+    }
+
+    // Set an encoded key to a value that has been encoded but not encrypted
+    pub(crate) fn set_raw(&self, key: &Raw, value: Raw) -> Result<(), Error> {
+        let v = self.crypt.encrypt(key, value)?;
+        self.observer.time(&self.tree, Operation::Set, || {
+            self.write(key, Some(v))?;
+            Ok(())
+        })
+    }
+
     /// Remove the value associated with the specified key from the database
     pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), Error> {
-        self.0.remove(key.into().to_raw_key()?)?;
-        Ok(())
+        let key = key.into().to_raw_key()?;
+        self.remove_raw(&key)
+    }
+
+    pub(crate) fn remove_raw(&self, key: &Raw) -> Result<(), Error> {
+        self.observer.time(&self.tree, Operation::Remove, || {
+            self.write(key, None)?;
+            Ok(())
+        })
+    }
+
+    /// Set the value associated with the specified key to the provided value, returning the
+    /// previous value
+    pub fn swap<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<Option<V>, Error> {
+        self.observer.time(&self.tree, Operation::Swap, || {
+            let key = key.into().to_raw_key()?;
+            let v = self.encode(&key, &value.into())?;
+            let expired = ttl::check(&self.tree, &self.ttl, &key)?;
+
+            match self.write(&key, Some(v))? {
+                Some(x) if !expired => Ok(Some(self.decode(&key, x)?)),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// Remove the value associated with the specified key from the database, returning the
+    /// previous value
+    pub fn take<X: Into<K>>(&self, key: X) -> Result<Option<V>, Error> {
+        self.observer.time(&self.tree, Operation::Take, || {
+            let key = key.into().to_raw_key()?;
+            let expired = ttl::check(&self.tree, &self.ttl, &key)?;
+            let prev = self.write(&key, None)?;
+
+            match prev {
+                Some(x) if !expired => Ok(Some(self.decode(&key, x)?)),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// Set the value associated with the specified key to `value` only if the current value is
+    /// `old`, returning a `CompareAndSwapError` with the current value otherwise. An expired value
+    /// is removed before it is compared.
+    ///
+    /// Not supported for encrypted buckets
+    pub fn compare_and_swap<X: Into<K>>(
+        &self,
+        key: X,
+        old: Option<V>,
+        value: Option<V>,
+    ) -> Result<Result<(), CompareAndSwapError<V>>, Error> {
+        self.observer
+            .time(&self.tree, Operation::CompareAndSwap, || {
+                self.unsupported_if_encrypted("compare_and_swap")?;
+                let old = match old {
+                    Some(x) => Some(x.to_raw_value()?),
+                    None => None,
+                };
+                let raw = match &value {
+                    Some(x) => Some(x.to_raw_value()?),
+                    None => None,
+                };
+                let key = key.into().to_raw_key()?;
+                self.expired(&key)?;
+
+                // Nothing is written on a mismatch, so the current value is returned from the
+                // transaction instead of aborting it
+                let current = self.run_transaction(&Raw::default(), |t: Transaction<_, _>| {
+                    let current = t.current(&key)?;
+                    if current != old {
+                        return Ok(Some(current));
+                    }
+                    t.write(&key, raw.clone())?;
+                    Ok(None)
+                })?;
+
+                match current {
+                    None => Ok(Ok(())),
+                    Some(current) => {
+                        let current = match current {
+                            Some(x) => Some(self.decode(&key, x)?),
+                            None => None,
+                        };
+                        Ok(Err(CompareAndSwapError {
+                            current,
+                            proposed: value,
+                        }))
+                    }
+                }
+            })
+    }
+
+    /// Atomically update the value associated with the specified key using `f`, returning the
+    /// previous value. `f` may be called more than once if there is contention, returning `None`
+    /// removes the key
+    pub fn fetch_and_update<X: Into<K>, F: FnMut(Option<V>) -> Option<V>>(
+        &self,
+        key: X,
+        f: F,
+    ) -> Result<Option<V>, Error> {
+        self.observer
+            .time(&self.tree, Operation::FetchAndUpdate, || {
+                let key = key.into().to_raw_key()?;
+                let f = RefCell::new(f);
+                self.expired(&key)?;
+                let prev = self.run_transaction(&Raw::default(), |t| {
+                    let prev = t.current(&key)?;
+                    let x = match &prev {
+                        Some(x) => Some(
+                            self.decode(&key, x.clone())
+                                .map_err(TransactionError::Abort)?,
+                        ),
+                        None => None,
+                    };
+                    let next = match (f.borrow_mut())(x) {
+                        Some(x) => Some(self.encode(&key, &x).map_err(TransactionError::Abort)?),
+                        None => None,
+                    };
+                    t.write(&key, next)?;
+                    Ok(prev)
+                })?;
+
+                match prev {
+                    None => Ok(None),
+                    Some(x) => Ok(Some(self.decode(&key, x)?)),
+                }
+            })
+    }
+
+    /// Set the merge operator used by `merge`
+    pub fn set_merge_operator<M: Merge<V>>(&self) {
+        self.tree.set_merge_operator(merge_operator::<V, M>)
+    }
+
+    /// Merge `value` into the value associated with the specified key using the bucket's merge
+    /// operator, returning the new value
+    ///
+    /// Not supported for encrypted buckets or when the changefeed is enabled, since the merge
+    /// operator is run by sled outside of a transaction
+    pub fn merge<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<Option<V>, Error> {
+        self.unsupported_if_encrypted("merge")?;
+        if self.changefeed.is_some() {
+            return Err(Error::Message(
+                "merge is not supported when the changefeed is enabled".into(),
+            ));
+        }
+        let key = key.into().to_raw_key()?;
+        let name = self.tree.name();
+        let v = value
+            .into()
+            .to_raw_value()
+            .map_err(|e| encode_error::<V>(&name, &key, e))?;
+
+        // The merge operator can't be run in a transaction, so an expired value is removed and
+        // the deadline cleared before merging
+        self.expired(&key)?;
+        ttl::remove(&self.tree, &self.ttl, &key)?;
+        let x = self.tree.merge(&key, v)?;
+
+        match x {
+            None => Ok(None),
+            Some(x) => V::from_raw_value(x)
+                .map(Some)
+                .map_err(|e| decode_error::<V>(&name, &key, e)),
+        }
+    }
+
+    /// Get a view of the bucket where `prefix` is automatically added to and removed from all
+    /// keys
+    pub fn scoped<P: AsRef<[u8]>>(&self, prefix: P) -> Scoped<'a, K, V> {
+        Scoped::new(self.handle(), prefix.as_ref().into())
+    }
+
+    /// Get a view of the bucket as a queue ordered by key
+    pub fn queue(&self) -> Queue<'a, K, V> {
+        Queue::new(self.handle())
     }
 
     /// Get an iterator over keys/values
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(bucket = %self.name()))
+    )]
     pub fn iter(&self) -> Iter<K, V> {
-        Iter(self.0.iter(), PhantomData, PhantomData)
+        self.iter_over(self.tree.iter())
+    }
+
+    /// Get an iterator over keys, values are not decrypted or decoded
+    pub fn keys(&self) -> Keys {
+        Keys(
+            self.tree.iter(),
+            ttl::Live::new(&self.ttl, self.tree.name()),
+        )
+    }
+
+    /// Get an iterator over values, ordered by key
+    pub fn values(&self) -> Values<V> {
+        let name = self.tree.name();
+        let live = ttl::Live::new(&self.ttl, name.clone());
+        Values(
+            self.tree.iter(),
+            self.crypt.clone(),
+            name,
+            live,
+            PhantomData,
+        )
+    }
+
+    /// Get an iterator over keys/values, skipping values that can't be decoded
+    pub fn iter_lossy(&self) -> impl Iterator<Item = Result<Item<K, V>, Error>> {
+        self.iter().filter(|item| match item {
+            Ok(item) => item.decode().is_ok(),
+            Err(_) => true,
+        })
+    }
+
+    /// Get the keys of all values that can't be decoded
+    pub fn scan_corrupt(&self) -> Result<Vec<Raw>, Error> {
+        let mut dst = Vec::new();
+        for item in self.tree.iter() {
+            let (k, v) = item?;
+            if self.decode(&k, v).is_err() {
+                dst.push(k);
+            }
+        }
+        Ok(dst)
     }
 
     /// Get an iterator over keys/values in the specified range
     pub fn iter_range<X: Into<K>>(&self, a: X, b: X) -> Iter<K, V> {
         let a = a.into();
         let b = b.into();
-        Iter(self.0.range(a..b), PhantomData, PhantomData)
+        self.iter_over(self.tree.range(a..b))
+    }
+
+    /// Get an iterator over keys/values within the given bounds
+    pub fn range<X: Clone + Into<K>, R: RangeBounds<X>>(
+        &self,
+        range: R,
+    ) -> Result<Iter<K, V>, Error> {
+        let a = raw_bound(range.start_bound())?;
+        let b = raw_bound(range.end_bound())?;
+        let iter = self.tree.range::<Raw, _>((a, b));
+        Ok(self.iter_over(iter))
+    }
+
+    /// Get an iterator over keys/values whose encoded key starts with the encoded `prefix`
+    ///
+    /// This is `iter_prefix` for keys that have to be encoded using `Key::to_raw_key`, like
+    /// `range` it returns an error if `prefix` can't be encoded
+    pub fn range_prefix<X: Into<K>>(&self, prefix: X) -> Result<Iter<K, V>, Error> {
+        let prefix = prefix.into().to_raw_key()?;
+        Ok(self.iter_over(self.tree.scan_prefix(prefix)))
+    }
+
+    /// Get up to `limit` items ordered by key, starting after `cursor` or at the first key when
+    /// `cursor` is `None`
+    ///
+    /// Returns an error if `limit` is zero, since an empty page couldn't say whether more items
+    /// follow it
+    pub fn page(&self, cursor: Option<&Cursor>, limit: usize) -> Result<Page<K, V>, Error> {
+        if limit == 0 {
+            return Err(Error::Message(
+                "Page limit must be greater than zero".into(),
+            ));
+        }
+        let iter = match cursor {
+            Some(c) => {
+                let start: Bound<&[u8]> = Bound::Excluded(&c.0);
+                self.tree.range::<&[u8], _>((start, Bound::Unbounded))
+            }
+            None => self.tree.iter(),
+        };
+
+        let mut items = Vec::with_capacity(limit);
+        let mut more = false;
+        for item in self.iter_over(iter) {
+            if items.len() == limit {
+                more = true;
+                break;
+            }
+            items.push(item?);
+        }
+
+        let next = match items.last() {
+            Some(x) if more => Some(Cursor(x.0.clone())),
+            _ => None,
+        };
+        Ok(Page { items, next })
     }
 
     /// Iterate over keys/values with the specified prefix
     pub fn iter_prefix<X: Into<K>>(&self, a: X) -> Iter<K, V> {
         let a = a.into();
-        Iter(self.0.scan_prefix(a), PhantomData, PhantomData)
+        self.iter_over(self.tree.scan_prefix(a))
     }
 
-    /// Apply batch update
+    /// Apply batch update, batches created using `Scoped::new_batch` can only be applied to
+    /// their scope
     pub fn batch(&self, batch: Batch<K, V>) -> Result<(), Error> {
-        self.0.apply_batch(batch.0)?;
-        Ok(())
+        self.batch_scoped(&Raw::default(), batch)
+    }
+
+    // Apply a batch created for the scope with the given prefix, its keys are already prefixed
+    pub(crate) fn batch_scoped(&self, prefix: &Raw, batch: Batch<K, V>) -> Result<(), Error> {
+        if batch.1 != *prefix {
+            return Err(Error::Message(
+                "Batch prefix does not match scope prefix".into(),
+            ));
+        }
+
+        self.observer.batch(&self.tree, batch.0.len());
+        self.observer.time(&self.tree, Operation::Batch, || {
+            if !batch.has_conditions() {
+                return self.write_batch(&batch.encrypted(&self.crypt)?);
+            }
+
+            // Expired values are removed first so conditions don't see them, the expiration tree
+            // isn't part of the transaction
+            for k in batch.condition_keys() {
+                self.expired(k)?;
+            }
+            self.run_transaction(prefix, |t| t.apply(&batch))
+        })
     }
 
     /// Get updates when a key with the given prefix is changed
     pub fn watch_prefix<X: Into<K>>(&self, prefix: X) -> Result<Watch<K, V>, Error> {
-        let w = self.0.watch_prefix(prefix.into());
-        Ok(Watch(w, PhantomData, PhantomData))
+        let w = self.tree.watch_prefix(prefix.into());
+        let name = self.tree.name();
+        Ok(Watch(w, self.crypt.clone(), name, PhantomData, PhantomData))
+    }
+
+    /// Get updates when any key in the bucket is changed
+    pub fn watch_all(&self) -> Result<Watch<K, V>, Error> {
+        let w = self.tree.watch_prefix(b"");
+        let name = self.tree.name();
+        Ok(Watch(w, self.crypt.clone(), name, PhantomData, PhantomData))
+    }
+
+    /// Block until the specified key has a value, returning it, or until `timeout` has passed,
+    /// returning `None`. The current value is returned immediately if the key is already set.
+    ///
+    /// The key is checked every few milliseconds on the calling thread, sled's subscribers can't
+    /// wait with a timeout
+    pub fn wait_for<X: Into<K>>(&self, key: X, timeout: Duration) -> Result<Option<V>, Error> {
+        let key = key.into().to_raw_key()?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(v) = self.get_raw(&key)? {
+                return Ok(Some(v));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Ok(None);
+            }
+            std::thread::sleep(remaining.min(WAIT_RETRY));
+        }
     }
 
     /// Execute a transaction
-    pub fn transaction<A, E: From<sled::Error>, F: Fn(Transaction<K, V>) -> Result<A, TransactionError<E>>>(
+    ///
+    /// `f` is retried immediately with no limit on the number of attempts when the transaction
+    /// conflicts with another one, see `transaction_with_retry` to control this
+    pub fn transaction<A, E: From<Error>, F: Fn(Transaction<K, V, E>) -> Result<A, TransactionError<E>>>(
+        &self,
+        f: F,
+    ) -> Result<A, E> {
+        let attempts = Cell::new(0);
+        self.observer.time(&self.tree, Operation::Transaction, || {
+            self.run_transaction(&Raw::default(), |txn| {
+                self.retried(&attempts);
+                f(txn)
+            })
+        })
+    }
+
+    /// Execute a transaction, retrying conflicts according to `policy`
+    ///
+    /// Returns `Error::Conflict` if the transaction still conflicts after the maximum number of
+    /// attempts
+    pub fn transaction_with_retry<
+        A,
+        E: From<Error>,
+        F: Fn(Transaction<K, V, E>) -> Result<A, TransactionError<E>>,
+    >(
         &self,
+        policy: &RetryPolicy,
         f: F,
     ) -> Result<A, E> {
-        let result = self.0.transaction(|t| {
-            let txn = Transaction::new(t);
-            f(txn)
+        let attempts = Cell::new(0);
+        self.observer.time(&self.tree, Operation::Transaction, || {
+            self.run_transaction(&Raw::default(), |txn| {
+                policy.attempt(attempts.get())?;
+                self.retried(&attempts);
+                f(txn)
+            })
+        })
+    }
+
+    /// Execute a transaction across two buckets
+    pub fn transaction2<
+        A,
+        T: Key<'a>,
+        U: Value,
+        E: From<Error>,
+        F: Fn(Transaction<K, V, E>, Transaction<T, U, E>) -> Result<A, TransactionError<E>>,
+    >(
+        &self,
+        other: &Bucket<'a, T, U>,
+        f: F,
+    ) -> Result<A, E> {
+        let error = RefCell::new(None);
+        let written = (RefCell::new(Vec::new()), RefCell::new(Vec::new()));
+        let mut trees = vec![&self.tree, &other.tree];
+        trees.extend(self.changefeed.iter().chain(other.changefeed.iter()));
+        let result = run_on(&trees, |t| {
+            let mut logs = t[2..].iter();
+            let res = f(
+                self.view(t[0], &mut logs, &written.0),
+                other.view(t[1], &mut logs, &written.1),
+            );
+            stash_abort(&error, res)
         });
+        let x = unstash_abort(error, result)?;
+        self.clear_ttl(written.0.into_inner())?;
+        other.clear_ttl(written.1.into_inner())?;
+        Ok(x)
+    }
 
-        match result {
-            Ok(x) => Ok(x),
-            Err(sled::TransactionError::Abort(x)) => Err(x),
-            Err(sled::TransactionError::Storage(e)) => Err(e.into()),
-        }
+    /// Execute a transaction across three buckets
+    pub fn transaction3<
+        A,
+        T: Key<'a>,
+        U: Value,
+        X: Key<'a>,
+        Y: Value,
+        E: From<Error>,
+        F: Fn(
+            Transaction<K, V, E>,
+            Transaction<T, U, E>,
+            Transaction<X, Y, E>,
+        ) -> Result<A, TransactionError<E>>,
+    >(
+        &self,
+        a: &Bucket<'a, T, U>,
+        b: &Bucket<'a, X, Y>,
+        f: F,
+    ) -> Result<A, E> {
+        let error = RefCell::new(None);
+        let written = (
+            RefCell::new(Vec::new()),
+            RefCell::new(Vec::new()),
+            RefCell::new(Vec::new()),
+        );
+        let mut trees = vec![&self.tree, &a.tree, &b.tree];
+        trees.extend(
+            self.changefeed
+                .iter()
+                .chain(a.changefeed.iter())
+                .chain(b.changefeed.iter()),
+        );
+        let result = run_on(&trees, |t| {
+            let mut logs = t[3..].iter();
+            let res = f(
+                self.view(t[0], &mut logs, &written.0),
+                a.view(t[1], &mut logs, &written.1),
+                b.view(t[2], &mut logs, &written.2),
+            );
+            stash_abort(&error, res)
+        });
+        let x = unstash_abort(error, result)?;
+        self.clear_ttl(written.0.into_inner())?;
+        a.clear_ttl(written.1.into_inner())?;
+        b.clear_ttl(written.2.into_inner())?;
+        Ok(x)
     }
 
     /// Get previous key and value in order, if one exists
     pub fn prev_key<X: Into<K>>(&self, key: X) -> Result<Option<Item<K, V>>, Error> {
-        let item = self.0.get_lt(key.into())?;
-        Ok(item.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        let end = Bound::Excluded(key.into().to_raw_key()?);
+        let iter = self.tree.range::<Raw, _>((Bound::Unbounded, end));
+        self.iter_over(iter).next_back().transpose()
     }
 
 
     /// Get next key and value in order, if one exists
     pub fn next_key<X: Into<K>>(&self, key: X) -> Result<Option<Item<K, V>>, Error> {
-        let item = self.0.get_gt(key.into())?;
-        Ok(item.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        let start = Bound::Excluded(key.into().to_raw_key()?);
+        let iter = self.tree.range::<Raw, _>((start, Bound::Unbounded));
+        self.iter_over(iter).next().transpose()
     }
 
     /// Flush to disk
     pub fn flush(&self) -> Result<usize, Error> {
-        Ok(self.0.flush()?)
+        let start = Instant::now();
+        let f = self.tree.flush()?;
+        self.observer.flushed(start, f);
+        Ok(f)
     }
 
     /// Flush to disk
     pub async fn flush_async(&self) -> Result<usize, Error> {
-        let f = self.0.flush_async().await?;
+        let start = Instant::now();
+        let f = self.tree.flush_async().await?;
+        self.observer.flushed(start, f);
         Ok(f)
     }
 
+    /// Get the first item, ordered by key
+    pub fn first(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.iter().next().transpose()
+    }
+
+    /// Get the last item, ordered by key
+    pub fn last(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.iter().next_back().transpose()
+    }
+
     /// Pop the last item
     pub fn pop_back(&self) -> Result<Option<Item<K, V>>, Error> {
-        let x = self.0.pop_max()?;
-        Ok(x.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        self.pop(true)
     }
 
 
     /// Pop the first item
     pub fn pop_front(&self) -> Result<Option<Item<K, V>>, Error> {
-        let x = self.0.pop_min()?;
-        Ok(x.map(|(k, v)| Item(k, v, PhantomData, PhantomData)))
+        self.pop(false)
+    }
+
+    fn pop(&self, back: bool) -> Result<Option<Item<K, V>>, Error> {
+        // Expired keys popped here are discarded, the next key is tried instead
+        if self.changefeed.is_none() {
+            loop {
+                let x = match back {
+                    true => self.tree.pop_max()?,
+                    false => self.tree.pop_min()?,
+                };
+                let expired = match &x {
+                    Some((k, _)) => {
+                        let expired = ttl::check(&self.tree, &self.ttl, k)?;
+                        ttl::remove(&self.tree, &self.ttl, k)?;
+                        expired
+                    }
+                    None => false,
+                };
+                if !expired {
+                    return self.item(x);
+                }
+            }
+        }
+
+        // The removal has to be recorded, so the key is removed in a transaction and another
+        // one is tried if it was already removed by someone else
+        loop {
+            let k = match back {
+                true => self.keys().next_back(),
+                false => self.keys().next(),
+            };
+            let k = match k {
+                Some(k) => k?,
+                None => return Ok(None),
+            };
+            let v = self.run_transaction(&Raw::default(), |t: Transaction<_, _>| {
+                match t.current(&k)? {
+                    Some(_) => t.write(&k, None),
+                    None => Ok(None),
+                }
+            })?;
+            if let Some(v) = v {
+                return self.item(Some((k, v)));
+            }
+        }
     }
 
     /// Get the number of items
+    ///
+    /// This requires scanning the whole bucket. sled 0.31 doesn't keep a count of the items in a
+    /// tree, and keeping one here would mean updating it atomically with every write, including
+    /// batches, merges and keys removed by the expiration sweeper. `CacheBucket::len` and
+    /// `CacheBucket::size_in_bytes` don't scan, since every write to a cache goes through it.
     pub fn len(&self) -> usize {
-        self.0.len()
+        if self.ttl.is_empty() {
+            return self.tree.len();
+        }
+        self.keys().count()
     }
 
     /// Returns true when there are no items
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.keys().next().is_none()
     }
 
     /// Remove all items
     pub fn clear(&self) -> Result<(), Error> {
-        self.0.clear()?;
+        if self.changefeed.is_some() {
+            let mut items = Vec::new();
+            for k in self.tree.iter().keys() {
+                items.push((k?, None));
+            }
+            self.write_batch(&items)?;
+        } else {
+            self.tree.clear()?;
+        }
+        ttl::clear(&self.ttl, &self.tree.name())?;
         Ok(())
     }
 
+    /// Copy all items to `other`, which may belong to another store, returning the number of
+    /// items copied
+    ///
+    /// Values are decoded and converted to `U`, so `other` may use a different value type, such
+    /// as `Compressed<V, _>`, or a different encryption key. Items are written in batches so the
+    /// copy is not atomic. Expired items are skipped and expiration times are not copied.
+    pub fn copy_to<'b, T: Key<'b>, U: Value + From<V>>(
+        &self,
+        other: &Bucket<'b, T, U>,
+    ) -> Result<usize, Error> {
+        let mut batch = Vec::with_capacity(COPY_BATCH_SIZE);
+        let mut n = 0;
+        for item in self.tree.iter() {
+            let (k, v) = item?;
+            if ttl::check(&self.tree, &self.ttl, &k)? {
+                continue;
+            }
+
+            let v = other.encode(&k, &U::from(self.decode(&k, v)?))?;
+            batch.push((k, Some(v)));
+            n += 1;
+            if batch.len() == COPY_BATCH_SIZE {
+                other.write_batch(&batch)?;
+                batch.clear();
+            }
+        }
+        other.write_batch(&batch)?;
+        Ok(n)
+    }
+
+    /// Insert every key/value pair in `items`, returning the number of items written, see
+    /// `bulk_insert_with`
+    pub fn bulk_insert<X: Into<K>, Y: Into<V>, I: IntoIterator<Item = (X, Y)>>(
+        &self,
+        items: I,
+    ) -> Result<usize, Error> {
+        self.bulk_insert_with(&BulkInsert::default(), items)
+    }
+
+    /// Insert every key/value pair in `items` using large batches, returning the number of
+    /// items written
+    ///
+    /// This is intended for initial imports, it is much faster than calling `set` for each item
+    /// but is not atomic: if it fails part way through the batches that were already written
+    /// are kept.
+    pub fn bulk_insert_with<X: Into<K>, Y: Into<V>, I: IntoIterator<Item = (X, Y)>>(
+        &self,
+        options: &BulkInsert,
+        items: I,
+    ) -> Result<usize, Error> {
+        let mut chunk = Vec::with_capacity(options.batch_size);
+        let mut n = 0;
+        for (k, v) in items {
+            let k = k.into().to_raw_key()?;
+            let v = self.encode(&k, &v.into())?;
+            chunk.push((k, v));
+            if chunk.len() == options.batch_size {
+                n += self.load(&mut chunk, options.sort)?;
+            }
+        }
+        n += self.load(&mut chunk, options.sort)?;
+
+        if options.flush {
+            self.flush()?;
+        }
+        Ok(n)
+    }
+
+    // Write and clear a chunk of encoded items for `bulk_insert_with`
+    fn load(&self, chunk: &mut Vec<(Raw, Raw)>, sort: bool) -> Result<usize, Error> {
+        if chunk.is_empty() {
+            return Ok(0);
+        }
+
+        // A stable sort keeps the last value for duplicate keys last
+        if sort {
+            chunk.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+
+        let n = chunk.len();
+        let items: Vec<_> = chunk.drain(..).map(|(k, v)| (k, Some(v))).collect();
+        self.observer.batch(&self.tree, n);
+        self.observer
+            .time(&self.tree, Operation::Batch, || self.write_batch(&items))?;
+        Ok(n)
+    }
+
+    /// Write all keys and values to `w` as newline-delimited JSON records of the form
+    /// `{"key": ..., "value": ...}`. Keys and values are written as strings when they are valid
+    /// UTF-8 and as arrays of bytes otherwise.
+    ///
+    /// Values in encrypted buckets are exported as they are stored, without decrypting them
+    #[cfg(feature = "json-value")]
+    pub fn export_json<W: std::io::Write>(&self, w: W) -> Result<(), Error> {
+        crate::jsonl::export(&self.tree, w)
+    }
+
+    /// Import records written by `export_json`, all records are applied atomically
+    #[cfg(feature = "json-value")]
+    pub fn import_json<R: std::io::Read>(&self, r: R) -> Result<(), Error> {
+        self.write_batch(&crate::jsonl::import(r)?)
+    }
+
     /// CRC32 checksum of all keys and values
     pub fn checksum(&self) -> Result<u32, Error> {
-        Ok(self.0.checksum()?)
+        Ok(self.tree.checksum()?)
+    }
+}
+
+impl<'a, V: Value> Bucket<'a, Integer, V> {
+    /// Add a value using the key after the current last key, starting at 0, returning the new
+    /// key
+    ///
+    /// This is safe to use from multiple threads, keys are never reused unless the last item is
+    /// removed
+    pub fn push<Y: Into<V>>(&self, value: Y) -> Result<Integer, Error> {
+        let value = value.into();
+        loop {
+            // Keys that aren't integers are an error rather than being skipped, since the next key
+            // couldn't be worked out from them
+            let next = match self.tree.iter().keys().next_back() {
+                Some(k) => u128::from(Integer::try_from(k?.as_ref())?)
+                    .checked_add(1)
+                    .ok_or_else(|| Error::Message("Integer keys are exhausted".into()))?,
+                None => 0,
+            };
+            let key = Integer::from(next);
+            let raw = key.to_raw_key()?;
+            let v = self.encode(&raw, &value)?;
+            let inserted = self.run_transaction(&Raw::default(), |t: Transaction<_, _>| {
+                if t.current(&raw)?.is_some() {
+                    return Ok(false);
+                }
+                t.write(&raw, Some(v.clone()))?;
+                Ok(true)
+            })?;
+            if inserted {
+                return Ok(key);
+            }
+        }
+    }
+}
+
+fn raw_bound<'a, K: Key<'a>, X: Clone + Into<K>>(b: Bound<&X>) -> Result<Bound<Raw>, Error> {
+    let b = match b {
+        Bound::Included(x) => Bound::Included(x.clone().into().to_raw_key()?),
+        Bound::Excluded(x) => Bound::Excluded(x.clone().into().to_raw_key()?),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    Ok(b)
+}
+
+// Add the bucket, key and value type to an error from encoding or encrypting a value
+pub(crate) fn encode_error<V>(bucket: &[u8], key: &[u8], e: Error) -> Error {
+    Error::Encode {
+        bucket: String::from_utf8_lossy(bucket).into_owned(),
+        key: key.into(),
+        codec: std::any::type_name::<V>(),
+        source: Box::new(e),
+    }
+}
+
+// Add the bucket, key and value type to an error from decrypting or decoding a value, checksum
+// mismatches only need the key
+pub(crate) fn decode_error<V>(bucket: &[u8], key: &[u8], e: Error) -> Error {
+    match e {
+        Error::ChecksumMismatch { .. } => Error::ChecksumMismatch { key: key.into() },
+        e => Error::Decode {
+            bucket: String::from_utf8_lossy(bucket).into_owned(),
+            key: key.into(),
+            codec: std::any::type_name::<V>(),
+            source: Box::new(e),
+        },
     }
 }
 
+pub(crate) fn decode<V: Value>(
+    crypt: &Crypt,
+    bucket: &[u8],
+    key: &[u8],
+    value: Raw,
+) -> Result<V, Error> {
+    crypt
+        .decrypt(key, value)
+        .and_then(V::from_raw_value)
+        .map_err(|e| decode_error::<V>(bucket, key, e))
+}
+
 impl<'a, K: Key<'a>, V: Value> Batch<K, V> {
     /// Create a new Batch instance
     pub fn new() -> Batch<K, V> {
-        Batch(sled::Batch::default(), PhantomData, PhantomData)
+        Batch(
+            Vec::new(),
+            Raw::default(),
+            Vec::new(),
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    fn key<X: Into<K>>(&self, key: X) -> Result<Raw, Error> {
+        Ok(prefixed(&self.1, key.into().to_raw_key()?))
     }
 
     /// Set the value associated with the specified key to the provided value
     pub fn set<X: Into<K>>(&mut self, key: X, value: &V) -> Result<(), Error> {
         let v = value.to_raw_value()?;
-        self.0.insert(key.into().to_raw_key()?, v);
+        self.0
+            .push((prefixed(&self.1, key.into().to_raw_key()?), Some(v)));
         Ok(())
     }
 
     /// Remove the value associated with the specified key from the database
     pub fn remove<X: Into<K>>(&mut self, key: X) -> Result<(), Error> {
-        self.0.remove(key.into().to_raw_key()?);
+        self.0
+            .push((prefixed(&self.1, key.into().to_raw_key()?), None));
         Ok(())
     }
+
+    /// Set the value associated with the specified key if the key has no value when the batch
+    /// is applied
+    ///
+    /// Conditions are checked against the bucket before any of the batch is applied, if one of
+    /// them fails the batch fails with `Error::ConditionFailed` and nothing is written. A key
+    /// whose TTL has passed has no value, except in a batch applied by `Transaction::batch`,
+    /// which can't read expiration times.
+    pub fn set_if_absent<X: Into<K>, Y: Into<V>>(&mut self, key: X, value: Y) -> Result<(), Error> {
+        let key = self.key(key)?;
+        self.2.push((key.clone(), Condition::Absent));
+        self.0.push((key, Some(value.into().to_raw_value()?)));
+        Ok(())
+    }
+
+    /// Remove the value associated with the specified key if it is equal to `expected` when the
+    /// batch is applied, values are compared using their encoded representation
+    ///
+    /// See `set_if_absent` for how conditions are checked
+    pub fn remove_if_eq<X: Into<K>, Y: Into<V>>(
+        &mut self,
+        key: X,
+        expected: Y,
+    ) -> Result<(), Error> {
+        let key = self.key(key)?;
+        let expected = expected.into().to_raw_value()?;
+        self.2.push((key.clone(), Condition::Eq(expected)));
+        self.0.push((key, None));
+        Ok(())
+    }
+
+    /// Get the pending value for the specified key, returns `None` if the batch doesn't change
+    /// the key and `Some(None)` if the batch removes it
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<Option<V>>, Error> {
+        self.get_raw(&self.key(key)?)
+    }
+
+    // A batch isn't tied to a bucket until it's applied, so errors have no bucket name
+    fn get_raw(&self, key: &[u8]) -> Result<Option<Option<V>>, Error> {
+        match self.0.iter().rev().find(|(k, _)| k == key) {
+            Some((_, Some(v))) => match V::from_raw_value(v.clone()) {
+                Ok(v) => Ok(Some(Some(v))),
+                Err(e) => Err(decode_error::<V>(b"", key, e)),
+            },
+            Some((_, None)) => Ok(Some(None)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) fn check_conditions(
+    conditions: &[(Raw, Condition)],
+    t: &sled::TransactionalTree,
+    crypt: &Crypt,
+) -> Result<(), TransactionError<Error>> {
+    for (k, c) in conditions {
+        let current = match t.get(k)? {
+            Some(v) => Some(crypt.decrypt(k, v).map_err(TransactionError::Abort)?),
+            None => None,
+        };
+        let ok = match c {
+            Condition::Absent => current.is_none(),
+            Condition::Eq(x) => current.as_ref() == Some(x),
+        };
+        if !ok {
+            return Err(TransactionError::Abort(Error::ConditionFailed(k.clone())));
+        }
+    }
+    Ok(())
+}
+
+impl<K, V> Batch<K, V> {
+    pub(crate) fn has_conditions(&self) -> bool {
+        !self.2.is_empty()
+    }
+
+    pub(crate) fn condition_keys(&self) -> impl Iterator<Item = &Raw> {
+        self.2.iter().map(|(k, _)| k)
+    }
+
+    /// Check the conditions in the batch against the values in `t`
+    pub(crate) fn check(
+        &self,
+        t: &sled::TransactionalTree,
+        crypt: &Crypt,
+    ) -> Result<(), TransactionError<Error>> {
+        check_conditions(&self.2, t, crypt)
+    }
+
+    // Values are encrypted once the destination bucket is known
+    pub(crate) fn encrypted(&self, crypt: &Crypt) -> Result<Vec<(Raw, Option<Raw>)>, Error> {
+        let mut dst = Vec::with_capacity(self.0.len());
+        for (k, v) in &self.0 {
+            let v = match v {
+                Some(v) => Some(crypt.encrypt(k, v.clone())?),
+                None => None,
+            };
+            dst.push((k.clone(), v));
+        }
+        Ok(dst)
+    }
+
+    pub(crate) fn to_sled(&self, crypt: &Crypt) -> Result<sled::Batch, Error> {
+        let mut batch = sled::Batch::default();
+        for (k, v) in &self.0 {
+            match v {
+                Some(v) => {
+                    let v = crypt.encrypt(k, v.clone())?;
+                    batch.insert(k, v)
+                }
+                None => batch.remove(k),
+            }
+        }
+        Ok(batch)
+    }
 }
@@ -0,0 +1,50 @@
+/// Options for `Bucket::bulk_insert_with`
+///
+/// By default items are written in batches of 10,000 keys, each batch is sorted by key before it
+/// is written and the bucket is flushed once every item has been written.
+///
+/// sled's background flush interval can't be changed once the store is open, leave
+/// `Config::flush_every_ms` unset when opening a store for a large initial import so that
+/// nothing is flushed until the load is finished.
+#[derive(Debug, Clone)]
+pub struct BulkInsert {
+    pub(crate) batch_size: usize,
+    pub(crate) sort: bool,
+    pub(crate) flush: bool,
+}
+
+impl Default for BulkInsert {
+    fn default() -> BulkInsert {
+        BulkInsert {
+            batch_size: 10_000,
+            sort: true,
+            flush: true,
+        }
+    }
+}
+
+impl BulkInsert {
+    /// Create a new set of options using the defaults
+    pub fn new() -> BulkInsert {
+        BulkInsert::default()
+    }
+
+    /// Write items in batches of `n` keys
+    pub fn batch_size(mut self, n: usize) -> BulkInsert {
+        self.batch_size = n.max(1);
+        self
+    }
+
+    /// Sort each batch by key before writing it, later items still replace earlier items with
+    /// the same key
+    pub fn sort(mut self, sort: bool) -> BulkInsert {
+        self.sort = sort;
+        self
+    }
+
+    /// Flush the bucket once every item has been written
+    pub fn flush(mut self, flush: bool) -> BulkInsert {
+        self.flush = flush;
+        self
+    }
+}
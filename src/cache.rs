@@ -0,0 +1,231 @@
+use crate::observer::Operation;
+use crate::{ttl, Bucket, Error, Key, Raw, Value};
+
+/// Name of the tree used to track cache usage
+pub(crate) const TREE_NAME: &str = "__kv__cache";
+
+// Entries in the cache tree start with the bucket prefix followed by one of these tags
+const STATS: u8 = 0;
+const ORDER: u8 = 1;
+const ACCESS: u8 = 2;
+
+/// Remove all cache entries for the named bucket
+pub(crate) fn clear(cache: &sled::Tree, bucket: &[u8]) -> Result<(), Error> {
+    for k in cache.scan_prefix(ttl::key(bucket, b"")).keys() {
+        cache.remove(k?)?;
+    }
+    Ok(())
+}
+
+/// Maximum size of a `CacheBucket`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheLimit {
+    /// Maximum number of entries
+    Entries(u64),
+
+    /// Maximum number of bytes used by keys and values
+    Bytes(u64),
+}
+
+/// A `Bucket` that evicts the least recently used entries once it grows past a limit
+///
+/// Access order and usage are stored in the database, so they persist across restarts.
+/// Updates are not atomic, concurrent writers may briefly push the bucket past its limit.
+/// Changes made through other handles to the bucket are not tracked.
+pub struct CacheBucket<'a, K: Key<'a>, V: Value> {
+    bucket: Bucket<'a, K, V>,
+    cache: sled::Tree,
+    db: sled::Db,
+    limit: CacheLimit,
+    prefix: Vec<u8>,
+}
+
+impl<'a, K: Key<'a>, V: Value> CacheBucket<'a, K, V> {
+    pub(crate) fn new(
+        bucket: Bucket<'a, K, V>,
+        cache: sled::Tree,
+        db: sled::Db,
+        limit: CacheLimit,
+    ) -> CacheBucket<'a, K, V> {
+        let prefix = ttl::key(&bucket.tree.name(), b"");
+        CacheBucket {
+            bucket,
+            cache,
+            db,
+            limit,
+            prefix,
+        }
+    }
+
+    fn entry(&self, tag: u8, tick: Option<u64>, key: &[u8]) -> Vec<u8> {
+        let mut dst = self.prefix.clone();
+        dst.push(tag);
+        if let Some(tick) = tick {
+            dst.extend_from_slice(&tick.to_be_bytes());
+        }
+        dst.extend_from_slice(key);
+        dst
+    }
+
+    fn tick(x: &[u8]) -> u64 {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&x[..8]);
+        u64::from_be_bytes(buf)
+    }
+
+    // Mark `key` as the most recently used entry
+    fn touch(&self, key: &[u8]) -> Result<(), Error> {
+        let tick = self.db.generate_id()?;
+        let access = self.entry(ACCESS, None, key);
+        if let Some(old) = self.cache.insert(access, &tick.to_be_bytes())? {
+            self.cache
+                .remove(self.entry(ORDER, Some(Self::tick(&old)), key))?;
+        }
+        self.cache.insert(self.entry(ORDER, Some(tick), key), b"")?;
+        Ok(())
+    }
+
+    fn untouch(&self, key: &[u8]) -> Result<(), Error> {
+        if let Some(old) = self.cache.remove(self.entry(ACCESS, None, key))? {
+            self.cache
+                .remove(self.entry(ORDER, Some(Self::tick(&old)), key))?;
+        }
+        Ok(())
+    }
+
+    // Update the number of entries and bytes, returning the new values
+    fn adjust(&self, entries: i64, bytes: i64) -> Result<(u64, u64), Error> {
+        let add = |x: u64, d: i64| (x as i64).saturating_add(d).max(0) as u64;
+        let stats = self
+            .cache
+            .update_and_fetch(self.entry(STATS, None, b""), |old| {
+                let (n, b) = old.map(parse_stats).unwrap_or((0, 0));
+                let mut dst = Vec::with_capacity(16);
+                dst.extend_from_slice(&add(n, entries).to_be_bytes());
+                dst.extend_from_slice(&add(b, bytes).to_be_bytes());
+                Some(dst)
+            })?;
+        Ok(stats.as_deref().map(parse_stats).unwrap_or((0, 0)))
+    }
+
+    fn stats(&self) -> Result<(u64, u64), Error> {
+        let stats = self.cache.get(self.entry(STATS, None, b""))?;
+        Ok(stats.as_deref().map(parse_stats).unwrap_or((0, 0)))
+    }
+
+    // Writes go through the bucket so they are recorded in the changefeed and reported to the
+    // observer
+    fn write(&self, key: &Raw, value: Option<Raw>, op: Operation) -> Result<Option<Raw>, Error> {
+        let b = &self.bucket;
+        b.observer.time(&b.tree, op, || b.write(key, value))
+    }
+
+    fn remove_raw(&self, key: &[u8]) -> Result<(), Error> {
+        let old = self.write(&key.into(), None, Operation::Remove)?;
+        self.untouch(key)?;
+        if let Some(old) = old {
+            self.adjust(-1, -((key.len() + old.len()) as i64))?;
+        }
+        Ok(())
+    }
+
+    // Remove the least recently used entries until the bucket is within its limit
+    fn evict(&self) -> Result<(), Error> {
+        loop {
+            let (n, b) = self.stats()?;
+            let over = match self.limit {
+                CacheLimit::Entries(max) => n > max,
+                CacheLimit::Bytes(max) => b > max,
+            };
+            if !over {
+                return Ok(());
+            }
+
+            let order = self.entry(ORDER, None, b"");
+            let k = match self.cache.scan_prefix(&order).keys().next() {
+                Some(k) => k?,
+                None => return Ok(()),
+            };
+            self.cache.remove(&k)?;
+            self.remove_raw(&k[order.len() + 8..])?;
+        }
+    }
+
+    /// Get the underlying bucket
+    pub fn bucket(&self) -> &Bucket<'a, K, V> {
+        &self.bucket
+    }
+
+    /// Get the limit
+    pub fn limit(&self) -> CacheLimit {
+        self.limit
+    }
+
+    /// Returns true if the bucket contains the given key, this doesn't count as a use of the
+    /// entry
+    pub fn contains<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        self.bucket.contains(key)
+    }
+
+    /// Get the value associated with the specified key and mark it as recently used
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<V>, Error> {
+        let key = key.into().to_raw_key()?;
+        let v = self.bucket.get_raw(&key)?;
+        if v.is_some() {
+            self.touch(&key)?;
+        }
+        Ok(v)
+    }
+
+    /// Set the value associated with the specified key, evicting the least recently used
+    /// entries if the bucket grows past its limit
+    pub fn set<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        let v: Raw = self.bucket.encode(&key, &value.into())?;
+        let n = v.len() as i64;
+        match self.write(&key, Some(v), Operation::Set)? {
+            Some(old) => self.adjust(0, n - old.len() as i64)?,
+            None => self.adjust(1, key.len() as i64 + n)?,
+        };
+        self.touch(&key)?;
+        self.evict()
+    }
+
+    /// Remove the value associated with the specified key
+    pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        self.remove_raw(&key)
+    }
+
+    /// Get the number of entries
+    pub fn len(&self) -> Result<u64, Error> {
+        Ok(self.stats()?.0)
+    }
+
+    /// Returns true when there are no entries
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Get the number of bytes used by keys and values
+    pub fn size_in_bytes(&self) -> Result<u64, Error> {
+        Ok(self.stats()?.1)
+    }
+
+    /// Remove all entries
+    pub fn clear(&self) -> Result<(), Error> {
+        self.bucket.clear()?;
+        clear(&self.cache, &self.bucket.tree.name())
+    }
+}
+
+fn parse_stats(x: &[u8]) -> (u64, u64) {
+    if x.len() != 16 {
+        return (0, 0);
+    }
+    let mut n = [0u8; 8];
+    let mut b = [0u8; 8];
+    n.copy_from_slice(&x[..8]);
+    b.copy_from_slice(&x[8..]);
+    (u64::from_be_bytes(n), u64::from_be_bytes(b))
+}
@@ -0,0 +1,179 @@
+use crate::transaction::TransactionError;
+use crate::ttl;
+use crate::{Error, Raw};
+
+/// Each bucket's changes are stored in a tree named after it, so transactions on different
+/// buckets don't share a tree
+pub(crate) const TREE_PREFIX: &str = "__kv__changes/";
+
+/// Key holding the last sequence number, every change is stored under its big-endian sequence
+/// number so this sorts before all of them
+const SEQ_KEY: &[u8] = b"";
+
+/// Name of the tree holding the changes to `bucket`
+pub(crate) fn tree_name(bucket: &[u8]) -> Vec<u8> {
+    let mut dst = TREE_PREFIX.as_bytes().to_vec();
+    dst.extend_from_slice(bucket);
+    dst
+}
+
+/// Open the changefeed of `bucket`, trees used internally don't have one
+pub(crate) fn open(db: &sled::Db, bucket: &[u8]) -> Result<Option<sled::Tree>, Error> {
+    if bucket.starts_with(b"__kv__") {
+        return Ok(None);
+    }
+    Ok(Some(db.open_tree(tree_name(bucket))?))
+}
+
+/// A change to a key, recorded when `Config::changefeed` is enabled
+///
+/// Values are identified by the CRC32 of their stored bytes, use the bucket to read the current
+/// value of a key
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Sequence number, the changes to each bucket are numbered from 1 without gaps in the order
+    /// they were committed
+    pub seq: u64,
+
+    /// Time the change was made, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// Bucket name
+    pub bucket: String,
+
+    /// Key
+    pub key: Raw,
+
+    /// Checksum of the previous value, `None` if the key had no value
+    pub old: Option<u32>,
+
+    /// Checksum of the new value, `None` if the key was removed
+    pub new: Option<u32>,
+}
+
+fn invalid() -> Error {
+    Error::Message("Invalid changefeed entry".into())
+}
+
+fn encode_hash(dst: &mut Vec<u8>, x: Option<&[u8]>) {
+    match x {
+        Some(x) => {
+            dst.push(1);
+            dst.extend_from_slice(&crc32fast::hash(x).to_be_bytes());
+        }
+        None => dst.extend_from_slice(&[0; 5]),
+    }
+}
+
+fn decode_u64(x: &[u8]) -> Result<u64, Error> {
+    let mut n = [0u8; 8];
+    if x.len() != 8 {
+        return Err(invalid());
+    }
+    n.copy_from_slice(x);
+    Ok(u64::from_be_bytes(n))
+}
+
+fn decode_u32(x: &[u8]) -> u32 {
+    let mut n = [0u8; 4];
+    n.copy_from_slice(x);
+    u32::from_be_bytes(n)
+}
+
+// Entries are the timestamp, the old and new value checksums each preceded by a byte that is 1
+// when the value exists, and the length of the bucket name followed by the bucket name and key
+fn decode(seq: &[u8], v: &[u8]) -> Result<Change, Error> {
+    if v.len() < 22 {
+        return Err(invalid());
+    }
+    let hash = |x: &[u8]| match x[0] {
+        0 => None,
+        _ => Some(decode_u32(&x[1..])),
+    };
+    let n = decode_u32(&v[18..22]) as usize;
+    let bucket = v.get(22..22 + n).ok_or_else(invalid)?;
+    Ok(Change {
+        seq: decode_u64(seq)?,
+        timestamp: decode_u64(&v[..8])?,
+        bucket: String::from_utf8(bucket.to_vec())?,
+        key: v[22 + n..].into(),
+        old: hash(&v[8..13]),
+        new: hash(&v[13..18]),
+    })
+}
+
+/// Append a change to the bucket's log as part of a transaction, `old` and `new` are the stored
+/// values
+pub(crate) fn record(
+    log: &sled::TransactionalTree,
+    bucket: &[u8],
+    key: &[u8],
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+) -> Result<(), TransactionError<Error>> {
+    // Every change to the bucket reads and writes the same counter, so concurrent transactions
+    // conflict and sequence numbers follow the commit order
+    let seq = match log.get(SEQ_KEY)? {
+        Some(x) => decode_u64(&x).map_err(TransactionError::Abort)? + 1,
+        None => 1,
+    };
+    let now = ttl::now().map_err(TransactionError::Abort)?;
+
+    let mut v = Vec::with_capacity(22 + bucket.len() + key.len());
+    v.extend_from_slice(&now.to_be_bytes());
+    encode_hash(&mut v, old);
+    encode_hash(&mut v, new);
+    v.extend_from_slice(&(bucket.len() as u32).to_be_bytes());
+    v.extend_from_slice(bucket);
+    v.extend_from_slice(key);
+
+    log.insert(SEQ_KEY, &seq.to_be_bytes())?;
+    log.insert(&seq.to_be_bytes(), v)?;
+    Ok(())
+}
+
+/// Returns the sequence number of the last change, or 0 if nothing has been recorded
+pub(crate) fn last(log: &sled::Tree) -> Result<u64, Error> {
+    match log.get(SEQ_KEY)? {
+        Some(x) => decode_u64(&x),
+        None => Ok(0),
+    }
+}
+
+/// Remove all changes with a sequence number less than `seq`, returns the number removed
+pub(crate) fn truncate(log: &sled::Tree, seq: u64) -> Result<usize, Error> {
+    let mut n = 0;
+    if seq <= 1 {
+        return Ok(n);
+    }
+    for k in log.range(1u64.to_be_bytes()..seq.to_be_bytes()).keys() {
+        log.remove(k?)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Iterator over changes, see `Store::changes`
+pub struct Changes(Option<sled::Iter>);
+
+impl Changes {
+    pub(crate) fn new(log: &sled::Tree, seq: u64) -> Changes {
+        Changes(Some(log.range(seq.max(1).to_be_bytes()..)))
+    }
+
+    // Changes to a bucket that has never been opened with the changefeed enabled
+    pub(crate) fn empty() -> Changes {
+        Changes(None)
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.as_mut()?.next()? {
+            Ok((k, v)) => Some(decode(&k, &v)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{Error, Raw, Value};
+
+const CHECKSUM_LEN: usize = 4;
+
+/// Value wrapper that stores a CRC32 checksum of the encoded value and verifies it when the
+/// value is read, returning `Error::ChecksumMismatch` if the stored bytes have changed
+pub struct Checked<V>(pub V);
+
+impl<V> Checked<V> {
+    /// Wrap a value
+    pub fn new(x: V) -> Checked<V> {
+        Checked(x)
+    }
+
+    /// Convert back into inner value
+    pub fn into_inner(self) -> V {
+        self.0
+    }
+}
+
+impl<V> From<V> for Checked<V> {
+    fn from(x: V) -> Checked<V> {
+        Checked::new(x)
+    }
+}
+
+impl<V> AsRef<V> for Checked<V> {
+    fn as_ref(&self) -> &V {
+        &self.0
+    }
+}
+
+impl<V> AsMut<V> for Checked<V> {
+    fn as_mut(&mut self) -> &mut V {
+        &mut self.0
+    }
+}
+
+impl<V: Clone> Clone for Checked<V> {
+    fn clone(&self) -> Self {
+        Checked::new(self.0.clone())
+    }
+}
+
+// The checksum is stored before the encoded value as a big-endian u32
+impl<V: Value> Value for Checked<V> {
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        let x = self.0.to_raw_value()?;
+        let mut dst = Vec::with_capacity(CHECKSUM_LEN + x.len());
+        dst.extend_from_slice(&crc32fast::hash(&x).to_be_bytes());
+        dst.extend_from_slice(&x);
+        Ok(dst.into())
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        let mismatch = || Error::ChecksumMismatch {
+            key: Raw::default(),
+        };
+        if r.len() < CHECKSUM_LEN {
+            return Err(mismatch());
+        }
+
+        let mut sum = [0u8; CHECKSUM_LEN];
+        sum.copy_from_slice(&r[..CHECKSUM_LEN]);
+        let x = &r[CHECKSUM_LEN..];
+        if crc32fast::hash(x) != u32::from_be_bytes(sum) {
+            return Err(mismatch());
+        }
+        Ok(Checked::new(V::from_raw_value(x.into())?))
+    }
+}
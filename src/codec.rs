@@ -39,11 +39,37 @@ macro_rules! codec {
                 $x(self.0.clone())
             }
         }
+
+        impl<T: serde::Serialize + serde::de::DeserializeOwned> serde::Serialize for $x<T> {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                self.0.serialize(s)
+            }
+        }
+
+        impl<'de, T: serde::Serialize + serde::de::DeserializeOwned> serde::Deserialize<'de>
+            for $x<T>
+        {
+            fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                T::deserialize(d).map($x)
+            }
+        }
     };
 
     ($x:ident, {$ser:expr, $de:expr}) => {
         codec!($x);
 
+        impl<T: serde::Serialize + serde::de::DeserializeOwned> $x<T> {
+            #[doc(hidden)]
+            pub fn encode(x: &T) -> Result<Raw, Error> {
+                Ok($ser(x)?.into())
+            }
+
+            #[doc(hidden)]
+            pub fn decode(r: &Raw) -> Result<T, Error> {
+                Ok($de(r)?)
+            }
+        }
+
         impl<T: serde::Serialize + serde::de::DeserializeOwned> Value for $x<T> {
             fn to_raw_value(&self) -> Result<Raw, Error> {
                 let x = $ser(&self.0)?;
@@ -97,6 +123,13 @@ mod lexpr_value {
     codec!(Lexpr, {serde_lexpr::to_vec, serde_lexpr::from_slice});
 }
 
+#[cfg(feature = "cbor-value")]
+mod cbor_value {
+    use super::*;
+
+    codec!(Cbor, {serde_cbor::to_vec, serde_cbor::from_slice});
+}
+
 #[cfg(feature = "json-value")]
 pub use json_value::Json;
 
@@ -108,3 +141,65 @@ pub use bincode_value::Bincode;
 
 #[cfg(feature = "lexpr-value")]
 pub use lexpr_value::Lexpr;
+
+#[cfg(feature = "cbor-value")]
+pub use cbor_value::Cbor;
+
+/// A value returned by `Bucket::get_ref`, serde types can be deserialized from it without
+/// copying, so `&str` and `&[u8]` fields borrow from the stored bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueRef(Raw);
+
+impl ValueRef {
+    pub(crate) fn new(raw: Raw) -> ValueRef {
+        ValueRef(raw)
+    }
+
+    /// Get the encoded value
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Convert into the encoded value
+    pub fn into_raw(self) -> Raw {
+        self.0
+    }
+
+    /// Deserialize a JSON value
+    #[cfg(feature = "json-value")]
+    pub fn json<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        Ok(serde_json::from_slice(&self.0)?)
+    }
+
+    /// Deserialize a MessagePack value
+    #[cfg(feature = "msgpack-value")]
+    pub fn msgpack<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        Ok(rmp_serde::from_read_ref(self.as_bytes())?)
+    }
+
+    /// Deserialize a bincode value
+    #[cfg(feature = "bincode-value")]
+    pub fn bincode<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        Ok(bincode::deserialize(&self.0)?)
+    }
+
+    /// Deserialize a CBOR value
+    #[cfg(feature = "cbor-value")]
+    pub fn cbor<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        Ok(serde_cbor::from_slice(&self.0)?)
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
@@ -0,0 +1,34 @@
+use crate::Error;
+
+/// Disk usage of a store, returned by `Store::space_usage`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpaceUsage {
+    /// Size of the store on disk in bytes
+    pub size_on_disk: u64,
+
+    /// Total size of every key and value in the store in bytes, including internal trees
+    pub live_bytes: u64,
+}
+
+impl SpaceUsage {
+    /// Estimate of the number of bytes `Store::compact` would reclaim
+    ///
+    /// sled stores some metadata with each key, so this is an upper bound
+    pub fn reclaimable(&self) -> u64 {
+        self.size_on_disk.saturating_sub(self.live_bytes)
+    }
+}
+
+pub(crate) fn usage(db: &sled::Db) -> Result<SpaceUsage, Error> {
+    let mut live_bytes = 0;
+    for name in db.tree_names() {
+        for item in db.open_tree(&name)?.iter() {
+            let (k, v) = item?;
+            live_bytes += (k.len() + v.len()) as u64;
+        }
+    }
+    Ok(SpaceUsage {
+        size_on_disk: db.size_on_disk()?,
+        live_bytes,
+    })
+}
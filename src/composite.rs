@@ -0,0 +1,197 @@
+use crate::{Error, Key, Raw};
+
+/// A value that can be used as part of a `Composite` key
+///
+/// Encoded values sort in the same order as the values themselves
+pub trait KeyPart: Sized {
+    /// Append the encoded value to `dst`
+    fn encode_key(&self, dst: &mut Vec<u8>);
+
+    /// Decode a value from the beginning of `src`, advancing `src` past it
+    fn decode_key(src: &mut &[u8]) -> Result<Self, Error>;
+}
+
+fn invalid() -> Error {
+    Error::Message("Invalid composite key".into())
+}
+
+fn take<'a>(src: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if src.len() < n {
+        return Err(invalid());
+    }
+    let (a, b) = src.split_at(n);
+    *src = b;
+    Ok(a)
+}
+
+macro_rules! key_part_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl KeyPart for $t {
+                fn encode_key(&self, dst: &mut Vec<u8>) {
+                    dst.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_key(src: &mut &[u8]) -> Result<Self, Error> {
+                    let mut buf = [0u8; std::mem::size_of::<$t>()];
+                    let n = buf.len();
+                    buf.copy_from_slice(take(src, n)?);
+                    Ok(<$t>::from_be_bytes(buf))
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! key_part_signed {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl KeyPart for $t {
+                fn encode_key(&self, dst: &mut Vec<u8>) {
+                    // Flipping the sign bit makes negative numbers sort before positive ones
+                    let x = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                    x.encode_key(dst)
+                }
+
+                fn decode_key(src: &mut &[u8]) -> Result<Self, Error> {
+                    let x = <$u>::decode_key(src)?;
+                    Ok((x ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    };
+}
+
+key_part_unsigned!(u8, u16, u32, u64, u128);
+key_part_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyPart for bool {
+    fn encode_key(&self, dst: &mut Vec<u8>) {
+        dst.push(*self as u8)
+    }
+
+    fn decode_key(src: &mut &[u8]) -> Result<Self, Error> {
+        match u8::decode_key(src)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Byte strings are terminated by `0x00 0x00`, zero bytes are escaped as `0x00 0xff`
+pub(crate) fn encode_bytes(x: &[u8], dst: &mut Vec<u8>) {
+    for b in x {
+        dst.push(*b);
+        if *b == 0 {
+            dst.push(0xff);
+        }
+    }
+    dst.extend_from_slice(&[0, 0]);
+}
+
+impl KeyPart for Vec<u8> {
+    fn encode_key(&self, dst: &mut Vec<u8>) {
+        encode_bytes(self, dst)
+    }
+
+    fn decode_key(src: &mut &[u8]) -> Result<Self, Error> {
+        let mut dst = Vec::new();
+        loop {
+            match take(src, 1)?[0] {
+                0 => match take(src, 1)?[0] {
+                    0 => return Ok(dst),
+                    0xff => dst.push(0),
+                    _ => return Err(invalid()),
+                },
+                b => dst.push(b),
+            }
+        }
+    }
+}
+
+impl KeyPart for String {
+    fn encode_key(&self, dst: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), dst)
+    }
+
+    fn decode_key(src: &mut &[u8]) -> Result<Self, Error> {
+        let x = Vec::<u8>::decode_key(src)?;
+        Ok(String::from_utf8(x)?)
+    }
+}
+
+macro_rules! key_part_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: KeyPart),*> KeyPart for ($($t,)*) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, dst: &mut Vec<u8>) {
+                let ($($t,)*) = self;
+                $($t.encode_key(dst);)*
+            }
+
+            fn decode_key(src: &mut &[u8]) -> Result<Self, Error> {
+                Ok(($($t::decode_key(src)?,)*))
+            }
+        }
+    };
+}
+
+key_part_tuple!(A);
+key_part_tuple!(A, B);
+key_part_tuple!(A, B, C);
+key_part_tuple!(A, B, C, D);
+key_part_tuple!(A, B, C, D, E);
+key_part_tuple!(A, B, C, D, E, F);
+
+/// Composite key type, keys are encoded so that they sort in the same order as the values they
+/// were created from. This makes it possible to use tuples of integers and strings as keys and
+/// still get meaningful results from range queries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Composite<T: KeyPart>(T, Raw);
+
+impl<T: KeyPart> Composite<T> {
+    /// Create a new composite key
+    pub fn new(x: T) -> Composite<T> {
+        let mut buf = Vec::new();
+        x.encode_key(&mut buf);
+        Composite(x, buf.into())
+    }
+
+    /// Get a reference to the inner value
+    pub fn inner(&self) -> &T {
+        &self.0
+    }
+
+    /// Convert back into inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: KeyPart> From<T> for Composite<T> {
+    fn from(x: T) -> Composite<T> {
+        Composite::new(x)
+    }
+}
+
+impl<T: KeyPart> AsRef<[u8]> for Composite<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.1.as_ref()
+    }
+}
+
+impl<'a, T: KeyPart> Key<'a> for Composite<T> {
+    fn from_raw_key(r: &Raw) -> Result<Self, Error> {
+        let mut src = r.as_ref();
+        let x = T::decode_key(&mut src)?;
+        if !src.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Composite(x, r.clone()))
+    }
+
+    fn to_raw_key(&self) -> Result<Raw, Error> {
+        Ok(self.1.clone())
+    }
+}
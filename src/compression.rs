@@ -0,0 +1,94 @@
+use std::marker::PhantomData;
+
+use crate::{Error, Raw, Value};
+
+/// Compression algorithm used by `Compressed`
+pub trait Compression {
+    /// Compress `src`
+    fn compress(src: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Decompress data returned by `compress`
+    fn decompress(src: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Value wrapper that compresses the encoded value using `C`, this can be used to compress
+/// values in a single bucket independently of `Config::use_compression`
+pub struct Compressed<V, C: Compression>(pub V, PhantomData<C>);
+
+impl<V, C: Compression> Compressed<V, C> {
+    /// Wrap a value
+    pub fn new(x: V) -> Compressed<V, C> {
+        Compressed(x, PhantomData)
+    }
+
+    /// Convert back into inner value
+    pub fn into_inner(self) -> V {
+        self.0
+    }
+}
+
+impl<V, C: Compression> From<V> for Compressed<V, C> {
+    fn from(x: V) -> Compressed<V, C> {
+        Compressed::new(x)
+    }
+}
+
+impl<V, C: Compression> AsRef<V> for Compressed<V, C> {
+    fn as_ref(&self) -> &V {
+        &self.0
+    }
+}
+
+impl<V, C: Compression> AsMut<V> for Compressed<V, C> {
+    fn as_mut(&mut self) -> &mut V {
+        &mut self.0
+    }
+}
+
+impl<V: Clone, C: Compression> Clone for Compressed<V, C> {
+    fn clone(&self) -> Self {
+        Compressed::new(self.0.clone())
+    }
+}
+
+impl<V: Value, C: Compression> Value for Compressed<V, C> {
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        let x = self.0.to_raw_value()?;
+        Ok(C::compress(&x)?.into())
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        let x = C::decompress(&r)?;
+        Ok(Compressed::new(V::from_raw_value(x.into())?))
+    }
+}
+
+/// Zstandard compression, `LEVEL` is the compression level from 1 to 22
+#[cfg(feature = "zstd-value")]
+pub struct Zstd<const LEVEL: i32 = 3>;
+
+#[cfg(feature = "zstd-value")]
+impl<const LEVEL: i32> Compression for Zstd<LEVEL> {
+    fn compress(src: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(zstd::encode_all(src, LEVEL)?)
+    }
+
+    fn decompress(src: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(zstd::decode_all(src)?)
+    }
+}
+
+/// LZ4 compression
+#[cfg(feature = "lz4-value")]
+pub struct Lz4;
+
+#[cfg(feature = "lz4-value")]
+impl Compression for Lz4 {
+    fn compress(src: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(lz4_flex::compress_prepend_size(src))
+    }
+
+    fn decompress(src: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(lz4_flex::decompress_size_prepended(src)?)
+    }
+}
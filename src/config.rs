@@ -1,6 +1,11 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::{env, fs, io};
 
+use fs2::FileExt;
 use toml;
 
 use crate::error::Error;
@@ -31,9 +36,232 @@ pub struct Config {
     /// Specify the cache capacity
     #[serde(default)]
     pub cache_capacity: Option<u64>,
+
+    /// Specify how often expired keys are purged in the background
+    #[serde(default)]
+    pub purge_expired_every_ms: Option<u64>,
+
+    /// Fail to open the database if it already exists
+    #[serde(default)]
+    pub create_new: Option<bool>,
+
+    /// Specify the zstd compression level used when `use_compression` is enabled
+    #[serde(default)]
+    pub compression_factor: Option<i32>,
+
+    /// Specify the size of log segments in bytes, this must be a power of two
+    #[serde(default)]
+    pub segment_size: Option<usize>,
+
+    /// Specify how log segments are reused
+    #[serde(default)]
+    pub segment_mode: Option<SegmentMode>,
+
+    /// Specify the percentage of valid pages remaining in a segment before it is cleaned up
+    #[serde(default)]
+    pub segment_cleanup_threshold: Option<u8>,
+
+    /// Specify the cleanup threshold skew in percentage points between the first and last
+    /// segments
+    #[serde(default)]
+    pub segment_cleanup_skew: Option<usize>,
+
+    /// Specify the number of operations between page table snapshots
+    #[serde(default)]
+    pub snapshot_after_ops: Option<u64>,
+
+    /// Specify where page table snapshots are stored
+    #[serde(default)]
+    pub snapshot_path: Option<PathBuf>,
+
+    /// Specify how often generated IDs are persisted
+    #[serde(default)]
+    pub idgen_persist_interval: Option<u64>,
+
+    /// Print a performance profile when the store is closed
+    #[serde(default)]
+    pub print_profile_on_drop: Option<bool>,
+
+    /// Specify how long to keep retrying when the store is locked by another process, by
+    /// default opening a locked store fails immediately
+    #[serde(default)]
+    pub open_timeout_ms: Option<u64>,
+
+    /// Bucket operations that take longer than this are logged as slow when the `tracing`
+    /// feature is enabled
+    #[serde(default)]
+    pub slow_operation_threshold_ms: Option<u64>,
+
+    /// Record every change to a bucket in the changefeed, see `Store::changes`
+    #[serde(default)]
+    pub changefeed: bool,
+}
+
+/// Log segment selection mode
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentMode {
+    /// Always write to the end of the log
+    Linear,
+
+    /// Reuse segments once their contents have been moved elsewhere
+    Gc,
+}
+
+impl FromStr for SegmentMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SegmentMode, Error> {
+        match s {
+            "linear" => Ok(SegmentMode::Linear),
+            "gc" => Ok(SegmentMode::Gc),
+            _ => Err(Error::InvalidConfiguration(format!(
+                "Unknown segment mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl From<SegmentMode> for sled::SegmentMode {
+    fn from(mode: SegmentMode) -> sled::SegmentMode {
+        match mode {
+            SegmentMode::Linear => sled::SegmentMode::Linear,
+            SegmentMode::Gc => sled::SegmentMode::Gc,
+        }
+    }
+}
+
+fn invalid_env(name: &str, value: &str) -> Error {
+    Error::InvalidConfiguration(format!(
+        "Invalid value for environment variable {}: {:?}",
+        name, value
+    ))
+}
+
+// Variables used by `Config::merge_vars`, keyed by name
+type Vars = HashMap<String, OsString>;
+
+// Parse the variable `prefix` + `name`, returns `None` when it isn't set
+fn env_var<T: FromStr>(vars: &Vars, prefix: &str, name: &str) -> Result<Option<T>, Error> {
+    let name = format!("{}{}", prefix, name);
+    let value = match vars.get(&name) {
+        Some(x) => x.clone(),
+        None => return Ok(None),
+    };
+    let value = match value.into_string() {
+        Ok(x) => x,
+        Err(x) => return Err(invalid_env(&name, &x.to_string_lossy())),
+    };
+    match value.trim().parse() {
+        Ok(x) => Ok(Some(x)),
+        Err(_) => Err(invalid_env(&name, &value)),
+    }
+}
+
+// Booleans may also be written as `1` or `0`
+fn env_bool(vars: &Vars, prefix: &str, name: &str) -> Result<Option<bool>, Error> {
+    match env_var::<String>(vars, prefix, name)? {
+        None => Ok(None),
+        Some(x) => match x.as_str() {
+            "1" | "true" => Ok(Some(true)),
+            "0" | "false" => Ok(Some(false)),
+            _ => Err(invalid_env(&format!("{}{}", prefix, name), &x)),
+        },
+    }
+}
+
+// Get the environment variables starting with `prefix`
+fn env_vars(prefix: &str) -> Vars {
+    env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v)))
+        .filter(|(k, _)| k.starts_with(prefix))
+        .collect()
 }
 
 impl Config {
+    /// Create a configuration object from environment variables named `prefix` followed by the
+    /// upper case field name, for example `KV_PATH` and `KV_CACHE_CAPACITY` when `prefix` is
+    /// `KV_`. The path variable is required, see `merge_env` for the other fields.
+    pub fn from_env(prefix: &str) -> Result<Config, Error> {
+        Config::from_vars(prefix, &env_vars(prefix))
+    }
+
+    // Like `from_env`, using the variables in `vars` instead of the environment
+    pub(crate) fn from_vars(prefix: &str, vars: &Vars) -> Result<Config, Error> {
+        let name = format!("{}PATH", prefix);
+        match vars.get(&name) {
+            Some(path) => Config::new(path).merge_vars(prefix, vars),
+            None => Err(Error::Message(format!(
+                "Missing environment variable {}",
+                name
+            ))),
+        }
+    }
+
+    /// Override fields using environment variables named `prefix` followed by the upper case
+    /// field name, fields without a matching variable are left unchanged
+    ///
+    /// Booleans may be `true`, `false`, `1` or `0` and `SEGMENT_MODE` may be `linear` or `gc`.
+    /// An error naming the variable is returned if a value can't be parsed.
+    pub fn merge_env(self, prefix: &str) -> Result<Config, Error> {
+        self.merge_vars(prefix, &env_vars(prefix))
+    }
+
+    // Like `merge_env`, using the variables in `vars` instead of the environment
+    pub(crate) fn merge_vars(mut self, prefix: &str, vars: &Vars) -> Result<Config, Error> {
+        if let Some(x) = vars.get(&format!("{}PATH", prefix)) {
+            self.path = x.into();
+        }
+        if let Some(x) = env_bool(vars, prefix, "READ_ONLY")? {
+            self.read_only = x;
+        }
+        if let Some(x) = env_bool(vars, prefix, "TEMPORARY")? {
+            self.temporary = x;
+        }
+        if let Some(x) = env_bool(vars, prefix, "USE_COMPRESSION")? {
+            self.use_compression = x;
+        }
+        if let Some(x) = env_bool(vars, prefix, "CHANGEFEED")? {
+            self.changefeed = x;
+        }
+
+        macro_rules! optional {
+            ($($field:ident: $name:expr),*) => {
+                $(
+                    if let Some(x) = env_var(vars, prefix, $name)? {
+                        self.$field = Some(x);
+                    }
+                )*
+            };
+        }
+        optional!(
+            flush_every_ms: "FLUSH_EVERY_MS",
+            cache_capacity: "CACHE_CAPACITY",
+            purge_expired_every_ms: "PURGE_EXPIRED_EVERY_MS",
+            open_timeout_ms: "OPEN_TIMEOUT_MS",
+            slow_operation_threshold_ms: "SLOW_OPERATION_THRESHOLD_MS",
+            compression_factor: "COMPRESSION_FACTOR",
+            segment_size: "SEGMENT_SIZE",
+            segment_mode: "SEGMENT_MODE",
+            segment_cleanup_threshold: "SEGMENT_CLEANUP_THRESHOLD",
+            segment_cleanup_skew: "SEGMENT_CLEANUP_SKEW",
+            snapshot_after_ops: "SNAPSHOT_AFTER_OPS",
+            idgen_persist_interval: "IDGEN_PERSIST_INTERVAL"
+        );
+
+        if let Some(x) = vars.get(&format!("{}SNAPSHOT_PATH", prefix)) {
+            self.snapshot_path = Some(x.into());
+        }
+        if let Some(x) = env_bool(vars, prefix, "CREATE_NEW")? {
+            self.create_new = Some(x);
+        }
+        if let Some(x) = env_bool(vars, prefix, "PRINT_PROFILE_ON_DROP")? {
+            self.print_profile_on_drop = Some(x);
+        }
+        Ok(self)
+    }
+
     /// Create a default configuration object
     pub fn new<P: AsRef<Path>>(p: P) -> Config {
         Config {
@@ -43,15 +271,26 @@ impl Config {
             use_compression: false,
             flush_every_ms: None,
             cache_capacity: None,
+            purge_expired_every_ms: None,
+            create_new: None,
+            compression_factor: None,
+            segment_size: None,
+            segment_mode: None,
+            segment_cleanup_threshold: None,
+            segment_cleanup_skew: None,
+            snapshot_after_ops: None,
+            snapshot_path: None,
+            idgen_persist_interval: None,
+            print_profile_on_drop: None,
+            open_timeout_ms: None,
+            slow_operation_threshold_ms: None,
+            changefeed: false,
         }
     }
 
     /// Save Config to an io::Write
     pub fn save_to<W: io::Write>(&self, mut w: W) -> Result<(), Error> {
-        let s = match toml::to_string(self) {
-            Ok(s) => s,
-            Err(_) => return Err(Error::InvalidConfiguration),
-        };
+        let s = toml::to_string(self)?;
         w.write_all(s.as_ref())?;
         Ok(())
     }
@@ -66,10 +305,7 @@ impl Config {
     pub fn load_from<R: io::Read>(mut r: R) -> Result<Config, Error> {
         let mut buf = Vec::new();
         r.read_to_end(&mut buf)?;
-        match toml::from_slice(buf.as_ref()) {
-            Ok(cfg) => Ok(cfg),
-            Err(_) => Err(Error::InvalidConfiguration),
-        }
+        Ok(toml::from_slice(buf.as_ref())?)
     }
 
     /// Load configuration to a file
@@ -108,19 +344,182 @@ impl Config {
         self
     }
 
+    /// Set expiration purge frequency
+    pub fn purge_expired_every_ms(mut self, ms: u64) -> Config {
+        self.purge_expired_every_ms = Some(ms);
+        self
+    }
+
+    /// Set `create_new` field
+    pub fn create_new(mut self, create_new: bool) -> Config {
+        self.create_new = Some(create_new);
+        self
+    }
+
+    /// Set zstd compression level
+    pub fn compression_factor(mut self, factor: i32) -> Config {
+        self.compression_factor = Some(factor);
+        self
+    }
+
+    /// Set log segment size
+    pub fn segment_size(mut self, size: usize) -> Config {
+        self.segment_size = Some(size);
+        self
+    }
+
+    /// Set log segment mode
+    pub fn segment_mode(mut self, mode: SegmentMode) -> Config {
+        self.segment_mode = Some(mode);
+        self
+    }
+
+    /// Set segment cleanup threshold
+    pub fn segment_cleanup_threshold(mut self, threshold: u8) -> Config {
+        self.segment_cleanup_threshold = Some(threshold);
+        self
+    }
+
+    /// Set segment cleanup skew
+    pub fn segment_cleanup_skew(mut self, skew: usize) -> Config {
+        self.segment_cleanup_skew = Some(skew);
+        self
+    }
+
+    /// Set snapshot frequency
+    pub fn snapshot_after_ops(mut self, ops: u64) -> Config {
+        self.snapshot_after_ops = Some(ops);
+        self
+    }
+
+    /// Set snapshot path
+    pub fn snapshot_path<P: AsRef<Path>>(mut self, path: P) -> Config {
+        self.snapshot_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set ID generator persistence interval
+    pub fn idgen_persist_interval(mut self, interval: u64) -> Config {
+        self.idgen_persist_interval = Some(interval);
+        self
+    }
+
+    /// Toggle `print_profile_on_drop` value
+    pub fn print_profile_on_drop(mut self, print: bool) -> Config {
+        self.print_profile_on_drop = Some(print);
+        self
+    }
+
+    /// Set how long to keep retrying when the store is locked by another process
+    pub fn open_timeout(mut self, timeout: Duration) -> Config {
+        self.open_timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    /// Log bucket operations that take longer than `threshold` as slow, this requires the
+    /// `tracing` feature
+    pub fn slow_operation_threshold(mut self, threshold: Duration) -> Config {
+        self.slow_operation_threshold_ms = Some(threshold.as_millis() as u64);
+        self
+    }
+
+    /// Record every change to a bucket in the changefeed
+    pub fn changefeed(mut self, changefeed: bool) -> Config {
+        self.changefeed = changefeed;
+        self
+    }
+
+    // How long to wait for a store that's locked by another process
+    pub(crate) fn lock_timeout(&self) -> Duration {
+        Duration::from_millis(self.open_timeout_ms.unwrap_or(0))
+    }
+
     pub(crate) fn open(&mut self) -> Result<sled::Db, Error> {
-        let config = sled::Config::new()
+        let deadline = Instant::now() + self.lock_timeout();
+        loop {
+            match self.try_open() {
+                Err(Error::StoreLocked(_)) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(50))
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn try_open(&mut self) -> Result<sled::Db, Error> {
+        let mut config = sled::Config::new()
             .path(&self.path)
             .read_only(self.read_only)
             .temporary(self.temporary)
             .flush_every_ms(self.flush_every_ms)
             .use_compression(self.use_compression);
-        let config = if let Some(cache_capacity) = self.cache_capacity {
-            config.cache_capacity(cache_capacity)
+        if let Some(x) = self.cache_capacity {
+            config = config.cache_capacity(x);
+        }
+        if let Some(x) = self.create_new {
+            config = config.create_new(x);
+        }
+        if let Some(x) = self.compression_factor {
+            config = config.compression_factor(x);
+        }
+        if let Some(x) = self.segment_size {
+            config = config.segment_size(x);
+        }
+        if let Some(x) = self.segment_mode {
+            config = config.segment_mode(x.into());
+        }
+        if let Some(x) = self.segment_cleanup_threshold {
+            config = config.segment_cleanup_threshold(x);
+        }
+        if let Some(x) = self.segment_cleanup_skew {
+            config = config.segment_cleanup_skew(x);
+        }
+        if let Some(x) = self.snapshot_after_ops {
+            config = config.snapshot_after_ops(x);
+        }
+        if let Some(x) = &self.snapshot_path {
+            config = config.snapshot_path(Some(x.clone()));
+        }
+        if let Some(x) = self.idgen_persist_interval {
+            config = config.idgen_persist_interval(x);
+        }
+        if let Some(x) = self.print_profile_on_drop {
+            config = config.print_profile_on_drop(x);
+        }
+        match config.open() {
+            Ok(db) => Ok(db),
+            Err(sled::Error::Io(e)) if self.is_locked(&e) => {
+                Err(Error::StoreLocked(self.path.clone()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // sled 0.31 reports a failure to lock the database file as `ErrorKind::Other`, losing the
+    // kind of the underlying error, so unless the kind is `WouldBlock` the lock is checked by
+    // trying to take it the same way sled does
+    fn is_locked(&self, e: &io::Error) -> bool {
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return true;
+        }
+        let file = match fs::File::open(self.path.join("db")) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+        let res = if self.read_only {
+            FileExt::try_lock_shared(&file)
         } else {
-            config
+            FileExt::try_lock_exclusive(&file)
         };
-        let db = config.open()?;
-        Ok(db)
+        match res {
+            Ok(()) => {
+                let _ = FileExt::unlock(&file);
+                false
+            }
+            Err(e) => {
+                e.kind() == io::ErrorKind::WouldBlock
+                    || e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+            }
+        }
     }
 }
@@ -0,0 +1,100 @@
+#[cfg(feature = "encryption")]
+use std::sync::Arc;
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::XChaCha20Poly1305;
+
+use crate::{Error, Raw};
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
+
+/// Value encryption for a single bucket
+///
+/// Encrypted values are stored as a random nonce followed by the ciphertext, the bucket name and
+/// key are authenticated along with the value so values can't be moved to another key
+#[derive(Clone, Default)]
+pub(crate) struct Crypt(#[cfg(feature = "encryption")] Option<(Arc<XChaCha20Poly1305>, Raw)>);
+
+#[cfg(feature = "encryption")]
+impl Crypt {
+    pub(crate) fn new(key: &[u8; 32]) -> Crypt {
+        let cipher = XChaCha20Poly1305::new(key.into());
+        Crypt(Some((Arc::new(cipher), Raw::default())))
+    }
+
+    /// Use the same cipher for the named bucket
+    pub(crate) fn bucket(&self, name: &[u8]) -> Crypt {
+        Crypt(self.0.as_ref().map(|(c, _)| (c.clone(), name.into())))
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn encrypt(&self, key: &[u8], value: Raw) -> Result<Raw, Error> {
+        let (cipher, bucket) = match &self.0 {
+            Some(x) => x,
+            None => return Ok(value),
+        };
+
+        let aad = crate::ttl::key(bucket, key);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &value,
+            aad: &aad,
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| Error::Message("Unable to encrypt value".into()))?;
+
+        let mut dst = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        dst.extend_from_slice(&nonce);
+        dst.extend_from_slice(&ciphertext);
+        Ok(dst.into())
+    }
+
+    pub(crate) fn decrypt(&self, key: &[u8], value: Raw) -> Result<Raw, Error> {
+        let (cipher, bucket) = match &self.0 {
+            Some(x) => x,
+            None => return Ok(value),
+        };
+
+        let invalid = || Error::Message("Unable to decrypt value".into());
+        if value.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+
+        let aad = crate::ttl::key(bucket, key);
+        let (nonce, ciphertext) = value.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let x = cipher
+            .decrypt(nonce.into(), payload)
+            .map_err(|_| invalid())?;
+        Ok(x.into())
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Crypt {
+    pub(crate) fn bucket(&self, _name: &[u8]) -> Crypt {
+        Crypt()
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn encrypt(&self, _key: &[u8], value: Raw) -> Result<Raw, Error> {
+        Ok(value)
+    }
+
+    pub(crate) fn decrypt(&self, _key: &[u8], value: Raw) -> Result<Raw, Error> {
+        Ok(value)
+    }
+}
@@ -3,6 +3,8 @@ use std::sync::PoisonError;
 
 use thiserror::Error as TError;
 
+use crate::Raw;
+
 #[derive(Debug, TError)]
 /// Error type
 pub enum Error {
@@ -14,21 +16,90 @@ pub enum Error {
     #[error("IO error: {0}")]
     IO(#[from] io::Error),
 
-    /// Configuration is invalid
-    #[error("Configuration is invalid")]
-    InvalidConfiguration,
+    /// A transaction conflicted on every attempt allowed by its `RetryPolicy`, the number of
+    /// attempts is included in the error
+    #[error("Transaction conflict after {0} attempts")]
+    Conflict(usize),
+
+    /// A conditional operation in a batch failed, the key is included in the error and none of
+    /// the batch was applied
+    #[error("Batch condition failed for key {0:?}")]
+    ConditionFailed(crate::Raw),
+
+    /// The store is locked by another process, the operating system doesn't report which
+    /// process holds the lock
+    #[error("Store is locked by another process: {0:?}")]
+    StoreLocked(std::path::PathBuf),
+
+    /// Configuration is invalid, the reason is included in the error
+    #[error("Configuration is invalid: {0}")]
+    InvalidConfiguration(String),
+
+    /// Configuration couldn't be written as TOML
+    #[error("Unable to encode configuration: {0}")]
+    ConfigEncode(#[from] toml::ser::Error),
+
+    /// Configuration couldn't be read from TOML
+    #[error("Unable to decode configuration: {0}")]
+    ConfigDecode(#[from] toml::de::Error),
+
+    /// A `Checked` value didn't match its checksum, the key is empty when the value wasn't read
+    /// from a bucket or transaction
+    #[error("Checksum mismatch for key {key:?}")]
+    ChecksumMismatch {
+        /// Key
+        key: Raw,
+    },
+
+    /// A `Tagged` value has a tag that doesn't match any variant of the enum, the tag and the
+    /// enum type are included in the error
+    #[error("Unknown tag {tag:?} for {ty}")]
+    UnknownTag {
+        /// Tag
+        tag: String,
+        /// Name of the enum type
+        ty: &'static str,
+    },
+
+    /// A value couldn't be encoded, the bucket, key and value type are included in the error
+    #[error("Unable to encode {codec} value for key {key:?} in bucket {bucket:?}")]
+    Encode {
+        /// Bucket name
+        bucket: String,
+        /// Key
+        key: Raw,
+        /// Name of the value type
+        codec: &'static str,
+        /// Underlying error
+        #[source]
+        source: Box<Error>,
+    },
+
+    /// A value couldn't be decoded, the bucket, key and value type are included in the error
+    #[error("Unable to decode {codec} value for key {key:?} in bucket {bucket:?}")]
+    Decode {
+        /// Bucket name
+        bucket: String,
+        /// Key
+        key: Raw,
+        /// Name of the value type
+        codec: &'static str,
+        /// Underlying error
+        #[source]
+        source: Box<Error>,
+    },
 
     /// RwLock is poisoned
     #[error("RwLock is poisoned")]
     Poison,
 
     /// UTF8 Error
-    #[error("UTF8 error")]
-    Utf8(std::str::Utf8Error),
+    #[error("UTF8 error: {0}")]
+    Utf8(#[from] std::str::Utf8Error),
 
     /// String UTF8 Error
-    #[error("String UTF8 error")]
-    FromUtf8(std::string::FromUtf8Error),
+    #[error("String UTF8 error: {0}")]
+    FromUtf8(#[from] std::string::FromUtf8Error),
 
     /// SystemTime
     #[error("SystemTime: {0}")]
@@ -62,6 +133,16 @@ pub enum Error {
     #[cfg(feature = "lexpr-value")]
     #[error("S-Expression error: {0}")]
     Lexpr(#[from] serde_lexpr::Error),
+
+    /// CBOR error
+    #[cfg(feature = "cbor-value")]
+    #[error("CBOR error: {0}")]
+    Cbor(#[from] serde_cbor::Error),
+
+    /// LZ4 decompression error
+    #[cfg(feature = "lz4-value")]
+    #[error("LZ4 error: {0}")]
+    Lz4(#[from] lz4_flex::block::DecompressError),
 }
 
 impl<T> From<PoisonError<T>> for Error {
@@ -70,18 +151,24 @@ impl<T> From<PoisonError<T>> for Error {
     }
 }
 
-impl From<std::str::Utf8Error> for Error {
-    fn from(e: std::str::Utf8Error) -> Error {
-        Error::Utf8(e)
-    }
+/// Returned by `Bucket::compare_and_swap` when the current value isn't the expected one
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareAndSwapError<V> {
+    /// Current value, `None` if the key has no value
+    pub current: Option<V>,
+
+    /// Value that would have been written
+    pub proposed: Option<V>,
 }
 
-impl From<std::string::FromUtf8Error> for Error {
-    fn from(e: std::string::FromUtf8Error) -> Error {
-        Error::FromUtf8(e)
+impl<V> std::fmt::Display for CompareAndSwapError<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Compare and swap conflict")
     }
 }
 
+impl<V: std::fmt::Debug> std::error::Error for CompareAndSwapError<V> {}
+
 impl From<Error> for sled::ConflictableTransactionError<Error> {
     fn from(e: Error) -> sled::ConflictableTransactionError<Error> {
         sled::ConflictableTransactionError::Abort(e)
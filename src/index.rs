@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::ops::{Bound, RangeBounds};
+
+use crate::changes;
+use crate::composite::encode_bytes;
+use crate::crypt::Crypt;
+use crate::transaction::{run_on, stash_abort, unstash_abort};
+use crate::ttl;
+use crate::{Bucket, Error, Item, Key, Raw, TransactionError, Value};
+
+/// Name of the tree used to store secondary indexes
+pub(crate) const TREE_NAME: &str = "__kv__index";
+
+/// Function used to get the index keys for a value
+pub type Extractor<V> = fn(&V) -> Vec<Raw>;
+
+/// Index entries are the length of the bucket name, the bucket name, the length of the index
+/// name and the index name followed by the encoded index key and the primary key
+fn prefix(bucket: &[u8], index: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(8 + bucket.len() + index.len());
+    dst.extend_from_slice(&(bucket.len() as u32).to_be_bytes());
+    dst.extend_from_slice(bucket);
+    dst.extend_from_slice(&(index.len() as u32).to_be_bytes());
+    dst.extend_from_slice(index);
+    dst
+}
+
+fn entry(prefix: &[u8], index_key: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut dst = prefix.to_vec();
+    encode_bytes(index_key, &mut dst);
+    dst.extend_from_slice(key);
+    dst
+}
+
+// Skip over the encoded index key, returning the primary key
+fn primary_key(entry: &[u8]) -> Option<&[u8]> {
+    let mut i = 0;
+    while i + 1 < entry.len() {
+        match (entry[i], entry[i + 1]) {
+            (0, 0) => return Some(&entry[i + 2..]),
+            (0, _) => i += 2,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+// Index keys are terminated by `0x00 0x00`, which makes `0x00 0x01` the smallest suffix that
+// sorts after every entry with the given index key
+fn bound<X: AsRef<[u8]>>(prefix: &[u8], b: Bound<&X>, start: bool) -> Bound<Vec<u8>> {
+    let after = |x: &X| {
+        let mut dst = entry(prefix, x.as_ref(), b"");
+        let n = dst.len();
+        dst[n - 1] = 1;
+        dst
+    };
+
+    match b {
+        Bound::Included(x) if start => Bound::Included(entry(prefix, x.as_ref(), b"")),
+        Bound::Included(x) => Bound::Excluded(after(x)),
+        Bound::Excluded(x) if start => Bound::Included(after(x)),
+        Bound::Excluded(x) => Bound::Excluded(entry(prefix, x.as_ref(), b"")),
+        Bound::Unbounded if start => Bound::Included(prefix.to_vec()),
+        Bound::Unbounded => {
+            // The smallest key that sorts after every entry starting with `prefix`
+            let mut dst = prefix.to_vec();
+            while let Some(0xff) = dst.last() {
+                dst.pop();
+            }
+            match dst.last_mut() {
+                Some(x) => {
+                    *x += 1;
+                    Bound::Excluded(dst)
+                }
+                None => Bound::Unbounded,
+            }
+        }
+    }
+}
+
+/// Remove all index entries for the named bucket
+pub(crate) fn clear(index: &sled::Tree, bucket: &[u8]) -> Result<(), Error> {
+    let mut p = Vec::with_capacity(4 + bucket.len());
+    p.extend_from_slice(&(bucket.len() as u32).to_be_bytes());
+    p.extend_from_slice(bucket);
+    for k in index.scan_prefix(p).keys() {
+        index.remove(k?)?;
+    }
+    Ok(())
+}
+
+/// A `Bucket` with secondary indexes
+///
+/// Index entries are updated in the same transaction as the value when using `set` and
+/// `remove`. Changes made through other handles to the bucket are not indexed, `reindex` can be
+/// used to rebuild the indexes after such changes.
+pub struct Indexed<'a, K: Key<'a>, V: Value> {
+    bucket: Bucket<'a, K, V>,
+    index: sled::Tree,
+    indexes: Vec<(String, Vec<u8>, Extractor<V>)>,
+}
+
+impl<'a, K: Key<'a>, V: Value> Indexed<'a, K, V> {
+    pub(crate) fn new(bucket: Bucket<'a, K, V>, index: sled::Tree) -> Indexed<'a, K, V> {
+        Indexed {
+            bucket,
+            index,
+            indexes: Vec::new(),
+        }
+    }
+
+    /// Register an index, `f` is used to get the index keys for each value
+    pub fn index<S: AsRef<str>>(mut self, name: S, f: Extractor<V>) -> Indexed<'a, K, V> {
+        let p = prefix(&self.bucket.tree.name(), name.as_ref().as_bytes());
+        self.indexes.push((name.as_ref().to_string(), p, f));
+        self
+    }
+
+    /// Get the underlying bucket
+    pub fn bucket(&self) -> &Bucket<'a, K, V> {
+        &self.bucket
+    }
+
+    fn prefix(&self, name: &str) -> Result<&[u8], Error> {
+        match self.indexes.iter().find(|x| x.0 == name) {
+            Some(x) => Ok(&x.1),
+            None => Err(Error::Message(format!("Unknown index: {}", name))),
+        }
+    }
+
+    /// Returns true if the bucket contains the given key
+    pub fn contains<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        self.bucket.contains(key)
+    }
+
+    /// Get the value associated with the specified key
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<V>, Error> {
+        self.bucket.get(key)
+    }
+
+    /// Set the value associated with the specified key to the provided value and update the
+    /// indexes
+    pub fn set<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
+        let value = value.into();
+        let key = key.into().to_raw_key()?;
+        let v = self.bucket.encode(&key, &value)?;
+        let mut entries = Vec::new();
+        for (_, p, f) in &self.indexes {
+            for x in f(&value) {
+                entries.push(entry(p, &x, &key));
+            }
+        }
+
+        self.write(&key, Some(v), |index| {
+            for e in &entries {
+                index.insert(e.as_slice(), b"")?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove the value associated with the specified key and its index entries
+    pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        self.write(&key, None, |_| Ok(()))
+    }
+
+    // Set or remove an encoded key in a transaction that removes the index entries for the old
+    // value, calls `f` to add entries for the new one, clears the expiration time and records
+    // the change in the changefeed when it is enabled
+    fn write<F: Fn(&sled::TransactionalTree) -> Result<(), TransactionError<Error>>>(
+        &self,
+        key: &Raw,
+        value: Option<Raw>,
+        f: F,
+    ) -> Result<(), Error> {
+        let name = self.bucket.tree.name();
+        let ttl_key = ttl::key(&name, key);
+        let mut trees = vec![&self.bucket.tree, &self.bucket.ttl, &self.index];
+        trees.extend(self.bucket.changefeed.iter());
+
+        let error = RefCell::new(None);
+        let res = run_on(&trees, |t| {
+            let res = (|| {
+                t[1].remove(ttl_key.as_slice())?;
+                let old = match &value {
+                    Some(v) => t[0].insert(key, v)?,
+                    None => t[0].remove(key)?,
+                };
+                if let Some(old) = &old {
+                    self.remove_entries(t[2], key, old.clone())?;
+                }
+                f(t[2])?;
+                if let Some(log) = t.get(3) {
+                    changes::record(log, &name, key, old.as_deref(), value.as_deref())?;
+                }
+                Ok(())
+            })();
+            stash_abort(&error, res)
+        });
+        unstash_abort(error, res)
+    }
+
+    fn remove_entries(
+        &self,
+        index: &sled::TransactionalTree,
+        key: &[u8],
+        old: Raw,
+    ) -> Result<(), TransactionError<Error>> {
+        let old = self
+            .bucket
+            .decode(key, old)
+            .map_err(TransactionError::Abort)?;
+        for (_, p, f) in &self.indexes {
+            for x in f(&old) {
+                index.remove(entry(p, &x, key))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get all items with the given key in the named index
+    pub fn get_by_index<X: AsRef<[u8]>>(
+        &self,
+        name: &str,
+        index_key: X,
+    ) -> Result<IndexIter<K, V>, Error> {
+        let p = entry(self.prefix(name)?, index_key.as_ref(), b"");
+        Ok(IndexIter::new(self, self.index.scan_prefix(p)))
+    }
+
+    /// Get all items with keys in the named index within the given bounds, ordered by index key
+    pub fn index_range<X: AsRef<[u8]>, R: RangeBounds<X>>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Result<IndexIter<K, V>, Error> {
+        let p = self.prefix(name)?;
+        let a = bound(p, range.start_bound(), true);
+        let b = bound(p, range.end_bound(), false);
+        Ok(IndexIter::new(self, self.index.range((a, b))))
+    }
+
+    /// Rebuild all indexes from the values in the bucket
+    ///
+    /// This is not atomic, concurrent updates may leave the indexes incomplete
+    pub fn reindex(&self) -> Result<(), Error> {
+        clear(&self.index, &self.bucket.tree.name())?;
+        for item in self.bucket.tree.iter() {
+            let (k, v) = item?;
+            let v = self.bucket.decode(&k, v)?;
+            for (_, p, f) in &self.indexes {
+                for x in f(&v) {
+                    self.index.insert(entry(p, &x, &k), b"")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over items found using a secondary index
+///
+/// Entries that refer to keys that no longer exist in the bucket or have expired are skipped
+pub struct IndexIter<K, V> {
+    bucket: sled::Tree,
+    crypt: Crypt,
+    live: ttl::Live,
+    iter: sled::Iter,
+    prefix_len: usize,
+    _marker: std::marker::PhantomData<(K, V)>,
+}
+
+impl<K, V> IndexIter<K, V> {
+    fn new<'a>(indexed: &Indexed<'a, K, V>, iter: sled::Iter) -> IndexIter<K, V>
+    where
+        K: Key<'a>,
+        V: Value,
+    {
+        IndexIter {
+            bucket: indexed.bucket.tree.clone(),
+            crypt: indexed.bucket.crypt.clone(),
+            live: ttl::Live::new(&indexed.bucket.ttl, indexed.bucket.tree.name()),
+            iter,
+            prefix_len: 8 + indexed.bucket.tree.name().len(),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    fn entry(&self, k: &[u8]) -> Result<Option<Item<K, V>>, Error> {
+        // Skip the bucket name and then the index name
+        let key = k
+            .get(self.prefix_len - 4..self.prefix_len)
+            .and_then(|n| {
+                let mut len = [0u8; 4];
+                len.copy_from_slice(n);
+                let start = self
+                    .prefix_len
+                    .checked_add(u32::from_be_bytes(len) as usize)?;
+                k.get(start..)
+            })
+            .and_then(primary_key);
+        let key = match key {
+            Some(key) => key,
+            None => return Err(Error::Message("Invalid index entry".into())),
+        };
+
+        if self.live.is_expired(key)? {
+            return Ok(None);
+        }
+        match self.bucket.get(key)? {
+            Some(v) => {
+                let v = self.crypt.decrypt(key, v)?;
+                Ok(Some(Item::new(key.into(), v, self.bucket.name())))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<K, V> Iterator for IndexIter<K, V> {
+    type Item = Result<Item<K, V>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let k = match self.iter.next()? {
+                Ok((k, _)) => k,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            match self.entry(&k) {
+                Ok(Some(item)) => return Some(Ok(item)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
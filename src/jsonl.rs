@@ -0,0 +1,60 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::{Error, Raw};
+
+// Keys and values are written as strings when they are valid UTF-8 and as arrays of bytes
+// otherwise
+fn to_json(x: &[u8]) -> JsonValue {
+    match std::str::from_utf8(x) {
+        Ok(s) => JsonValue::String(s.to_string()),
+        Err(_) => JsonValue::Array(x.iter().map(|b| JsonValue::from(*b)).collect()),
+    }
+}
+
+fn from_json(x: Option<&JsonValue>, line: usize) -> Result<Raw, Error> {
+    let invalid = || Error::Message(format!("Invalid JSON record on line {}", line));
+    match x {
+        Some(JsonValue::String(s)) => Ok(s.as_str().into()),
+        Some(JsonValue::Array(a)) => {
+            let mut dst = Vec::with_capacity(a.len());
+            for b in a {
+                match b.as_u64() {
+                    Some(b) if b <= 255 => dst.push(b as u8),
+                    _ => return Err(invalid()),
+                }
+            }
+            Ok(dst.into())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+pub(crate) fn export<W: Write>(tree: &sled::Tree, w: W) -> Result<(), Error> {
+    let mut w = BufWriter::new(w);
+    for item in tree.iter() {
+        let (k, v) = item?;
+        let record = json!({"key": to_json(&k), "value": to_json(&v)});
+        serde_json::to_writer(&mut w, &record)?;
+        w.write_all(b"\n")?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+pub(crate) fn import<R: Read>(r: R) -> Result<Vec<(Raw, Option<Raw>)>, Error> {
+    let r = BufReader::new(r);
+    let mut batch = Vec::new();
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: JsonValue = serde_json::from_str(&line)?;
+        let k = from_json(record.get("key"), i + 1)?;
+        let v = from_json(record.get("value"), i + 1)?;
+        batch.push((k, Some(v)));
+    }
+    Ok(batch)
+}
@@ -52,13 +52,13 @@
 //!         }
 //!
 //!         // A transaction
-//!         bucket.transaction(|txn| {
+//!         bucket.transaction(|txn: Transaction<_, _>| {
 //!             txn.set("x", Json(SomeType {a: 1, b: 2}))?;
 //!             txn.set("y", Json(SomeType {a: 3, b: 4}))?;
 //!             txn.set("z", Json(SomeType {a: 5, b: 6}))?;
 //!
 //!             // A nested transaction
-//!             test.transaction(|txn2| {
+//!             test.transaction(|txn2: Transaction<_, _>| {
 //!                 let x = txn.get("x")?.unwrap();
 //!                 let v = format!("{}", x.as_ref().a);
 //!                 txn2.set(b"x", v.as_str())?;
@@ -75,21 +75,86 @@
 //! # }
 //! ```
 
+// Allows code generated by `kv_derive` to refer to `::kv` from within this crate
+#[cfg(feature = "derive")]
+extern crate self as kv;
+
+#[cfg(feature = "async")]
+pub mod asynch;
+mod backup;
+mod blob;
 mod bucket;
+mod bulk;
+mod cache;
+mod changes;
+mod checked;
 mod codec;
+mod compact;
+mod composite;
+mod compression;
 mod config;
+mod crypt;
 mod error;
+mod index;
+#[cfg(feature = "json-value")]
+mod jsonl;
+mod lock;
+mod migrate;
+mod observer;
+mod page;
+mod queue;
+mod recovery;
+pub mod replication;
+mod scoped;
+pub mod serde_raw;
 mod store;
+mod store_batch;
+mod tagged;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+mod tiered;
+mod timeseries;
 mod transaction;
+mod ttl;
 mod types;
+mod verify;
 
-pub use bucket::{Batch, Bucket, Event, Item, Iter, Watch};
+pub use blob::{BlobBucket, BlobReader, BlobWriter};
+pub use bucket::{Batch, Bucket, Entry, Event, Item, Iter, Keys, Values, Watch};
+pub use bulk::BulkInsert;
+pub use cache::{CacheBucket, CacheLimit};
+pub use changes::{Change, Changes};
+pub use checked::Checked;
 pub use codec::*;
-pub use config::Config;
-pub use error::Error;
+pub use compact::SpaceUsage;
+pub use composite::{Composite, KeyPart};
+#[cfg(feature = "lz4-value")]
+pub use compression::Lz4;
+#[cfg(feature = "zstd-value")]
+pub use compression::Zstd;
+pub use compression::{Compressed, Compression};
+pub use config::{Config, SegmentMode};
+pub use error::{CompareAndSwapError, Error};
+pub use index::{Extractor, IndexIter, Indexed};
+#[cfg(feature = "derive")]
+pub use kv_derive::{KeyPart, Value};
+pub use lock::Lock;
+pub use migrate::Migrations;
+#[cfg(feature = "metrics")]
+pub use observer::MetricsObserver;
+pub use observer::{Operation, StoreObserver};
+pub use page::{Cursor, Page};
+pub use queue::Queue;
+pub use recovery::{RecoveredBucket, RecoveryPolicy, RecoveryReport};
+pub use scoped::Scoped;
 pub use store::Store;
-pub use transaction::{Transaction, TransactionError};
-pub use types::{Integer, Key, Raw, Value};
+pub use store_batch::StoreBatch;
+pub use tagged::{Tagged, TaggedEnum};
+pub use tiered::{TieredBucket, WritePolicy};
+pub use timeseries::{Point, Points, TimeSeriesBucket};
+pub use transaction::{RetryPolicy, Transaction, TransactionError, TransactionStats};
+pub use types::{Integer, Key, Merge, Raw, SignedInteger, Value};
+pub use verify::{BucketReport, CorruptEntry, IntegrityReport, Verify};
 
 /// Abort a transaction
 pub fn abort<E>(x: E) -> TransactionError<E> {
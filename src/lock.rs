@@ -0,0 +1,179 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::observer::Handle;
+use crate::{ttl, Error, Raw};
+
+/// Name of the tree used to store locks
+pub(crate) const TREE_NAME: &str = "__kv__locks";
+
+// How long `acquire` waits before checking a held lock again
+const RETRY: Duration = Duration::from_millis(10);
+
+// Identifies the holder of a lock, sled only allows a store to be opened by one process so
+// this only needs to be unique within the process
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+// Bucket key locks are stored under `ttl::key`, which starts with the length of the bucket name,
+// so store locks start with a byte no bucket name length can start with
+const STORE_NAMESPACE: u8 = 0xff;
+
+/// Key of the store lock called `name`
+pub(crate) fn store_key(name: &str) -> Raw {
+    let mut dst = Vec::with_capacity(1 + name.len());
+    dst.push(STORE_NAMESPACE);
+    dst.extend_from_slice(name.as_bytes());
+    dst.into()
+}
+
+// Lock entries are the lease deadline followed by the holder's token
+fn entry(lease: Duration, token: &[u8]) -> Result<Raw, Error> {
+    let mut dst = ttl::deadline(lease)?.to_vec();
+    dst.extend_from_slice(token);
+    Ok(dst.into())
+}
+
+// An entry too short to hold a deadline can't have been written by `entry`, so it's treated as
+// expired rather than holding the lock forever
+fn expired(entry: &[u8]) -> Result<bool, Error> {
+    if entry.len() < 8 {
+        return Ok(true);
+    }
+    Ok(ttl::is_expired(&entry[..8], ttl::now()?))
+}
+
+/// Try to acquire the lock stored under `key`, returns `None` if it is held by someone else
+///
+/// `handle` is held by the lock so the store can tell it's still in use, see `Store::compact`
+pub(crate) fn try_acquire(
+    tree: &sled::Tree,
+    key: Raw,
+    lease: Duration,
+    handle: Handle,
+) -> Result<Option<Lock>, Error> {
+    let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+    loop {
+        let current = tree.get(&key)?;
+        if let Some(x) = &current {
+            if !expired(x)? {
+                return Ok(None);
+            }
+        }
+
+        // The lock may have been acquired or released since it was read
+        let value = entry(lease, &token)?;
+        if tree
+            .compare_and_swap(&key, current, Some(value.clone()))?
+            .is_ok()
+        {
+            return Ok(Some(Lock {
+                tree: tree.clone(),
+                key,
+                value,
+                released: false,
+                _handle: handle,
+            }));
+        }
+    }
+}
+
+/// Acquire the lock stored under `key`, waiting until it is released or its lease expires
+pub(crate) fn acquire(
+    tree: &sled::Tree,
+    key: Raw,
+    lease: Duration,
+    handle: Handle,
+) -> Result<Lock, Error> {
+    loop {
+        if let Some(lock) = try_acquire(tree, key.clone(), lease, handle.clone())? {
+            return Ok(lock);
+        }
+        thread::sleep(RETRY);
+    }
+}
+
+/// Like `acquire`, but returns `None` if the lock is still held once `timeout` has passed
+pub(crate) fn acquire_timeout(
+    tree: &sled::Tree,
+    key: Raw,
+    lease: Duration,
+    timeout: Duration,
+    handle: Handle,
+) -> Result<Option<Lock>, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(lock) = try_acquire(tree, key.clone(), lease, handle.clone())? {
+            return Ok(Some(lock));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Ok(None);
+        }
+        thread::sleep(remaining.min(RETRY));
+    }
+}
+
+/// Advisory lock returned by `Store::lock` and `Bucket::lock_key`, the lock is released when
+/// this is dropped
+///
+/// Locks don't prevent anything from being written, they only exclude other callers trying to
+/// acquire the same lock. A lock is lost once its lease expires unless it is renewed first,
+/// after which someone else can acquire it.
+pub struct Lock {
+    tree: sled::Tree,
+    key: Raw,
+    value: Raw,
+    released: bool,
+    _handle: Handle,
+}
+
+impl Lock {
+    /// Returns true until the lease expires
+    pub fn is_held(&self) -> Result<bool, Error> {
+        let current = self.tree.get(&self.key)?;
+        Ok(current.as_ref() == Some(&self.value) && !expired(&self.value)?)
+    }
+
+    /// Extend the lease so it expires `lease` from now, returns false if the lock has already
+    /// been lost
+    pub fn renew(&mut self, lease: Duration) -> Result<bool, Error> {
+        if !self.is_held()? {
+            return Ok(false);
+        }
+
+        let value = entry(lease, &self.value[8..])?;
+        match self
+            .tree
+            .compare_and_swap(&self.key, Some(&self.value), Some(value.clone()))?
+        {
+            Ok(()) => {
+                self.value = value;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn unlock(&self) -> Result<bool, Error> {
+        let held = self.is_held()?;
+        let _ = self
+            .tree
+            .compare_and_swap(&self.key, Some(&self.value), None as Option<Raw>)?;
+        Ok(held)
+    }
+
+    /// Release the lock, returns false if it had already been lost
+    pub fn release(mut self) -> Result<bool, Error> {
+        self.released = true;
+        self.unlock()
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.unlock();
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{Error, Store};
+
+/// Name of the tree used to store the schema version
+pub(crate) const TREE_NAME: &str = "__kv__meta";
+pub(crate) const VERSION_KEY: &[u8] = b"schema_version";
+
+type Migration<'a> = Box<dyn FnOnce(&Store) -> Result<(), Error> + 'a>;
+
+/// Ordered set of migrations, created using `Store::migrations`
+///
+/// Each migration is identified by the schema version it upgrades to. When `run` is called every
+/// migration with a version greater than the stored schema version is run in order, and the
+/// stored version is updated after each one. If a migration fails the version is left at the
+/// last successful migration so the remaining migrations are retried on the next run,
+/// migrations that can fail part way through should be safe to run again.
+pub struct Migrations<'a> {
+    store: &'a Store,
+    migrations: Vec<(u64, Migration<'a>)>,
+}
+
+impl<'a> Migrations<'a> {
+    pub(crate) fn new(store: &'a Store) -> Migrations<'a> {
+        Migrations {
+            store,
+            migrations: Vec::new(),
+        }
+    }
+
+    /// Register a migration that upgrades the store to `version`
+    pub fn add<F: FnOnce(&Store) -> Result<(), Error> + 'a>(
+        mut self,
+        version: u64,
+        f: F,
+    ) -> Migrations<'a> {
+        self.migrations.push((version, Box::new(f)));
+        self
+    }
+
+    /// Run all pending migrations, returns the new schema version
+    pub fn run(mut self) -> Result<u64, Error> {
+        self.migrations.sort_by_key(|x| x.0);
+        for w in self.migrations.windows(2) {
+            if w[0].0 == w[1].0 {
+                return Err(Error::Message(format!(
+                    "Duplicate migration version: {}",
+                    w[0].0
+                )));
+            }
+        }
+
+        let mut current = self.store.schema_version()?;
+        for (version, f) in self.migrations {
+            if version <= current {
+                continue;
+            }
+
+            f(self.store)?;
+            self.store.set_schema_version(version)?;
+            current = version;
+        }
+
+        Ok(current)
+    }
+}
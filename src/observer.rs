@@ -0,0 +1,259 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::Config;
+
+/// Bucket operations reported to a `StoreObserver`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    /// `Bucket::get`
+    Get,
+
+    /// `Bucket::set`
+    Set,
+
+    /// `Bucket::remove`
+    Remove,
+
+    /// `Bucket::contains`
+    Contains,
+
+    /// `Bucket::batch`
+    Batch,
+
+    /// `Bucket::transaction` and `Bucket::transaction_with_retry`
+    Transaction,
+
+    /// `Bucket::swap`
+    Swap,
+
+    /// `Bucket::take`
+    Take,
+
+    /// `Bucket::compare_and_swap`
+    CompareAndSwap,
+
+    /// `Bucket::fetch_and_update`
+    FetchAndUpdate,
+}
+
+impl Operation {
+    /// Get the name of the operation
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::Set => "set",
+            Operation::Remove => "remove",
+            Operation::Contains => "contains",
+            Operation::Batch => "batch",
+            Operation::Transaction => "transaction",
+            Operation::Swap => "swap",
+            Operation::Take => "take",
+            Operation::CompareAndSwap => "compare_and_swap",
+            Operation::FetchAndUpdate => "fetch_and_update",
+        }
+    }
+}
+
+/// Receives metrics from a `Store` and its buckets, see `Store::set_observer`
+///
+/// Every method has an empty default implementation so only the interesting ones need to be
+/// implemented. Methods are called on the thread performing the operation, so they should
+/// return quickly.
+pub trait StoreObserver: Send + Sync {
+    /// Called after each bucket operation with the time it took, including failed operations
+    fn operation(&self, _bucket: &str, _op: Operation, _elapsed: Duration) {}
+
+    /// Called with the number of updates in each batch applied to a bucket
+    fn batch(&self, _bucket: &str, _size: usize) {}
+
+    /// Called each time a transaction is retried because of a conflict
+    fn transaction_retry(&self, _bucket: &str) {}
+
+    /// Called after each successful flush with the time it took and the number of bytes flushed
+    fn flush(&self, _elapsed: Duration, _bytes: usize) {}
+}
+
+/// Shared handle to the observer for a store, which does nothing when no observer is set
+///
+/// When the `tracing` feature is enabled each operation is also run inside a span and
+/// operations slower than `slow` are logged
+///
+/// Every bucket holds a clone, so an observer set later is used by buckets that are already
+/// open. Buckets and locks also hold a `Handle`, which counts them so the store can tell whether
+/// any of them are still open.
+#[derive(Clone, Default)]
+pub(crate) struct Observer(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    observer: RwLock<Option<Arc<dyn StoreObserver>>>,
+    handles: AtomicUsize,
+    #[cfg(feature = "tracing")]
+    slow: Option<Duration>,
+}
+
+/// Held by every bucket and lock while it's open, see `Observer::handles`
+pub(crate) struct Handle(Observer);
+
+impl Clone for Handle {
+    fn clone(&self) -> Handle {
+        self.0.handle()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        (self.0).0.handles.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn name(tree: &sled::Tree) -> String {
+    String::from_utf8_lossy(&tree.name()).into_owned()
+}
+
+impl Observer {
+    pub(crate) fn new(config: &Config) -> Observer {
+        #[cfg(not(feature = "tracing"))]
+        let _ = config;
+
+        Observer(Arc::new(Shared {
+            observer: RwLock::new(None),
+            handles: AtomicUsize::new(0),
+            #[cfg(feature = "tracing")]
+            slow: config
+                .slow_operation_threshold_ms
+                .map(Duration::from_millis),
+        }))
+    }
+
+    pub(crate) fn set(&self, observer: Arc<dyn StoreObserver>) {
+        if let Ok(mut x) = self.0.observer.write() {
+            *x = Some(observer);
+        }
+    }
+
+    fn observer(&self) -> Option<Arc<dyn StoreObserver>> {
+        self.0.observer.read().ok()?.clone()
+    }
+
+    /// Returns a new handle counted by `handles`
+    pub(crate) fn handle(&self) -> Handle {
+        self.0.handles.fetch_add(1, Ordering::SeqCst);
+        Handle(self.clone())
+    }
+
+    /// Returns the number of buckets and locks that are still open
+    pub(crate) fn handles(&self) -> usize {
+        self.0.handles.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if `other` is a clone of this observer, so both belong to the same store
+    pub(crate) fn is_store(&self, other: &Observer) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    #[cfg(feature = "tracing")]
+    fn is_slow(&self, elapsed: Duration) -> bool {
+        self.0.slow.is_some_and(|slow| elapsed >= slow)
+    }
+
+    /// Run `f`, reporting how long it took as `op` on `tree`
+    pub(crate) fn time<T, F: FnOnce() -> T>(&self, tree: &sled::Tree, op: Operation, f: F) -> T {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "kv",
+            bucket = %String::from_utf8_lossy(&tree.name()),
+            op = op.as_str()
+        )
+        .entered();
+
+        let start = Instant::now();
+        let x = f();
+        let elapsed = start.elapsed();
+
+        #[cfg(feature = "tracing")]
+        if self.is_slow(elapsed) {
+            tracing::warn!(elapsed_ms = elapsed.as_millis() as u64, "slow operation");
+        }
+
+        if let Some(o) = self.observer() {
+            o.operation(&name(tree), op, elapsed);
+        }
+        x
+    }
+
+    pub(crate) fn batch(&self, tree: &sled::Tree, size: usize) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(bucket = %String::from_utf8_lossy(&tree.name()), size, "batch");
+
+        if let Some(o) = self.observer() {
+            o.batch(&name(tree), size)
+        }
+    }
+
+    pub(crate) fn transaction_retry(&self, tree: &sled::Tree) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(bucket = %String::from_utf8_lossy(&tree.name()), "transaction conflict");
+
+        if let Some(o) = self.observer() {
+            o.transaction_retry(&name(tree))
+        }
+    }
+
+    /// Report a flush that started at `start`
+    pub(crate) fn flushed(&self, start: Instant, bytes: usize) {
+        let elapsed = start.elapsed();
+
+        #[cfg(feature = "tracing")]
+        if self.is_slow(elapsed) {
+            tracing::warn!(elapsed_ms = elapsed.as_millis() as u64, bytes, "slow flush");
+        }
+
+        if let Some(o) = self.observer() {
+            o.flush(elapsed, bytes)
+        }
+    }
+}
+
+/// `StoreObserver` that records metrics using the `metrics` crate
+///
+/// The following metrics are recorded, labeled by `bucket` and `op` where applicable:
+///
+/// - `kv_operations_total` counter
+/// - `kv_operation_duration_seconds` histogram
+/// - `kv_batch_size` histogram
+/// - `kv_transaction_retries_total` counter
+/// - `kv_flush_duration_seconds` histogram
+/// - `kv_flush_bytes_total` counter
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsObserver;
+
+#[cfg(feature = "metrics")]
+impl StoreObserver for MetricsObserver {
+    fn operation(&self, bucket: &str, op: Operation, elapsed: Duration) {
+        let labels = [
+            ("bucket", bucket.to_string()),
+            ("op", op.as_str().to_string()),
+        ];
+        metrics::counter!("kv_operations_total", &labels).increment(1);
+        metrics::histogram!("kv_operation_duration_seconds", &labels).record(elapsed);
+    }
+
+    fn batch(&self, bucket: &str, size: usize) {
+        metrics::histogram!("kv_batch_size", "bucket" => bucket.to_string()).record(size as f64);
+    }
+
+    fn transaction_retry(&self, bucket: &str) {
+        metrics::counter!("kv_transaction_retries_total", "bucket" => bucket.to_string())
+            .increment(1);
+    }
+
+    fn flush(&self, elapsed: Duration, bytes: usize) {
+        metrics::histogram!("kv_flush_duration_seconds").record(elapsed);
+        metrics::counter!("kv_flush_bytes_total").increment(bytes as u64);
+    }
+}
@@ -0,0 +1,64 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Item, Raw};
+
+/// Position in a bucket returned by `Bucket::page`, used to fetch the next page
+///
+/// Cursors are serialized as hex encoded strings, so they can be passed to clients unchanged
+/// and parsed again using `FromStr`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Cursor(pub(crate) Raw);
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cursor, Error> {
+        let invalid = || Error::Message(format!("Invalid cursor: {:?}", s));
+        if !s.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let mut dst = Vec::with_capacity(s.len() / 2);
+        for i in (0..s.len()).step_by(2) {
+            let b = s.get(i..i + 2).ok_or_else(invalid)?;
+            dst.push(u8::from_str_radix(b, 16).map_err(|_| invalid())?);
+        }
+        Ok(Cursor(dst.into()))
+    }
+}
+
+impl From<Cursor> for String {
+    fn from(c: Cursor) -> String {
+        c.to_string()
+    }
+}
+
+impl TryFrom<String> for Cursor {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Cursor, Error> {
+        s.parse()
+    }
+}
+
+/// A page of items returned by `Bucket::page`
+pub struct Page<K, V> {
+    /// Items in the page, ordered by key
+    pub items: Vec<Item<K, V>>,
+
+    /// Cursor for the next page, `None` when this is the last page
+    pub next: Option<Cursor>,
+}
@@ -0,0 +1,48 @@
+use crate::{Bucket, Error, Item, Key, Value};
+
+/// A persistent queue backed by a `Bucket`, items are popped in key order
+///
+/// Created using `Bucket::queue`. Using keys that increase over time, such as timestamps,
+/// gives a FIFO queue while keys that encode a priority give a priority queue.
+pub struct Queue<'a, K: Key<'a>, V: Value>(Bucket<'a, K, V>);
+
+impl<'a, K: Key<'a>, V: Value> Queue<'a, K, V> {
+    pub(crate) fn new(bucket: Bucket<'a, K, V>) -> Queue<'a, K, V> {
+        Queue(bucket)
+    }
+
+    /// Get the underlying bucket
+    pub fn bucket(&self) -> &Bucket<'a, K, V> {
+        &self.0
+    }
+
+    /// Add an item to the queue
+    pub fn push<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
+        self.0.set(key, value)
+    }
+
+    /// Get the item with the smallest key without removing it
+    pub fn peek(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.0.first()
+    }
+
+    /// Remove and return the item with the smallest key
+    pub fn pop(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.0.pop_front()
+    }
+
+    /// Remove and return the item with the largest key
+    pub fn pop_back(&self) -> Result<Option<Item<K, V>>, Error> {
+        self.0.pop_back()
+    }
+
+    /// Get the number of items
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true when there are no items
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
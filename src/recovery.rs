@@ -0,0 +1,370 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::{Error, Raw};
+
+/// What `Store::open_with_recovery` does with a damaged store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryPolicy {
+    /// Copy every readable entry into a new store, an error is still returned if sled can't open
+    /// the store even after discarding its settings and snapshots
+    Salvage,
+
+    /// Like `Salvage`, but a store that can't be opened at all is moved aside and replaced with
+    /// an empty store
+    Reset,
+}
+
+/// Entries recovered from a single bucket by `Store::open_with_recovery`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveredBucket {
+    /// Bucket name, internal trees used by `kv` are included
+    pub name: String,
+
+    /// Number of entries recovered
+    pub recovered: u64,
+
+    /// True if the whole bucket was read, when this is false entries between the damaged part
+    /// and the last entry that could be read in either direction were lost
+    pub complete: bool,
+
+    /// Description of the error that stopped the bucket from being read completely
+    pub error: Option<String>,
+}
+
+/// Result of `Store::open_with_recovery`
+///
+/// sled can't read past damaged data, so the number of lost entries isn't known. Buckets are
+/// read forwards and then backwards from the end, so only entries in the damaged part are lost.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Buckets that were read, empty if the store was reset
+    pub buckets: Vec<RecoveredBucket>,
+
+    /// Path the damaged store was moved to, `None` if the store wasn't damaged
+    pub damaged: Option<PathBuf>,
+}
+
+impl RecoveryReport {
+    /// Returns true if the store wasn't damaged
+    pub fn is_ok(&self) -> bool {
+        self.damaged.is_none()
+    }
+
+    /// Returns true if the store couldn't be opened and was replaced with an empty one
+    pub fn is_reset(&self) -> bool {
+        self.damaged.is_some() && self.buckets.is_empty()
+    }
+}
+
+// Copies entries in batches so a large bucket isn't held in memory
+struct Copier<'a> {
+    dst: Option<&'a sled::Tree>,
+    batch: sled::Batch,
+    pending: usize,
+    n: u64,
+}
+
+impl<'a> Copier<'a> {
+    fn insert(&mut self, k: Raw, v: Raw) -> Result<(), Error> {
+        self.n += 1;
+        if let Some(dst) = self.dst {
+            self.batch.insert(k, v);
+            self.pending += 1;
+            if self.pending == 1024 {
+                dst.apply_batch(std::mem::take(&mut self.batch))?;
+                self.pending = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<u64, Error> {
+        if let Some(dst) = self.dst {
+            dst.apply_batch(self.batch)?;
+        }
+        Ok(self.n)
+    }
+}
+
+fn salvage_tree(tree: &sled::Tree, dst: Option<&sled::Tree>) -> Result<RecoveredBucket, Error> {
+    let mut copier = Copier {
+        dst,
+        batch: sled::Batch::default(),
+        pending: 0,
+        n: 0,
+    };
+
+    let mut error = None;
+    let mut last = None;
+    for item in tree.iter() {
+        match item {
+            Ok((k, v)) => {
+                last = Some(k.clone());
+                copier.insert(k, v)?;
+            }
+            Err(e) => {
+                error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+
+    // sled iterators can't continue past an error, so read back from the end until reaching
+    // the damage or the last entry read going forwards
+    if error.is_some() {
+        for item in tree.iter().rev() {
+            match item {
+                Ok((k, v)) if last.as_ref() < Some(&k) => copier.insert(k, v)?,
+                _ => break,
+            }
+        }
+    }
+
+    Ok(RecoveredBucket {
+        name: String::from_utf8_lossy(&tree.name()).into_owned(),
+        recovered: copier.finish()?,
+        complete: error.is_none(),
+        error,
+    })
+}
+
+/// Read every tree in `src`, copying the readable entries into `dst` if it's given
+pub(crate) fn salvage(
+    src: &sled::Db,
+    dst: Option<&sled::Db>,
+) -> Result<Vec<RecoveredBucket>, Error> {
+    let mut buckets = Vec::new();
+    for name in src.tree_names() {
+        let tree = src.open_tree(&name)?;
+        let bucket = match dst {
+            Some(dst) => salvage_tree(&tree, Some(&dst.open_tree(&name)?))?,
+            None => salvage_tree(&tree, None)?,
+        };
+        buckets.push(bucket);
+    }
+    Ok(buckets)
+}
+
+/// Returns true if an error opening a store means its files are damaged, rather than it being
+/// locked or inaccessible
+pub(crate) fn is_damage(e: &Error) -> bool {
+    match e {
+        Error::Sled(sled::Error::Corruption { .. })
+        | Error::Sled(sled::Error::ReportableBug(_))
+        | Error::Sled(sled::Error::Unsupported(_)) => true,
+        Error::Sled(sled::Error::Io(e)) | Error::IO(e) => matches!(
+            e.kind(),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Copy a damaged store to `dst` without its settings and snapshots, sled then rebuilds them
+/// from the log when the copy is opened
+pub(crate) fn copy_for_salvage(src: &Path, dst: &Path) -> Result<(), Error> {
+    std::fs::create_dir(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let s = name.to_string_lossy();
+        if s == "conf" || s.starts_with("snap.") {
+            continue;
+        }
+        copy_all(&entry.path(), &dst.join(&name))?;
+    }
+    Ok(())
+}
+
+fn copy_all(src: &Path, dst: &Path) -> Result<(), Error> {
+    if !src.is_dir() {
+        std::fs::copy(src, dst)?;
+        return Ok(());
+    }
+    std::fs::create_dir(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        copy_all(&entry.path(), &dst.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+// A file next to the store rather than inside it, so the lock is still held while the store's
+// directory is moved aside and replaced
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+/// Exclusive lock on a file next to a persistent store, held by `Store` for as long as it's open
+/// and by `Store::compact` and `Store::open_with_recovery` until the replaced store is reopened
+///
+/// sled's own lock is released when the store is closed to be replaced, this one isn't, so
+/// another process opening the store waits until the replacement is finished
+pub(crate) struct StoreLock(fs::File);
+
+impl StoreLock {
+    /// Take the lock for the store at `path`, returning `Error::StoreLocked` if it's still held
+    /// by someone else after `timeout`
+    pub(crate) fn acquire(path: &Path, timeout: Duration) -> Result<StoreLock, Error> {
+        let lock = lock_path(path);
+        if let Some(parent) = lock.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match FileExt::try_lock_exclusive(&file) {
+                Ok(()) => return Ok(StoreLock(file)),
+                Err(e) if !is_contended(&e) => return Err(e.into()),
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+                Err(_) => return Err(Error::StoreLocked(path.to_path_buf())),
+            }
+        }
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.0);
+    }
+}
+
+fn is_contended(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+        || e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+// While a store is replaced by a copy, a file next to it records the copy, the path the store
+// is moved aside to and whether that is kept, so an interrupted replacement can be finished or
+// undone when the store is next opened
+fn intent_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".replacing");
+    path.with_file_name(name)
+}
+
+/// Replacement of a store by a copy, see `Store::compact` and `Store::open_with_recovery`
+///
+/// The store's lock is borrowed from `begin` until `commit`, so nobody else can open the store
+/// while it's being replaced
+pub(crate) struct Replace<'a> {
+    lock: &'a StoreLock,
+    path: PathBuf,
+    copy: PathBuf,
+    aside: PathBuf,
+    keep: bool,
+}
+
+impl<'a> Replace<'a> {
+    /// Start replacing the store at `path` with a copy that is about to be created at `copy`,
+    /// the store is moved to `aside` and removed afterwards unless `keep` is true
+    pub(crate) fn begin(
+        lock: &'a StoreLock,
+        path: &Path,
+        copy: PathBuf,
+        aside: PathBuf,
+        keep: bool,
+    ) -> Result<Replace<'a>, Error> {
+        let replace = Replace {
+            lock,
+            path: path.to_path_buf(),
+            copy,
+            aside,
+            keep,
+        };
+        replace.write("copy")?;
+        Ok(replace)
+    }
+
+    pub(crate) fn copy(&self) -> &Path {
+        &self.copy
+    }
+
+    // Written to a temporary file first so a partial intent is never read
+    fn write(&self, state: &str) -> Result<(), Error> {
+        let path = |x: &Path| {
+            x.to_str()
+                .map(str::to_string)
+                .ok_or_else(|| Error::Message(format!("Invalid store path: {:?}", x)))
+        };
+        let keep = if self.keep { "keep" } else { "remove" };
+        let intent = intent_path(&self.path);
+        let tmp = intent.with_extension("tmp");
+        let lines = [state.to_string(), path(&self.copy)?, path(&self.aside)?];
+        std::fs::write(&tmp, format!("{}\n{}\n", lines.join("\n"), keep))?;
+        std::fs::rename(&tmp, &intent)?;
+        Ok(())
+    }
+
+    /// Move the store aside and the finished copy into its place, the store must be closed.
+    /// Returns the path the store was moved to if it's kept.
+    pub(crate) fn commit(self) -> Result<Option<PathBuf>, Error> {
+        self.write("swap")?;
+        finish(self.lock, &self.path)
+    }
+}
+
+/// Finish or undo an interrupted replacement of the store at `path`, returns the path the store
+/// was moved to if it's kept
+///
+/// Whoever was replacing the store released the lock when it stopped, so a copy that may not be
+/// complete isn't still being made and can be removed
+pub(crate) fn finish(_lock: &StoreLock, path: &Path) -> Result<Option<PathBuf>, Error> {
+    let intent = intent_path(path);
+    let s = match std::fs::read_to_string(&intent) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let lines: Vec<&str> = s.lines().collect();
+    let (state, copy, aside, keep) = match lines.as_slice() {
+        [state, copy, aside, keep] => (*state, Path::new(copy), Path::new(aside), *keep == "keep"),
+        _ => {
+            return Err(Error::Message(format!(
+                "Invalid replacement file: {:?}",
+                intent
+            )))
+        }
+    };
+
+    match state {
+        "copy" => {
+            if copy.exists() {
+                std::fs::remove_dir_all(copy)?;
+            }
+        }
+        _ => {
+            // The copy is only missing once both renames have been made
+            if copy.exists() {
+                if path.exists() {
+                    std::fs::rename(path, aside)?;
+                }
+                std::fs::rename(copy, path)?;
+            }
+            if !keep && aside.exists() {
+                std::fs::remove_dir_all(aside)?;
+            }
+        }
+    }
+    std::fs::remove_file(&intent)?;
+    match state == "swap" && keep {
+        true => Ok(Some(aside.to_path_buf())),
+        false => Ok(None),
+    }
+}
@@ -0,0 +1,409 @@
+//! One-way replication from a primary `Store` to a follower
+//!
+//! The primary must be opened with `Config::changefeed` enabled. A `Replicator` sends a snapshot
+//! of every bucket followed by the changes recorded in the changefeed, either directly to a
+//! follower in the same process using `Replicator::sync`, or over any `Write` transport using
+//! `Replicator::send` to be applied on the other end by `Replicator::receive`.
+//!
+//! Each bucket has its own changefeed, so replication keeps a sequence number for every bucket and
+//! only buckets whose changes are no longer available are copied by a snapshot.
+//!
+//! Values are copied exactly as they are stored along with their expiration deadline, so a
+//! follower of an encrypted store must be opened with the same key to read them. Buckets that
+//! don't exist on the primary are dropped from the follower, including buckets that were only
+//! ever created on the follower.
+//!
+//! Only plain buckets are replicated. Blob, indexed, cache and time series buckets keep part of
+//! their data in internal trees that aren't sent to the follower, so `Replicator` returns an
+//! error instead of replicating a primary that uses any of them.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use crate::changes::{self, Changes};
+use crate::{blob, cache, index, timeseries, ttl, Bucket, Error, Raw, Store};
+
+/// Name of the tree used by a follower to store the next sequence number to replicate for each
+/// bucket, keyed by bucket name
+pub(crate) const TREE_NAME: &str = "__kv__replication";
+
+// Internal trees used by bucket types that can't be replicated
+const UNSUPPORTED: &[(&str, &str)] = &[
+    (blob::TREE_NAME, "Blob buckets"),
+    (index::TREE_NAME, "Indexed buckets"),
+    (cache::TREE_NAME, "Cache buckets"),
+];
+
+// The follower would be missing the data in these trees, so replication stops as soon as any of
+// them is used
+fn check_supported(primary: &Store) -> Result<(), Error> {
+    for name in primary.db.tree_names() {
+        let kind = match UNSUPPORTED.iter().find(|(x, _)| name == x.as_bytes()) {
+            Some((_, kind)) if !primary.db.open_tree(&name)?.is_empty() => *kind,
+            None if name.starts_with(timeseries::TREE_PREFIX.as_bytes()) => "Time series buckets",
+            _ => continue,
+        };
+        return Err(Error::Message(format!("{} can't be replicated", kind)));
+    }
+    Ok(())
+}
+
+// Longest byte string accepted from a stream, so a corrupt length can't exhaust memory
+const MAX_LEN: u64 = 1 << 30;
+
+// Updates sent from the primary to the follower
+enum Message {
+    // Every bucket on the primary, the follower drops any others
+    Buckets(Vec<Raw>),
+    // Remove every item from a bucket before it is copied by a snapshot
+    Clear(Raw),
+    // Bucket, key, value and the encoded expiration deadline
+    Set(Raw, Raw, Raw, Option<Raw>),
+    Remove(Raw, Raw),
+    // Every change to the bucket before this sequence number has been sent
+    Seq(Raw, u64),
+}
+
+fn write_bytes<W: Write>(w: &mut W, x: &[u8]) -> Result<(), Error> {
+    w.write_all(&(x.len() as u32).to_be_bytes())?;
+    w.write_all(x)?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, Error> {
+    let mut n = [0u8; 4];
+    r.read_exact(&mut n)?;
+    Ok(u32::from_be_bytes(n))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Raw, Error> {
+    let n = read_u32(r)? as u64;
+    if n > MAX_LEN {
+        return Err(Error::Message("Replication message is too long".into()));
+    }
+    let mut x = Vec::new();
+    r.take(n).read_to_end(&mut x)?;
+    if x.len() as u64 != n {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(x.into())
+}
+
+impl Message {
+    // Each message is a tag followed by its fields, byte strings are prefixed by their length
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        match self {
+            Message::Clear(b) => {
+                w.write_all(&[0])?;
+                write_bytes(w, b)
+            }
+            Message::Set(b, k, v, d) => {
+                w.write_all(&[1])?;
+                write_bytes(w, b)?;
+                write_bytes(w, k)?;
+                write_bytes(w, v)?;
+                match d {
+                    Some(d) => {
+                        w.write_all(&[1])?;
+                        write_bytes(w, d)
+                    }
+                    None => Ok(w.write_all(&[0])?),
+                }
+            }
+            Message::Remove(b, k) => {
+                w.write_all(&[2])?;
+                write_bytes(w, b)?;
+                write_bytes(w, k)
+            }
+            Message::Seq(b, n) => {
+                w.write_all(&[3])?;
+                write_bytes(w, b)?;
+                w.write_all(&n.to_be_bytes())?;
+                Ok(())
+            }
+            Message::Buckets(names) => {
+                w.write_all(&[4])?;
+                w.write_all(&(names.len() as u32).to_be_bytes())?;
+                for b in names {
+                    write_bytes(w, b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Returns `None` at the end of the stream
+    fn read<R: Read>(r: &mut R) -> Result<Option<Message>, Error> {
+        let mut tag = [0u8; 1];
+        match r.read_exact(&mut tag) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let msg = match tag[0] {
+            0 => Message::Clear(read_bytes(r)?),
+            1 => {
+                let (b, k, v) = (read_bytes(r)?, read_bytes(r)?, read_bytes(r)?);
+                let mut flag = [0u8; 1];
+                r.read_exact(&mut flag)?;
+                let d = match flag[0] {
+                    0 => None,
+                    _ => Some(read_bytes(r)?),
+                };
+                Message::Set(b, k, v, d)
+            }
+            2 => Message::Remove(read_bytes(r)?, read_bytes(r)?),
+            3 => {
+                let b = read_bytes(r)?;
+                let mut n = [0u8; 8];
+                r.read_exact(&mut n)?;
+                Message::Seq(b, u64::from_be_bytes(n))
+            }
+            4 => {
+                let mut names = Vec::new();
+                for _ in 0..read_u32(r)? {
+                    names.push(read_bytes(r)?);
+                }
+                Message::Buckets(names)
+            }
+            x => {
+                return Err(Error::Message(format!(
+                    "Invalid replication message: {}",
+                    x
+                )))
+            }
+        };
+        Ok(Some(msg))
+    }
+}
+
+// Applies messages to a follower
+struct Follower<'a> {
+    store: &'a Store,
+    buckets: HashMap<Raw, Bucket<'static, Raw, Raw>>,
+}
+
+impl<'a> Follower<'a> {
+    fn new(store: &'a Store) -> Follower<'a> {
+        Follower {
+            store,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn bucket(&mut self, name: &Raw) -> Result<&Bucket<'static, Raw, Raw>, Error> {
+        if !self.buckets.contains_key(name) {
+            let s = std::str::from_utf8(name)
+                .map_err(|_| Error::Message("Invalid bucket name".into()))?;
+            let bucket = self.store.raw_bucket(s)?;
+            self.buckets.insert(name.clone(), bucket);
+        }
+        Ok(&self.buckets[name])
+    }
+
+    fn apply(&mut self, msg: Message) -> Result<(), Error> {
+        match msg {
+            Message::Buckets(names) => {
+                let t = self.store.db.open_tree(TREE_NAME)?;
+                for name in self.store.buckets() {
+                    if !names.iter().any(|x| x == name.as_bytes()) {
+                        self.buckets.remove(name.as_bytes());
+                        self.store.drop_bucket(&name)?;
+                        t.remove(&name)?;
+                    }
+                }
+                Ok(())
+            }
+            Message::Clear(b) => self.bucket(&b)?.clear(),
+            Message::Set(b, k, v, d) => {
+                // Setting the value clears any deadline, so it's stored afterwards
+                self.bucket(&b)?.set(k.clone(), v)?;
+                if let Some(d) = d {
+                    self.store.ttl.insert(ttl::key(&b, &k), d)?;
+                }
+                Ok(())
+            }
+            Message::Remove(b, k) => self.bucket(&b)?.remove(k),
+            Message::Seq(b, n) => {
+                let t = self.store.db.open_tree(TREE_NAME)?;
+                t.insert(b, &n.to_be_bytes())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Streams changes from a primary store to a follower
+pub struct Replicator<'a> {
+    primary: &'a Store,
+    seqs: HashMap<String, u64>,
+}
+
+impl<'a> Replicator<'a> {
+    /// Replicate `primary` to a new follower, starting with a snapshot of every bucket
+    ///
+    /// Returns an error if the changefeed is not enabled for `primary`, or if it has blob,
+    /// indexed, cache or time series buckets
+    pub fn new(primary: &'a Store) -> Result<Replicator<'a>, Error> {
+        Replicator::resume(primary, HashMap::new())
+    }
+
+    /// Resume replication to a follower that has received every change to each bucket before
+    /// the sequence number given for it, usually the value returned by
+    /// `Replicator::follower_seqs`
+    ///
+    /// A snapshot of a bucket is sent first if it is missing from `seqs`, or if any of those
+    /// changes have since been removed by `Store::truncate_changes`
+    pub fn resume(primary: &'a Store, seqs: HashMap<String, u64>) -> Result<Replicator<'a>, Error> {
+        primary.changefeed("")?;
+        check_supported(primary)?;
+        Ok(Replicator { primary, seqs })
+    }
+
+    /// Returns the sequence number of the next change to be sent for each bucket
+    pub fn seqs(&self) -> &HashMap<String, u64> {
+        &self.seqs
+    }
+
+    /// Returns the sequence numbers `follower` should resume replication from, empty if it has
+    /// never received a snapshot
+    pub fn follower_seqs(follower: &Store) -> Result<HashMap<String, u64>, Error> {
+        let t = follower.db.open_tree(TREE_NAME)?;
+        let mut seqs = HashMap::new();
+        for item in t.iter() {
+            let (k, v) = item?;
+            if let (Ok(name), true) = (std::str::from_utf8(&k), v.len() == 8) {
+                let mut n = [0u8; 8];
+                n.copy_from_slice(&v);
+                seqs.insert(name.to_string(), u64::from_be_bytes(n));
+            }
+        }
+        Ok(seqs)
+    }
+
+    // Every bucket that exists or has a changefeed, a dropped bucket keeps its changefeed so
+    // the removal of its keys is replicated
+    fn buckets(&self) -> Vec<String> {
+        let mut names = self.primary.buckets();
+        for name in self.primary.db.tree_names() {
+            if let Some(x) = name.strip_prefix(changes::TREE_PREFIX.as_bytes()) {
+                if let Ok(x) = std::str::from_utf8(x) {
+                    names.push(x.to_string());
+                }
+            }
+        }
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    fn deadline(&self, tree: &sled::Tree, key: &[u8]) -> Result<Option<Raw>, Error> {
+        Ok(self.primary.ttl.get(ttl::key(&tree.name(), key))?)
+    }
+
+    // Pass every pending update to `f`, returns the number of keys sent
+    fn pending<F: FnMut(Message) -> Result<(), Error>>(
+        &mut self,
+        mut f: F,
+    ) -> Result<usize, Error> {
+        check_supported(self.primary)?;
+        let mut n = 0;
+        let names = self.primary.buckets();
+        f(Message::Buckets(
+            names.iter().map(|x| Raw::from(x.as_bytes())).collect(),
+        ))?;
+
+        for name in self.buckets() {
+            let log = self.primary.changefeed(&name)?;
+            let bucket = Raw::from(name.as_bytes());
+
+            // A dropped bucket is removed from the follower by `Message::Buckets`, so its
+            // changes are skipped
+            if !names.contains(&name) {
+                let seq = match &log {
+                    Some(log) => changes::last(log)? + 1,
+                    None => 1,
+                };
+                f(Message::Seq(bucket, seq))?;
+                self.seqs.insert(name, seq);
+                continue;
+            }
+            let tree = self.primary.db.open_tree(&name)?;
+
+            let (first, last) = match &log {
+                Some(log) => {
+                    let last = changes::last(log)?;
+                    match Changes::new(log, 0).next() {
+                        Some(c) => (c?.seq, last),
+                        None => (last + 1, last),
+                    }
+                }
+                None => (1, 0),
+            };
+
+            let mut seq = match self.seqs.get(&name) {
+                Some(&seq) if seq >= first && seq <= last + 1 => seq,
+                _ => {
+                    // Changes made while the snapshot is taken are sent again after it
+                    f(Message::Clear(bucket.clone()))?;
+                    for item in tree.iter() {
+                        let (k, v) = item?;
+                        let d = self.deadline(&tree, &k)?;
+                        f(Message::Set(bucket.clone(), k, v, d))?;
+                        n += 1;
+                    }
+                    last + 1
+                }
+            };
+
+            // The current value is sent for each change, so a key changed more than once is
+            // sent with its latest value every time
+            if let Some(log) = &log {
+                for change in Changes::new(log, seq) {
+                    let change = change?;
+                    match tree.get(&change.key)? {
+                        Some(v) => {
+                            let d = self.deadline(&tree, &change.key)?;
+                            f(Message::Set(bucket.clone(), change.key, v, d))?
+                        }
+                        None => f(Message::Remove(bucket.clone(), change.key))?,
+                    }
+                    seq = change.seq + 1;
+                    n += 1;
+                }
+            }
+
+            f(Message::Seq(bucket, seq))?;
+            self.seqs.insert(name, seq);
+        }
+        Ok(n)
+    }
+
+    /// Apply all pending updates to `follower`, returns the number of keys written
+    pub fn sync(&mut self, follower: &Store) -> Result<usize, Error> {
+        let mut follower = Follower::new(follower);
+        self.pending(|msg| follower.apply(msg))
+    }
+
+    /// Write all pending updates to `w`, returns the number of keys written
+    pub fn send<W: Write>(&mut self, mut w: W) -> Result<usize, Error> {
+        let n = self.pending(|msg| msg.write(&mut w))?;
+        w.flush()?;
+        Ok(n)
+    }
+
+    /// Apply updates written by `Replicator::send` to `follower` until the end of `r`, returns
+    /// the number of keys written
+    pub fn receive<R: Read>(follower: &Store, mut r: R) -> Result<usize, Error> {
+        let mut follower = Follower::new(follower);
+        let mut n = 0;
+        while let Some(msg) = Message::read(&mut r)? {
+            if let Message::Set(..) | Message::Remove(..) = msg {
+                n += 1;
+            }
+            follower.apply(msg)?;
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{Batch, Bucket, Error, Iter, Key, Raw, Transaction, TransactionError, Value};
+
+/// Prepend `prefix` to `key`
+pub(crate) fn prefixed(prefix: &[u8], key: Raw) -> Raw {
+    if prefix.is_empty() {
+        return key;
+    }
+
+    let mut dst = Vec::with_capacity(prefix.len() + key.len());
+    dst.extend_from_slice(prefix);
+    dst.extend_from_slice(&key);
+    dst.into()
+}
+
+/// A view of a `Bucket` where a prefix is automatically added to and removed from all keys
+///
+/// Created using `Bucket::scoped`
+#[derive(Clone)]
+pub struct Scoped<'a, K: Key<'a>, V: Value>(Bucket<'a, K, V>, Raw);
+
+impl<'a, K: Key<'a>, V: Value> Scoped<'a, K, V> {
+    pub(crate) fn new(bucket: Bucket<'a, K, V>, prefix: Raw) -> Scoped<'a, K, V> {
+        Scoped(bucket, prefix)
+    }
+
+    fn key<X: Into<K>>(&self, key: X) -> Result<Raw, Error> {
+        Ok(prefixed(&self.1, key.into().to_raw_key()?))
+    }
+
+    /// Get the prefix
+    pub fn prefix(&self) -> &[u8] {
+        &self.1
+    }
+
+    /// Get a nested view, `prefix` is added after the current prefix
+    pub fn scoped<P: AsRef<[u8]>>(&self, prefix: P) -> Scoped<'a, K, V> {
+        let bucket = self.0.handle();
+        Scoped(bucket, prefixed(&self.1, prefix.as_ref().into()))
+    }
+
+    /// Returns true if the scope contains the given key
+    pub fn contains<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        let key = self.key(key)?;
+        let v = (self.0).tree.contains_key(&key)?;
+        Ok(v && !self.0.expired(&key)?)
+    }
+
+    /// Get the value associated with the specified key
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<V>, Error> {
+        let key = self.key(key)?;
+        let v = (self.0).tree.get(&key)?;
+
+        match v {
+            None => Ok(None),
+            Some(_) if self.0.expired(&key)? => Ok(None),
+            Some(x) => Ok(Some(self.0.decode(&key, x)?)),
+        }
+    }
+
+    /// Set the value associated with the specified key to the provided value
+    pub fn set<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
+        let key = self.key(key)?;
+        let v = self.0.encode(&key, &value.into())?;
+        self.0.write(&key, Some(v))?;
+        Ok(())
+    }
+
+    /// Remove the value associated with the specified key from the database
+    pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), Error> {
+        self.0.write(&self.key(key)?, None)?;
+        Ok(())
+    }
+
+    /// Get an iterator over keys/values in the scope
+    pub fn iter(&self) -> Iter<K, V> {
+        let iter = (self.0).tree.scan_prefix(&self.1);
+        let mut iter = self.0.iter_over(iter);
+        iter.1 = self.1.len();
+        iter
+    }
+
+    /// Iterate over keys/values in the scope with the specified prefix
+    pub fn iter_prefix<X: Into<K>>(&self, prefix: X) -> Result<Iter<K, V>, Error> {
+        let iter = (self.0).tree.scan_prefix(self.key(prefix)?);
+        let mut iter = self.0.iter_over(iter);
+        iter.1 = self.1.len();
+        Ok(iter)
+    }
+
+    /// Create a new batch, keys will be added to the scope when it is applied
+    pub fn new_batch(&self) -> Batch<K, V> {
+        let mut batch = Batch::new();
+        batch.1 = self.1.clone();
+        batch
+    }
+
+    /// Apply batch update, the batch must have been created using `new_batch`
+    pub fn batch(&self, batch: Batch<K, V>) -> Result<(), Error> {
+        self.0.batch_scoped(&self.1, batch)
+    }
+
+    /// Execute a transaction, all keys accessed in the transaction are in the scope
+    pub fn transaction<
+        A,
+        E: From<Error>,
+        F: Fn(Transaction<K, V, E>) -> Result<A, TransactionError<E>>,
+    >(
+        &self,
+        f: F,
+    ) -> Result<A, E> {
+        self.0.run_transaction(&self.1, f)
+    }
+
+    /// Returns true when there are no items in the scope
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Remove all items in the scope
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut items = Vec::new();
+        for k in (self.0).tree.scan_prefix(&self.1).keys() {
+            items.push((k?, None));
+        }
+        self.0.write_batch(&items)
+    }
+}
@@ -0,0 +1,59 @@
+//! Serialize `Raw` values using `#[serde(with = "kv::serde_raw")]`
+//!
+//! `Raw` is defined by `sled`, so it can't implement `Serialize` or `Deserialize` directly. For
+//! human-readable formats such as JSON, values that are valid UTF-8 are written as strings and
+//! other values as arrays of bytes, matching `Bucket::export_json`. Other formats store the
+//! bytes as they are.
+
+use std::fmt;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::Serializer;
+
+use crate::Raw;
+
+/// Serialize a `Raw` value
+pub fn serialize<S: Serializer>(x: &Raw, s: S) -> Result<S::Ok, S::Error> {
+    if !s.is_human_readable() {
+        return s.serialize_bytes(x);
+    }
+    match std::str::from_utf8(x) {
+        Ok(x) => s.serialize_str(x),
+        Err(_) => s.collect_seq(x.iter()),
+    }
+}
+
+struct RawVisitor;
+
+impl<'de> Visitor<'de> for RawVisitor {
+    type Value = Raw;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string or an array of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, x: &str) -> Result<Raw, E> {
+        Ok(x.into())
+    }
+
+    fn visit_bytes<E: de::Error>(self, x: &[u8]) -> Result<Raw, E> {
+        Ok(x.into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Raw, A::Error> {
+        let mut dst = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element::<u8>()? {
+            dst.push(b);
+        }
+        Ok(dst.into())
+    }
+}
+
+/// Deserialize a `Raw` value
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Raw, D::Error> {
+    if d.is_human_readable() {
+        d.deserialize_any(RawVisitor)
+    } else {
+        d.deserialize_bytes(RawVisitor)
+    }
+}
@@ -1,19 +1,253 @@
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::{Bucket, Config, Error, Key, Value};
+use crate::backup;
+use crate::blob::{self, BlobBucket};
+use crate::cache::{self, CacheBucket, CacheLimit};
+use crate::changes::{self, Changes};
+use crate::compact::{self, SpaceUsage};
+use crate::crypt::Crypt;
+use crate::index::{self, Indexed};
+use crate::lock::{self, Lock};
+use crate::migrate::{self, Migrations};
+use crate::observer::{Observer, StoreObserver};
+use crate::recovery::{self, RecoveryPolicy, RecoveryReport, StoreLock};
+use crate::store_batch::StoreBatch;
+use crate::timeseries::{self, TimeSeriesBucket};
+use crate::ttl::{self, Sweeper};
+use crate::verify::{IntegrityReport, Verify};
+use crate::{Bucket, Config, Error, Key, Raw, Value};
+
+// Trees used internally are not listed as buckets
+fn is_internal(name: &[u8]) -> bool {
+    name.starts_with(b"__kv__")
+}
+
+// Buckets can't be opened or dropped using the names of internal trees
+fn check_name(name: &str) -> Result<(), Error> {
+    if is_internal(name.as_bytes()) {
+        return Err(Error::Message(format!(
+            "Invalid bucket name {:?}, names starting with \"__kv__\" are reserved",
+            name
+        )));
+    }
+    Ok(())
+}
+
+// Unused path next to the store with `suffix` appended to its name, followed by a number if
+// that name is already taken
+fn sibling(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::Message(format!("Invalid store path: {:?}", path)))?
+        .to_string_lossy()
+        .into_owned();
+    let mut dst = path.with_file_name(format!("{}.{}", name, suffix));
+    let mut n = 0;
+    while dst.exists() {
+        n += 1;
+        dst = path.with_file_name(format!("{}.{}.{}", name, suffix, n));
+    }
+    Ok(dst)
+}
 
 /// Store is used to read/write data to disk using `sled`
 pub struct Store {
     config: Config,
-    db: sled::Db,
+    pub(crate) db: sled::Db,
+    pub(crate) ttl: sled::Tree,
+    index: sled::Tree,
+    cache: sled::Tree,
+    blobs: sled::Tree,
+    blob_writers: blob::Writers,
+    locks: sled::Tree,
+    pub(crate) crypt: Crypt,
+    observer: Observer,
+    _sweeper: Option<Sweeper>,
+    // Declared last so it's released after the database is closed
+    file_lock: Option<StoreLock>,
 }
 
 impl Store {
     /// Create a new store from the given config
-    pub fn new(mut config: Config) -> Result<Store, Error> {
+    ///
+    /// If the store is locked by another process this waits for up to `Config::open_timeout`
+    /// before returning `Error::StoreLocked`. Persistent stores are locked using a file next to
+    /// the store's directory with a `.lock` suffix, which is kept after the store is dropped.
+    pub fn new(config: Config) -> Result<Store, Error> {
+        Store::open(config, Crypt::default())
+    }
+
+    /// Create a new store from the given config, failing immediately with `Error::StoreLocked`
+    /// if the store is locked by another process regardless of `Config::open_timeout`
+    pub fn try_new(mut config: Config) -> Result<Store, Error> {
+        let timeout = config.open_timeout_ms.take();
+        let mut store = Store::new(config)?;
+        store.config.open_timeout_ms = timeout;
+        Ok(store)
+    }
+
+    /// Create a new store from the given config, all values are encrypted using `key`
+    ///
+    /// Each value is encrypted using XChaCha20-Poly1305 with a random nonce, the bucket name and
+    /// key are authenticated along with the value. Keys are not encrypted. `Bucket::merge` and
+    /// `Bucket::compare_and_swap` are not supported for encrypted stores.
+    #[cfg(feature = "encryption")]
+    pub fn new_encrypted(config: Config, key: &[u8; 32]) -> Result<Store, Error> {
+        Store::open(config, Crypt::new(key))
+    }
+
+    /// Create a new store from the given config, recovering as much as possible if it is damaged
+    ///
+    /// If a bucket can't be read completely, every entry that can be read is copied into a new
+    /// store which replaces the damaged one. The damaged store is kept next to it with a
+    /// `.damaged` suffix, and the report lists how many entries were recovered from each bucket.
+    /// When sled can't open the store, a copy of it is opened without its settings and snapshots
+    /// so sled rebuilds them from its log, and the entries are recovered from the copy. If that
+    /// fails too, `RecoveryPolicy::Reset` replaces the store with an empty one while
+    /// `RecoveryPolicy::Salvage` returns the error. Errors that don't mean the store is damaged,
+    /// like `Error::StoreLocked`, are always returned.
+    ///
+    /// Existing files are never removed, a number is added to the suffix if the name is already
+    /// taken. The store stays locked until the recovered store is opened, so other processes
+    /// wait for recovery to finish. If the process stops while the damaged store is being
+    /// replaced, the replacement is finished the next time this is called.
+    ///
+    /// Recovery relies on sled, which already discards incomplete writes at the end of its log
+    /// when opening a store and stops reading a bucket at the first damaged entry.
+    pub fn open_with_recovery(
+        config: Config,
+        policy: RecoveryPolicy,
+    ) -> Result<(Store, RecoveryReport), Error> {
+        Store::recover(config, Crypt::default(), policy)
+    }
+
+    /// Like `open_with_recovery`, for a store created using `new_encrypted`
+    ///
+    /// Entries are copied without being decrypted, so `key` is only used to open the recovered
+    /// store
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted_with_recovery(
+        config: Config,
+        key: &[u8; 32],
+        policy: RecoveryPolicy,
+    ) -> Result<(Store, RecoveryReport), Error> {
+        Store::recover(config, Crypt::new(key), policy)
+    }
+
+    fn recover(
+        config: Config,
+        crypt: Crypt,
+        policy: RecoveryPolicy,
+    ) -> Result<(Store, RecoveryReport), Error> {
+        if config.temporary || config.read_only {
+            return Err(Error::Message(
+                "Only persistent, writable stores can be recovered".into(),
+            ));
+        }
+
+        let path = config.path.clone();
+        let lock = StoreLock::acquire(&path, config.lock_timeout())?;
+        let mut report = RecoveryReport {
+            buckets: Vec::new(),
+            damaged: recovery::finish(&lock, &path)?,
+        };
+
+        let (db, scratch) = match config.clone().open() {
+            Ok(db) => {
+                report.buckets = recovery::salvage(&db, None)?;
+                if report.buckets.iter().all(|x| x.complete) {
+                    drop(db);
+                    return Ok((Store::open_locked(config, crypt, Some(lock))?, report));
+                }
+                (db, None)
+            }
+            Err(e) if !recovery::is_damage(&e) => return Err(e),
+            Err(e) => {
+                let scratch = sibling(&path, "salvage")?;
+                recovery::copy_for_salvage(&path, &scratch)?;
+                let mut scratch_config = config.clone();
+                scratch_config.path = scratch.clone();
+                match scratch_config.open() {
+                    Ok(db) => (db, Some(scratch)),
+                    Err(_) if policy == RecoveryPolicy::Reset => {
+                        let damaged = sibling(&path, "damaged")?;
+                        std::fs::rename(&path, &damaged)?;
+                        report.damaged = Some(damaged);
+                        return Ok((Store::open_locked(config, crypt, Some(lock))?, report));
+                    }
+                    Err(_) => return Err(e),
+                }
+            }
+        };
+
+        let replace = recovery::Replace::begin(
+            &lock,
+            &path,
+            sibling(&path, "recovered")?,
+            sibling(&path, "damaged")?,
+            true,
+        )?;
+        let mut copy_config = config.clone();
+        copy_config.path = replace.copy().to_path_buf();
+        let dst = copy_config.open()?;
+        report.buckets = recovery::salvage(&db, Some(&dst))?;
+        dst.flush()?;
+        drop(dst);
+        drop(db);
+
+        // The scratch copy was made by this call, so it's the only thing removed
+        if let Some(scratch) = scratch {
+            std::fs::remove_dir_all(scratch)?;
+        }
+
+        report.damaged = replace.commit()?;
+        Ok((Store::open_locked(config, crypt, Some(lock))?, report))
+    }
+
+    fn open(config: Config, crypt: Crypt) -> Result<Store, Error> {
+        let lock = match !config.temporary && !config.read_only {
+            true => Some(StoreLock::acquire(&config.path, config.lock_timeout())?),
+            false => None,
+        };
+        Store::open_locked(config, crypt, lock)
+    }
+
+    // Persistent stores are opened holding `lock`, which is kept until the store is dropped
+    fn open_locked(
+        mut config: Config,
+        crypt: Crypt,
+        lock: Option<StoreLock>,
+    ) -> Result<Store, Error> {
+        // Finish replacing the store if compaction or recovery was interrupted
+        if let Some(lock) = &lock {
+            recovery::finish(lock, &config.path)?;
+        }
+        let db = config.open()?;
+        let ttl = db.open_tree(ttl::TREE_NAME)?;
+        let index = db.open_tree(index::TREE_NAME)?;
+        let cache = db.open_tree(cache::TREE_NAME)?;
+        let blobs = db.open_tree(blob::TREE_NAME)?;
+        let locks = db.open_tree(lock::TREE_NAME)?;
+        let sweeper = config.purge_expired_every_ms.map(|ms| {
+            let interval = Duration::from_millis(ms);
+            Sweeper::spawn(db.clone(), ttl.clone(), config.changefeed, interval)
+        });
         Ok(Store {
-            db: config.open()?,
+            db,
+            ttl,
+            index,
+            cache,
+            blobs,
+            blob_writers: Default::default(),
+            locks,
+            crypt,
+            observer: Observer::new(&config),
+            _sweeper: sweeper,
             config,
+            file_lock: lock,
         })
     }
 
@@ -28,11 +262,45 @@ impl Store {
         Ok(id)
     }
 
+    /// Report operations on buckets and flushes of the store to `observer`, replacing any
+    /// observer set before. This includes buckets that are already open.
+    pub fn set_observer<O: StoreObserver + 'static>(&self, observer: O) {
+        self.observer.set(Arc::new(observer));
+    }
+
+    /// Acquire the advisory lock called `name`, waiting until it is available. The lock is
+    /// released when the returned `Lock` is dropped or once `lease` has elapsed.
+    ///
+    /// Store locks are separate from the key locks of every bucket, see `Bucket::lock_key`
+    pub fn lock(&self, name: &str, lease: Duration) -> Result<Lock, Error> {
+        let key = lock::store_key(name);
+        lock::acquire(&self.locks, key, lease, self.observer.handle())
+    }
+
+    /// Acquire the advisory lock called `name`, returning `None` if it is still held once
+    /// `timeout` has passed
+    pub fn lock_timeout(
+        &self,
+        name: &str,
+        lease: Duration,
+        timeout: Duration,
+    ) -> Result<Option<Lock>, Error> {
+        let key = lock::store_key(name);
+        lock::acquire_timeout(&self.locks, key, lease, timeout, self.observer.handle())
+    }
+
+    /// Acquire the advisory lock called `name`, returning `None` if it is already held
+    pub fn try_lock(&self, name: &str, lease: Duration) -> Result<Option<Lock>, Error> {
+        let key = lock::store_key(name);
+        lock::try_acquire(&self.locks, key, lease, self.observer.handle())
+    }
+
     /// Get a list of bucket names
     pub fn buckets(&self) -> Vec<String> {
         self.db
             .tree_names()
             .into_iter()
+            .filter(|x| !is_internal(x))
             .map(|x| String::from_utf8(x.to_vec()))
             .filter_map(|x| match x {
                 Ok(x) => Some(x),
@@ -41,27 +309,377 @@ impl Store {
             .collect()
     }
 
-    /// Open a new bucket
+    /// Returns true if a bucket with the given name exists
+    pub fn bucket_exists<S: AsRef<str>>(&self, name: S) -> bool {
+        let name = name.as_ref().as_bytes();
+        !is_internal(name) && self.db.tree_names().iter().any(|x| x.as_ref() == name)
+    }
+
+    /// Open a new bucket, names starting with `__kv__` are reserved for trees used internally
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(bucket = name))
+    )]
     pub fn bucket<'a, K: Key<'a>, V: Value>(
         &self,
         name: Option<&str>,
     ) -> Result<Bucket<'a, K, V>, Error> {
-        let t = self.db.open_tree(name.unwrap_or("__sled__default"))?;
-        Ok(Bucket::new(t))
+        if let Some(name) = name {
+            check_name(name)?;
+        }
+        self.internal_bucket(name.unwrap_or("__sled__default"))
+    }
+
+    // Open a bucket without checking its name, so internal trees can be used as buckets
+    pub(crate) fn internal_bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: &str,
+    ) -> Result<Bucket<'a, K, V>, Error> {
+        let t = self.db.open_tree(name)?;
+        let crypt = self.crypt.bucket(&t.name());
+        self.open_bucket(t, crypt)
+    }
+
+    // Open a bucket that stores values as they are given, even when the store is encrypted
+    pub(crate) fn raw_bucket(&self, name: &str) -> Result<Bucket<'static, Raw, Raw>, Error> {
+        self.open_bucket(self.db.open_tree(name)?, Crypt::default())
+    }
+
+    fn open_bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        t: sled::Tree,
+        crypt: Crypt,
+    ) -> Result<Bucket<'a, K, V>, Error> {
+        let log = match self.config.changefeed {
+            true => changes::open(&self.db, &t.name())?,
+            false => None,
+        };
+        Ok(Bucket::new(
+            t,
+            self.ttl.clone(),
+            crypt,
+            self.observer.clone(),
+            log,
+            self.locks.clone(),
+        ))
+    }
+
+    /// Open a bucket with secondary indexes, indexes are registered using `Indexed::index`
+    pub fn indexed<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: Option<&str>,
+    ) -> Result<Indexed<'a, K, V>, Error> {
+        let bucket = self.bucket(name)?;
+        Ok(Indexed::new(bucket, self.index.clone()))
+    }
+
+    /// Atomically apply batch updates to several buckets, returns an error if the buckets in
+    /// `batch` don't belong to this store
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(bucket = ?batch.names()))
+    )]
+    pub fn batch(&self, batch: StoreBatch) -> Result<(), Error> {
+        batch.apply(&self.observer)
+    }
+
+    /// Copy all items in the bucket named `src` to the bucket named `dst` in `other`, which may
+    /// be the same store, returning the number of items copied. See `Bucket::copy_to`.
+    pub fn copy_bucket_to(
+        &self,
+        other: &Store,
+        src: Option<&str>,
+        dst: Option<&str>,
+    ) -> Result<usize, Error> {
+        let src = self.bucket::<Raw, Raw>(src)?;
+        let dst = other.bucket::<Raw, Raw>(dst)?;
+        src.copy_to(&dst)
+    }
+
+    /// Open a bucket that evicts the least recently used entries once it grows past `limit`
+    pub fn cache_bucket<'a, K: Key<'a>, V: Value>(
+        &self,
+        name: Option<&str>,
+        limit: CacheLimit,
+    ) -> Result<CacheBucket<'a, K, V>, Error> {
+        let bucket = self.bucket(name)?;
+        Ok(CacheBucket::new(
+            bucket,
+            self.cache.clone(),
+            self.db.clone(),
+            limit,
+        ))
     }
 
-    /// Remove a bucket from the store
-    pub fn drop_bucket<S: AsRef<str>>(&self, name: S) -> Result<(), Error> {
-        self.db.drop_tree(name.as_ref().as_bytes())?;
+    /// Open a bucket for streaming large values, values longer than `chunk_size` bytes are
+    /// split into chunks of that size
+    pub fn blob_bucket<'a, K: Key<'a>>(
+        &self,
+        name: Option<&str>,
+        chunk_size: usize,
+    ) -> Result<BlobBucket<'a, K>, Error> {
+        let bucket = self.bucket(name)?;
+        Ok(BlobBucket::new(
+            bucket,
+            self.blobs.clone(),
+            self.blob_writers.clone(),
+            self.db.clone(),
+            chunk_size,
+        ))
+    }
+
+    /// Open a time series whose points are partitioned into trees covering `period` each
+    ///
+    /// Returns `Error::InvalidConfiguration` if the series already exists with another period, and
+    /// an error if `name` contains `/`
+    pub fn time_series<V: Value>(
+        &self,
+        name: &str,
+        period: Duration,
+    ) -> Result<TimeSeriesBucket<V>, Error> {
+        timeseries::check_name(name)?;
+        let meta = self.internal_bucket(&format!("{}{}", timeseries::TREE_PREFIX, name))?;
+        TimeSeriesBucket::new(meta, self.db.clone(), name, period)
+    }
+
+    /// Remove a bucket and all of its contents from the store, returns true if the bucket existed
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(bucket = name.as_ref()))
+    )]
+    pub fn drop_bucket<S: AsRef<str>>(&self, name: S) -> Result<bool, Error> {
+        check_name(name.as_ref())?;
+
+        // The changefeed is kept, so the removal of every key can be read from it once the
+        // bucket is gone
+        if self.config.changefeed && self.bucket_exists(name.as_ref()) {
+            self.raw_bucket(name.as_ref())?.clear()?;
+        }
+        let existed = self.db.drop_tree(name.as_ref().as_bytes())?;
+        ttl::clear(&self.ttl, name.as_ref().as_bytes())?;
+        index::clear(&self.index, name.as_ref().as_bytes())?;
+        cache::clear(&self.cache, name.as_ref().as_bytes())?;
+        ttl::clear(&self.locks, name.as_ref().as_bytes())?;
+        ttl::clear(&self.blobs, name.as_ref().as_bytes())?;
+        Ok(existed)
+    }
+
+    /// Get the current schema version, this is 0 until a migration has been run
+    pub fn schema_version(&self) -> Result<u64, Error> {
+        let meta = self.db.open_tree(migrate::TREE_NAME)?;
+        match meta.get(migrate::VERSION_KEY)? {
+            None => Ok(0),
+            Some(x) if x.len() == 8 => {
+                let mut v = [0u8; 8];
+                v.copy_from_slice(&x);
+                Ok(u64::from_be_bytes(v))
+            }
+            Some(_) => Err(Error::Message("Invalid schema version".into())),
+        }
+    }
+
+    pub(crate) fn set_schema_version(&self, version: u64) -> Result<(), Error> {
+        let meta = self.db.open_tree(migrate::TREE_NAME)?;
+        meta.insert(migrate::VERSION_KEY, &version.to_be_bytes())?;
+        meta.flush()?;
         Ok(())
     }
 
+    /// Check every key and value in the store and compare the checksum of each bucket to the one
+    /// saved by `Verify::save`, see `Verify` to also check that values can be decoded
+    pub fn verify_integrity(&self) -> Result<IntegrityReport, Error> {
+        self.verify().run()
+    }
+
+    /// Create an integrity check for the store, see `Verify`
+    pub fn verify(&self) -> Verify<'_> {
+        Verify::new(self)
+    }
+
+    /// Register migrations to run against the store, see `Migrations`
+    pub fn migrations(&self) -> Migrations<'_> {
+        Migrations::new(self)
+    }
+
+    /// Flush all pending changes to disk, returns the number of bytes flushed
+    ///
+    /// Changes are also flushed in the background every `Config::flush_every_ms`
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn flush(&self) -> Result<usize, Error> {
+        let start = Instant::now();
+        let n = self.db.flush()?;
+        self.observer.flushed(start, n);
+        Ok(n)
+    }
+
+    /// Flush all pending changes to disk asynchronously, returns the number of bytes flushed
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub async fn flush_async(&self) -> Result<usize, Error> {
+        let start = Instant::now();
+        let n = self.db.flush_async().await?;
+        self.observer.flushed(start, n);
+        Ok(n)
+    }
+
+    /// Remove all keys with an expired TTL, returns the number of keys removed
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn purge_expired(&self) -> Result<usize, Error> {
+        ttl::purge(&self.db, &self.ttl, self.config.changefeed)
+    }
+
+    // Get the changefeed of the named bucket without creating it, returns an error if the
+    // changefeed is not enabled
+    pub(crate) fn changefeed(&self, bucket: &str) -> Result<Option<sled::Tree>, Error> {
+        if !self.config.changefeed {
+            return Err(Error::Message("Changefeed is not enabled".into()));
+        }
+        let name = changes::tree_name(bucket.as_bytes());
+        match self.db.tree_names().iter().any(|x| *x == name) {
+            true => Ok(Some(self.db.open_tree(name)?)),
+            false => Ok(None),
+        }
+    }
+
+    /// Iterate over the changefeed of the named bucket starting at sequence number `seq`, this
+    /// requires `Config::changefeed`
+    ///
+    /// Every write to the bucket is recorded in the same transaction as the write itself,
+    /// including keys removed when they expire and by `drop_bucket`. Each bucket has its own
+    /// sequence numbers, so changes to different buckets aren't ordered with respect to each
+    /// other. `merge` returns an error when the changefeed is enabled, since the merge operator
+    /// can't be run as part of a transaction, and `import_from` restores the changefeed from the
+    /// backup instead of recording the keys it writes.
+    ///
+    /// The iterator reflects the changefeed when it was created, call this again with the next
+    /// sequence number to tail the changefeed
+    pub fn changes(&self, bucket: &str, seq: u64) -> Result<Changes, Error> {
+        match self.changefeed(bucket)? {
+            Some(log) => Ok(Changes::new(&log, seq)),
+            None => Ok(Changes::empty()),
+        }
+    }
+
+    /// Remove all changes to the named bucket with a sequence number less than `seq` from the
+    /// changefeed, returns the number of changes removed
+    pub fn truncate_changes(&self, bucket: &str, seq: u64) -> Result<usize, Error> {
+        match self.changefeed(bucket)? {
+            Some(log) => changes::truncate(&log, seq),
+            None => Ok(0),
+        }
+    }
+
     /// Returns the size on disk in bytes
     pub fn size_on_disk(&self) -> Result<u64, Error> {
         let i = self.db.size_on_disk()?;
         Ok(i)
     }
 
+    /// Report how much space the store uses on disk and how much of it is used by live data,
+    /// this reads every key and value in the store
+    pub fn space_usage(&self) -> Result<SpaceUsage, Error> {
+        compact::usage(&self.db)
+    }
+
+    /// Rewrite the store to reclaim space left by removed keys, returning the reopened store
+    ///
+    /// sled eventually reuses space freed by deletions but never shrinks its files, this copies
+    /// the store to a new directory next to it and then replaces the original directory with the
+    /// copy. Temporary and read-only stores can't be compacted.
+    ///
+    /// Every bucket and lock opened from the store must be dropped before calling this, an error
+    /// is returned otherwise. The store stays locked until it's reopened, so other processes
+    /// wait for the compaction to finish before opening it. If the process stops part way
+    /// through, the store is left as it was or the compacted copy is moved into place the next
+    /// time it's opened.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn compact(mut self) -> Result<Store, Error> {
+        // Only persistent, writable stores are opened holding the lock file
+        let lock = match self.file_lock.take() {
+            Some(lock) => lock,
+            None => {
+                return Err(Error::Message(
+                    "Only persistent, writable stores can be compacted".into(),
+                ))
+            }
+        };
+
+        // Writes through another handle would go to the old copy of the store
+        if self.observer.handles() > 0 {
+            return Err(Error::Message(
+                "Every bucket and lock must be dropped before compacting the store".into(),
+            ));
+        }
+
+        let path = self.config.path.clone();
+        let replace = recovery::Replace::begin(
+            &lock,
+            &path,
+            sibling(&path, "compact")?,
+            sibling(&path, "old")?,
+            false,
+        )?;
+
+        let mut copy_config = self.config.clone();
+        copy_config.path = replace.copy().to_path_buf();
+        let dst = copy_config.open()?;
+        self.copy_trees(&dst)?;
+        drop(dst);
+        let config = self.config.clone();
+        let crypt = self.crypt.clone();
+        drop(self);
+
+        replace.commit()?;
+        Store::open_locked(config, crypt, Some(lock))
+    }
+
+    // Copy every tree into `db` and flush it
+    fn copy_trees(&self, db: &sled::Db) -> Result<(), Error> {
+        for name in self.db.tree_names() {
+            let src = self.db.open_tree(&name)?;
+            let dst = db.open_tree(&name)?;
+            let mut batch = sled::Batch::default();
+            let mut n = 0;
+            for item in src.iter() {
+                let (k, v) = item?;
+                batch.insert(k, v);
+                n += 1;
+                if n % 1024 == 0 {
+                    dst.apply_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            dst.apply_batch(batch)?;
+        }
+        db.flush()?;
+        Ok(())
+    }
+
+    /// Write a backup of all buckets to `w`, this can be done while the store is in use
+    ///
+    /// The backup starts with the magic bytes `KVBACKUP` followed by the format version (currently
+    /// `1`) as a big-endian `u32`. This is followed by a sequence of records, each starting with a
+    /// tag byte:
+    ///
+    /// - `1`: start of a bucket, followed by the bucket name. All entries up to the next bucket
+    ///   record belong to this bucket
+    /// - `2`: an entry, followed by the key and then the value
+    /// - `0`: end of the backup
+    ///
+    /// Names, keys and values are prefixed with their length as a big-endian `u64`. Buckets are
+    /// written one at a time, writes made during the backup may or may not be included.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn export_to<W: io::Write>(&self, w: W) -> Result<(), Error> {
+        backup::export(&self.db, w)
+    }
+
+    /// Restore a backup created using `export_to`, existing keys are overwritten
+    ///
+    /// The restored keys aren't recorded in the changefeed, the changefeed is restored from the
+    /// backup along with the buckets instead
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn import_from<R: io::Read>(&self, r: R) -> Result<(), Error> {
+        backup::import(&self.db, r)
+    }
+
     /// Export entire database
     pub fn export(&self) -> Vec<(Vec<u8>, Vec<u8>, impl Iterator<Item = Vec<Vec<u8>>>)> {
         self.db.export()
@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+
+use crate::bucket::{check_conditions, Condition};
+use crate::changes;
+use crate::crypt::Crypt;
+use crate::observer::Observer;
+use crate::transaction::{run_on, stash_abort, unstash_abort, MAX_TREES};
+use crate::ttl;
+use crate::{Batch, Bucket, Error, Key, Raw, TransactionError, Value};
+
+struct Staged {
+    store: Observer,
+    tree: sled::Tree,
+    ttl: sled::Tree,
+    log: Option<sled::Tree>,
+    crypt: Crypt,
+    items: Vec<(Raw, Option<Raw>)>,
+    conditions: Vec<(Raw, Condition)>,
+}
+
+impl Staged {
+    // Number of trees used by the bucket in the transaction
+    fn trees(&self) -> usize {
+        1 + self.log.iter().count()
+    }
+}
+
+/// Batch update across several buckets in the same store, applied atomically using
+/// `Store::batch`
+///
+/// Conditions from every batch are checked before anything is written, so if one of them fails
+/// no bucket is changed. Every bucket and changefeed in the batch is part of one sled
+/// transaction, which can use at most 69 trees including one shared by every bucket, so at most
+/// 68 buckets can be updated by a single `StoreBatch`, or 34 when the changefeed is enabled.
+#[derive(Default)]
+pub struct StoreBatch(Vec<Staged>);
+
+impl StoreBatch {
+    /// Create a new StoreBatch instance
+    pub fn new() -> StoreBatch {
+        StoreBatch::default()
+    }
+
+    /// Stage `batch` to be applied to `bucket`, batches added for the same bucket are applied in
+    /// the order they were added
+    pub fn add<'a, K: Key<'a>, V: Value>(
+        &mut self,
+        bucket: &Bucket<'a, K, V>,
+        batch: Batch<K, V>,
+    ) -> Result<(), Error> {
+        if let Some(x) = self.0.first() {
+            if !bucket.observer.is_store(&x.store) {
+                return Err(Error::Message(
+                    "Every bucket in a StoreBatch must belong to the same store".into(),
+                ));
+            }
+        }
+
+        let items = batch.encrypted(&bucket.crypt)?;
+        let name = bucket.tree.name();
+        if let Some(staged) = self.0.iter_mut().find(|x| x.tree.name() == name) {
+            staged.items.extend(items);
+            staged.conditions.extend(batch.2);
+            return Ok(());
+        }
+
+        let trees = 1 + self.0.iter().map(Staged::trees).sum::<usize>();
+        if trees + 1 + bucket.changefeed.iter().count() > MAX_TREES {
+            return Err(Error::Message(format!(
+                "A StoreBatch can use at most {} trees, one for each bucket and changefeed and \
+                 one for expiration deadlines",
+                MAX_TREES
+            )));
+        }
+        self.0.push(Staged {
+            store: bucket.observer.clone(),
+            tree: bucket.tree.clone(),
+            ttl: bucket.ttl.clone(),
+            log: bucket.changefeed.clone(),
+            crypt: bucket.crypt.clone(),
+            items,
+            conditions: batch.2,
+        });
+        Ok(())
+    }
+
+    /// Returns the number of buckets in the batch
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true when no batches have been added
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn names(&self) -> Vec<String> {
+        let names = self.0.iter().map(|x| x.tree.name());
+        names
+            .map(|x| String::from_utf8_lossy(&x).into_owned())
+            .collect()
+    }
+
+    // `t` holds the expiration tree shared by every bucket in the store, then each bucket and
+    // then the changefeeds of the buckets that have one
+    fn apply_to(&self, t: &[&sled::TransactionalTree]) -> Result<(), TransactionError<Error>> {
+        let n = self.0.len();
+        for (staged, tree) in self.0.iter().zip(&t[1..=n]) {
+            check_conditions(&staged.conditions, tree, &staged.crypt)?;
+        }
+
+        let mut logs = t[1 + n..].iter();
+        for (staged, tree) in self.0.iter().zip(&t[1..=n]) {
+            let log = staged.log.as_ref().and_then(|_| logs.next());
+            let name = staged.tree.name();
+            for (k, v) in &staged.items {
+                let old = match v {
+                    Some(v) => tree.insert(k, v)?,
+                    None => tree.remove(k)?,
+                };
+                t[0].remove(ttl::key(&name, k))?;
+                if let Some(log) = log {
+                    changes::record(log, &name, k, old.as_deref(), v.as_deref())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply(self, store: &Observer) -> Result<(), Error> {
+        let s = &self.0;
+        if s.is_empty() {
+            return Ok(());
+        }
+        if !store.is_store(&s[0].store) {
+            return Err(Error::Message(
+                "A StoreBatch can only be applied to the store its buckets belong to".into(),
+            ));
+        }
+
+        // Expired values are removed first so conditions don't see them, see `Bucket::batch`
+        for staged in s {
+            for (k, _) in &staged.conditions {
+                if ttl::check(&staged.tree, &staged.ttl, k)? {
+                    let log = staged.log.as_ref();
+                    ttl::expire(&staged.tree, &staged.ttl, log, k, ttl::now()?)?;
+                }
+            }
+        }
+
+        let mut trees = vec![&s[0].ttl];
+        trees.extend(s.iter().map(|x| &x.tree));
+        trees.extend(s.iter().filter_map(|x| x.log.as_ref()));
+
+        let error = RefCell::new(None);
+        let res = run_on(&trees, |t| stash_abort(&error, self.apply_to(t)));
+        unstash_abort(error, res)
+    }
+}
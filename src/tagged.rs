@@ -0,0 +1,123 @@
+use crate::{Error, Raw, Value};
+
+/// Enum whose variants can be stored in the same bucket using `Tagged`, each variant has a
+/// distinct tag
+///
+/// Use the `tagged!` macro to implement this for an enum where every variant holds a single
+/// `Value`, the variant names are used as tags.
+pub trait TaggedEnum: Sized {
+    /// Returns the tag for the variant of `self`
+    fn tag(&self) -> &'static str;
+
+    /// Encode the value held by `self`, without the tag
+    fn to_raw_value(&self) -> Result<Raw, Error>;
+
+    /// Decode a value stored with `tag`, returning `Error::UnknownTag` if no variant has that
+    /// tag
+    fn from_raw_value(tag: &str, r: Raw) -> Result<Self, Error>;
+}
+
+#[macro_export]
+/// Implement `TaggedEnum` for an enum where every variant holds a single `Value`, for example
+/// `tagged!(Shape { Circle, Square })`
+macro_rules! tagged {
+    ($e:ident { $($v:ident),+ $(,)? }) => {
+        impl $crate::TaggedEnum for $e {
+            fn tag(&self) -> &'static str {
+                match self {
+                    $($e::$v(_) => stringify!($v),)+
+                }
+            }
+
+            fn to_raw_value(&self) -> Result<$crate::Raw, $crate::Error> {
+                match self {
+                    $($e::$v(x) => $crate::Value::to_raw_value(x),)+
+                }
+            }
+
+            fn from_raw_value(tag: &str, r: $crate::Raw) -> Result<Self, $crate::Error> {
+                $(if tag == stringify!($v) {
+                    return Ok($e::$v($crate::Value::from_raw_value(r)?));
+                })+
+                Err($crate::Error::UnknownTag {
+                    tag: tag.to_string(),
+                    ty: std::any::type_name::<$e>(),
+                })
+            }
+        }
+    };
+}
+
+/// Value wrapper that stores the tag of a `TaggedEnum` variant with its value, so a bucket can
+/// hold several value types
+///
+/// Values with a tag that doesn't match any variant, including values that were stored without
+/// `Tagged`, fail to decode with `Error::UnknownTag`
+pub struct Tagged<E>(pub E);
+
+impl<E> Tagged<E> {
+    /// Wrap a value
+    pub fn new(x: E) -> Tagged<E> {
+        Tagged(x)
+    }
+
+    /// Convert back into inner value
+    pub fn into_inner(self) -> E {
+        self.0
+    }
+}
+
+impl<E> From<E> for Tagged<E> {
+    fn from(x: E) -> Tagged<E> {
+        Tagged::new(x)
+    }
+}
+
+impl<E> AsRef<E> for Tagged<E> {
+    fn as_ref(&self) -> &E {
+        &self.0
+    }
+}
+
+impl<E> AsMut<E> for Tagged<E> {
+    fn as_mut(&mut self) -> &mut E {
+        &mut self.0
+    }
+}
+
+impl<E: Clone> Clone for Tagged<E> {
+    fn clone(&self) -> Self {
+        Tagged::new(self.0.clone())
+    }
+}
+
+// The tag is stored before the encoded value, preceded by its length as a single byte
+impl<E: TaggedEnum> Value for Tagged<E> {
+    fn to_raw_value(&self) -> Result<Raw, Error> {
+        let tag = self.0.tag();
+        if tag.len() > u8::MAX as usize {
+            return Err(Error::Message(format!("Tag is too long: {:?}", tag)));
+        }
+
+        let x = self.0.to_raw_value()?;
+        let mut dst = Vec::with_capacity(1 + tag.len() + x.len());
+        dst.push(tag.len() as u8);
+        dst.extend_from_slice(tag.as_bytes());
+        dst.extend_from_slice(&x);
+        Ok(dst.into())
+    }
+
+    fn from_raw_value(r: Raw) -> Result<Self, Error> {
+        let n = r.first().map(|n| *n as usize + 1).unwrap_or(0);
+        let tag = match r.get(1..n).map(std::str::from_utf8) {
+            Some(Ok(tag)) => tag,
+            _ => {
+                return Err(Error::UnknownTag {
+                    tag: String::from_utf8_lossy(r.get(1..n).unwrap_or(&r)).into_owned(),
+                    ty: std::any::type_name::<E>(),
+                })
+            }
+        };
+        Ok(Tagged::new(E::from_raw_value(tag, r[n..].into())?))
+    }
+}
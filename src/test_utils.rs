@@ -0,0 +1,300 @@
+//! Helpers for testing `Key` and `Value` implementations
+//!
+//! `Gen` produces pseudo-random values from a seed, so a failing case can be reproduced by
+//! running the check again with the same seed. The `check_*` functions generate values using
+//! `Arbitrary` and panic with the failing input if an encoding doesn't round trip or doesn't
+//! preserve ordering. `fuzz_key` and `fuzz_value` can be called with the input of a fuzzing
+//! target to check that decoding arbitrary bytes never panics.
+
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+use crate::{
+    Checked, Composite, Compressed, Compression, Integer, Key, KeyPart, Raw, SignedInteger, Value,
+};
+
+/// Pseudo-random generator used by `Arbitrary`
+pub struct Gen {
+    state: u64,
+    size: usize,
+}
+
+impl Gen {
+    /// Create a generator from `seed`, strings and byte strings are at most 64 bytes long
+    pub fn new(seed: u64) -> Gen {
+        Gen {
+            state: seed ^ 0x9e37_79b9_7f4a_7c15,
+            size: 64,
+        }
+    }
+
+    /// Set the maximum length of generated strings and byte strings
+    pub fn size(mut self, size: usize) -> Gen {
+        self.size = size;
+        self
+    }
+
+    /// Generate a `u64`
+    pub fn u64(&mut self) -> u64 {
+        // xorshift64*
+        if self.state == 0 {
+            self.state = 1;
+        }
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Generate a number less than `n`, `n` must not be 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.u64() % n
+    }
+
+    /// Generate a byte string, `0x00` and `0xff` are generated more often than other bytes since
+    /// encodings often treat them specially
+    pub fn bytes(&mut self) -> Vec<u8> {
+        let n = self.below(self.size as u64 + 1) as usize;
+        (0..n)
+            .map(|_| match self.below(8) {
+                0 => 0,
+                1 => 0xff,
+                _ => self.u64() as u8,
+            })
+            .collect()
+    }
+
+    /// Generate a string
+    pub fn string(&mut self) -> String {
+        let n = self.below(self.size as u64 + 1) as usize;
+        (0..n)
+            .map(|_| match self.below(8) {
+                0 => '\0',
+                1 => char::from_u32(self.below(0x11_0000) as u32).unwrap_or('\u{fffd}'),
+                _ => (b' ' + self.below(95) as u8) as char,
+            })
+            .collect()
+    }
+}
+
+/// Types that can be generated by `Gen`
+pub trait Arbitrary: Sized {
+    /// Generate a value
+    fn arbitrary(g: &mut Gen) -> Self;
+}
+
+macro_rules! arbitrary_int {
+    ($($t:ty),*) => {
+        $(
+            impl Arbitrary for $t {
+                fn arbitrary(g: &mut Gen) -> Self {
+                    // Small values and the extremes are generated more often
+                    match g.below(4) {
+                        0 => g.below(16) as $t,
+                        1 => <$t>::MIN,
+                        2 => <$t>::MAX,
+                        _ => ((g.u64() as u128) << 64 | g.u64() as u128) as $t,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128);
+
+impl Arbitrary for bool {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.below(2) == 1
+    }
+}
+
+impl Arbitrary for Vec<u8> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.bytes()
+    }
+}
+
+impl Arbitrary for String {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.string()
+    }
+}
+
+impl Arbitrary for Raw {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.bytes().into()
+    }
+}
+
+impl Arbitrary for Integer {
+    fn arbitrary(g: &mut Gen) -> Self {
+        u128::arbitrary(g).into()
+    }
+}
+
+impl Arbitrary for SignedInteger {
+    fn arbitrary(g: &mut Gen) -> Self {
+        i128::arbitrary(g).into()
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Option<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        match g.below(4) {
+            0 => None,
+            _ => Some(T::arbitrary(g)),
+        }
+    }
+}
+
+macro_rules! arbitrary_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: Arbitrary),*> Arbitrary for ($($t,)*) {
+            fn arbitrary(g: &mut Gen) -> Self {
+                ($($t::arbitrary(g),)*)
+            }
+        }
+    };
+}
+
+arbitrary_tuple!(A);
+arbitrary_tuple!(A, B);
+arbitrary_tuple!(A, B, C);
+arbitrary_tuple!(A, B, C, D);
+arbitrary_tuple!(A, B, C, D, E);
+arbitrary_tuple!(A, B, C, D, E, F);
+
+impl<T: KeyPart + Arbitrary> Arbitrary for Composite<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Composite::new(T::arbitrary(g))
+    }
+}
+
+impl<V: Arbitrary> Arbitrary for Checked<V> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Checked::new(V::arbitrary(g))
+    }
+}
+
+impl<V: Arbitrary, C: Compression> Arbitrary for Compressed<V, C> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Compressed::new(V::arbitrary(g))
+    }
+}
+
+macro_rules! arbitrary_codec {
+    ($($feature:literal => $x:ident),*) => {
+        $(
+            #[cfg(feature = $feature)]
+            impl<T: Arbitrary + serde::Serialize + serde::de::DeserializeOwned> Arbitrary
+                for crate::$x<T>
+            {
+                fn arbitrary(g: &mut Gen) -> Self {
+                    crate::$x(T::arbitrary(g))
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_codec!(
+    "json-value" => Json,
+    "msgpack-value" => Msgpack,
+    "bincode-value" => Bincode,
+    "lexpr-value" => Lexpr,
+    "cbor-value" => Cbor
+);
+
+/// Panic unless `key` decodes from its encoding and encodes to the same bytes again
+pub fn assert_key_round_trip<K: for<'a> Key<'a>>(key: &K) {
+    let raw = key.to_raw_key().expect("key failed to encode");
+    let decoded = match K::from_raw_key(&raw) {
+        Ok(x) => x,
+        Err(e) => panic!("key encoded as {:?} failed to decode: {}", raw, e),
+    };
+    let again = decoded.to_raw_key().expect("decoded key failed to encode");
+    assert_eq!(raw, again, "key changed after a round trip");
+}
+
+/// Panic unless `value` decodes from its encoding and encodes to the same bytes again
+pub fn assert_value_round_trip<V: Value>(value: &V) {
+    let raw = value.to_raw_value().expect("value failed to encode");
+    let decoded = match V::from_raw_value(raw.clone()) {
+        Ok(x) => x,
+        Err(e) => panic!("value encoded as {:?} failed to decode: {}", raw, e),
+    };
+    let again = decoded
+        .to_raw_value()
+        .expect("decoded value failed to encode");
+    assert_eq!(raw, again, "value changed after a round trip");
+}
+
+/// Panic unless the keys created from `a` and `b` using `key` sort in the same order as `a`
+/// and `b`
+pub fn assert_order_preserved<T: PartialOrd + Debug, K: for<'a> Key<'a>, F: Fn(&T) -> K>(
+    a: &T,
+    b: &T,
+    key: F,
+) {
+    let expected = match a.partial_cmp(b) {
+        Some(x) => x,
+        None => return,
+    };
+    let x = key(a).to_raw_key().expect("key failed to encode");
+    let y = key(b).to_raw_key().expect("key failed to encode");
+    assert_eq!(
+        x.cmp(&y),
+        expected,
+        "order of {:?} and {:?} is not preserved by their encodings {:?} and {:?}",
+        a,
+        b,
+        x,
+        y
+    );
+    if expected != Ordering::Equal {
+        assert_ne!(x, y, "{:?} and {:?} have the same encoding", a, b);
+    }
+}
+
+/// Check that `n` generated keys round trip
+pub fn check_keys<K: for<'a> Key<'a> + Arbitrary>(g: &mut Gen, n: usize) {
+    for _ in 0..n {
+        assert_key_round_trip(&K::arbitrary(g));
+    }
+}
+
+/// Check that `n` generated values round trip
+pub fn check_values<V: Value + Arbitrary>(g: &mut Gen, n: usize) {
+    for _ in 0..n {
+        assert_value_round_trip(&V::arbitrary(g));
+    }
+}
+
+/// Check that keys created using `key` preserve the order of `n` generated pairs of values
+pub fn check_order<T, K, F>(g: &mut Gen, n: usize, key: F)
+where
+    T: Arbitrary + PartialOrd + Debug,
+    K: for<'a> Key<'a>,
+    F: Fn(&T) -> K,
+{
+    for _ in 0..n {
+        assert_order_preserved(&T::arbitrary(g), &T::arbitrary(g), &key);
+    }
+}
+
+/// Decode `data` as a key, checking that decoding doesn't panic and that any key it decodes
+/// round trips
+pub fn fuzz_key<K: for<'a> Key<'a>>(data: &[u8]) {
+    if let Ok(key) = K::from_raw_key(&data.into()) {
+        assert_key_round_trip(&key);
+    }
+}
+
+/// Decode `data` as a value, checking that decoding doesn't panic and that any value it decodes
+/// round trips
+pub fn fuzz_value<V: Value>(data: &[u8]) {
+    if let Ok(value) = V::from_raw_value(data.into()) {
+        assert_value_round_trip(&value);
+    }
+}
@@ -22,6 +22,59 @@ fn test_basic() {
     assert_eq!(bucket.get("testing").unwrap().unwrap(), b"abc123");
 }
 
+#[test]
+fn test_buckets() {
+    let path = reset("buckets");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    assert!(!store.bucket_exists("a"));
+
+    store.bucket::<&str, Raw>(Some("a")).unwrap();
+    store.bucket::<&str, Raw>(Some("b")).unwrap();
+    assert!(store.bucket_exists("a"));
+    assert!(store.bucket_exists("b"));
+    assert!(!store.bucket_exists("__kv__ttl"));
+    assert!(store.bucket::<&str, Raw>(Some("__kv__ttl")).is_err());
+    assert!(store.drop_bucket("__kv__ttl").is_err());
+
+    let buckets = store.buckets();
+    assert!(buckets.contains(&"a".to_string()));
+    assert!(buckets.contains(&"b".to_string()));
+
+    let a = store.bucket::<&str, Raw>(Some("a")).unwrap();
+    a.set("x", "1").unwrap();
+    a.set_with_ttl("y", "2", std::time::Duration::from_secs(60))
+        .unwrap();
+
+    assert!(store.drop_bucket("a").unwrap());
+    assert!(!store.drop_bucket("a").unwrap());
+    assert!(!store.bucket_exists("a"));
+    assert!(!store.buckets().contains(&"a".to_string()));
+
+    let a = store.bucket::<&str, Raw>(Some("a")).unwrap();
+    assert!(a.is_empty());
+    assert!(a.get("x").unwrap().is_none());
+}
+
+#[test]
+fn test_stats() {
+    let path = reset("stats");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Raw>(None).unwrap();
+    assert!(bucket.is_empty());
+
+    bucket.set("abc", "12345").unwrap();
+    bucket.set("de", "6").unwrap();
+    assert!(!bucket.is_empty());
+    assert_eq!(bucket.len(), 2);
+
+    bucket.flush().unwrap();
+    assert!(store.size_on_disk().unwrap() > 0);
+}
+
 #[test]
 fn test_integer_keys() {
     let path = reset("integer_keys");
@@ -96,6 +149,52 @@ fn test_msgpack_encoding() {
     );
 }
 
+#[cfg(all(feature = "msgpack-value", feature = "json-value"))]
+#[test]
+fn test_msgpack_json_interop() {
+    use crate::{Json, Msgpack};
+    let path = reset("msgpack_json");
+
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+    struct Testing {
+        a: i32,
+        b: String,
+    }
+
+    let t = Testing {
+        a: 1,
+        b: "field".into(),
+    };
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+
+    // Other MessagePack implementations typically encode structs as maps
+    let raw = store.bucket::<&str, Raw>(Some("msgpack")).unwrap();
+    raw.set("named", rmp_serde::to_vec_named(&t).unwrap())
+        .unwrap();
+
+    let msgpack = store
+        .bucket::<&str, Msgpack<Testing>>(Some("msgpack"))
+        .unwrap();
+    assert_eq!(msgpack.get("named").unwrap().unwrap().0, t);
+
+    let json = store.bucket::<&str, Json<Testing>>(Some("json")).unwrap();
+    json.set(
+        "named",
+        Json(msgpack.get("named").unwrap().unwrap().to_inner()),
+    )
+    .unwrap();
+    msgpack
+        .set(
+            "copy",
+            Msgpack(json.get("named").unwrap().unwrap().to_inner()),
+        )
+        .unwrap();
+    assert_eq!(msgpack.get("copy").unwrap().unwrap().0, t);
+    assert_eq!(json.get("named").unwrap().unwrap().0, t);
+}
+
 #[cfg(feature = "json-value")]
 #[test]
 fn test_json_encoding() {
@@ -133,6 +232,33 @@ fn test_json_encoding() {
     );
 }
 
+#[cfg(feature = "json-value")]
+#[test]
+fn test_json_lines() {
+    let path = reset("json_lines");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Raw, Raw>(Some("a")).unwrap();
+    bucket.set(b"abc", b"123").unwrap();
+    bucket.set(&[0xff, 0], &[0xff, 1]).unwrap();
+
+    let mut buf = Vec::new();
+    bucket.export_json(&mut buf).unwrap();
+    let s = String::from_utf8(buf.clone()).unwrap();
+    assert_eq!(
+        s,
+        "{\"key\":\"abc\",\"value\":\"123\"}\n{\"key\":[255,0],\"value\":[255,1]}\n"
+    );
+
+    let copy = store.bucket::<Raw, Raw>(Some("b")).unwrap();
+    copy.import_json(buf.as_slice()).unwrap();
+    assert_eq!(bucket.checksum().unwrap(), copy.checksum().unwrap());
+
+    assert!(copy.import_json(&b"{\"key\": 1}"[..]).is_err());
+    assert!(copy.import_json(&b"not json"[..]).is_err());
+}
+
 #[cfg(feature = "bincode-value")]
 #[test]
 fn test_bincode_encoding() {
@@ -170,6 +296,58 @@ fn test_bincode_encoding() {
     );
 }
 
+#[cfg(feature = "bincode-value")]
+#[test]
+fn test_bincode_batch_transaction() {
+    use crate::Bincode;
+    let path = reset("bincode_batch_transaction");
+
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+    struct Testing {
+        a: i32,
+        b: String,
+    }
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<String, Bincode<Testing>>(None).unwrap();
+
+    let mut batch = Batch::new();
+    for i in 0..10 {
+        let t = Testing {
+            a: i,
+            b: format!("{}", i),
+        };
+        batch.set(format!("batch{}", i), &Bincode(t)).unwrap();
+    }
+    bucket.batch(batch).unwrap();
+
+    for i in 0..10 {
+        let v = bucket.get(format!("batch{}", i)).unwrap().unwrap();
+        assert_eq!(v.0.a, i);
+        assert_eq!(v.0.b, format!("{}", i));
+    }
+
+    bucket
+        .transaction(|txn| {
+            let x = txn.get("batch1")?.unwrap();
+            let mut t = x.to_inner();
+            t.a += 100;
+            txn.set("txn", Bincode(t))?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+
+    let v = bucket.get("txn").unwrap().unwrap();
+    assert_eq!(
+        v.0,
+        Testing {
+            a: 101,
+            b: "1".into(),
+        }
+    );
+}
+
 #[cfg(feature = "lexpr-value")]
 #[test]
 fn test_sexpr_encoding() {
@@ -207,6 +385,43 @@ fn test_sexpr_encoding() {
     );
 }
 
+#[cfg(feature = "cbor-value")]
+#[test]
+fn test_cbor_encoding() {
+    use crate::Cbor;
+    let path = reset("cbor");
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+    struct Testing {
+        a: i32,
+        b: String,
+    }
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Cbor<Testing>>(None).unwrap();
+    assert!(path::Path::new(path.as_str()).exists());
+
+    bucket
+        .set(
+            "testing",
+            Cbor(Testing {
+                a: 1,
+                b: "field".into(),
+            }),
+        )
+        .unwrap();
+
+    let v = bucket.get("testing").unwrap();
+    assert_eq!(
+        v.unwrap().0,
+        Testing {
+            a: 1,
+            b: "field".into(),
+        }
+    );
+}
+
 #[test]
 fn test_config_encoding() {
     let mut cfg = Config::new("./test");
@@ -218,6 +433,70 @@ fn test_config_encoding() {
     let _ = fs::remove_file("./config");
 }
 
+#[test]
+fn test_config_env() {
+    let path = reset("config_env");
+
+    // The environment is shared by every test, so the variables are passed explicitly
+    let mut vars = std::collections::HashMap::new();
+    let mut set = |k: &str, v: &str| {
+        vars.insert(format!("KV_TEST_ENV_{}", k), std::ffi::OsString::from(v));
+    };
+    set("PATH", &path);
+    set("CACHE_CAPACITY", "1000000");
+    set("USE_COMPRESSION", "1");
+    set("SEGMENT_MODE", "gc");
+    let cfg = Config::from_vars("KV_TEST_ENV_", &vars).unwrap();
+    assert_eq!(cfg.path, std::path::PathBuf::from(&path));
+    assert_eq!(cfg.cache_capacity, Some(1000000));
+    assert!(cfg.use_compression);
+    assert_eq!(cfg.segment_mode, Some(SegmentMode::Gc));
+
+    let cfg = Config::new("other")
+        .cache_capacity(10)
+        .merge_vars("KV_TEST_ENV_", &vars)
+        .unwrap();
+    assert_eq!(cfg.cache_capacity, Some(1000000));
+    assert!(!cfg.read_only);
+
+    vars.insert(
+        "KV_TEST_ENV_FLUSH_EVERY_MS".into(),
+        std::ffi::OsString::from("soon"),
+    );
+    match Config::from_vars("KV_TEST_ENV_", &vars) {
+        Err(Error::InvalidConfiguration(msg)) => {
+            assert!(msg.contains("KV_TEST_ENV_FLUSH_EVERY_MS"))
+        }
+        _ => panic!("expected error"),
+    }
+    assert!(Config::from_env("KV_TEST_MISSING_").is_err());
+}
+
+#[test]
+fn test_config_tuning() {
+    let path = reset("config_tuning");
+
+    let cfg = Config::new(path.clone())
+        .segment_mode(SegmentMode::Gc)
+        .segment_size(1 << 20)
+        .snapshot_after_ops(1000)
+        .idgen_persist_interval(100)
+        .print_profile_on_drop(false);
+
+    let mut buf = Vec::new();
+    cfg.save_to(&mut buf).unwrap();
+    assert!(String::from_utf8(buf.clone())
+        .unwrap()
+        .contains("segment_mode = \"gc\""));
+    let cfg2 = Config::load_from(buf.as_slice()).unwrap();
+    assert!(cfg == cfg2);
+
+    let store = Store::new(cfg2).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+    bucket.set("a", "1").unwrap();
+    assert_eq!(bucket.get("a").unwrap().unwrap(), "1");
+}
+
 #[test]
 fn test_watch() {
     let path = reset("watch");
@@ -244,3 +523,2676 @@ fn test_watch() {
     assert!(next.value().unwrap() == None);
     assert!(next.key().unwrap() == "abc");
 }
+
+#[test]
+fn test_watch_all() {
+    let path = reset("watch_all");
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("watch_all")).unwrap();
+    let mut watch = bucket.watch_all().unwrap();
+
+    bucket.set("abc", "123").unwrap();
+    bucket.set("def", "456").unwrap();
+
+    let next = watch.next().unwrap().unwrap();
+    assert!(next.is_set());
+    assert!(next.key().unwrap() == "abc");
+    assert!(next.value().unwrap().unwrap() == "123");
+
+    let next = watch.next().unwrap().unwrap();
+    assert!(next.is_set());
+    assert!(next.key().unwrap() == "def");
+    assert!(next.value().unwrap().unwrap() == "456");
+}
+
+#[test]
+fn test_range() {
+    let path = reset("range");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+
+    for i in 0..100 {
+        bucket.set(i, format!("{}", i)).unwrap();
+    }
+
+    let keys: Vec<u128> = bucket
+        .range(10..20)
+        .unwrap()
+        .map(|item| item.unwrap().key().unwrap())
+        .collect();
+    assert_eq!(keys, (10..20).collect::<Vec<u128>>());
+
+    assert_eq!(bucket.range(10..=20).unwrap().count(), 11);
+    assert_eq!(bucket.range(..5).unwrap().count(), 5);
+    assert_eq!(bucket.range(95..).unwrap().count(), 5);
+    assert_eq!(bucket.range::<i32, _>(..).unwrap().count(), 100);
+
+    let names = store.bucket::<&str, String>(Some("names")).unwrap();
+    for k in &["a/1", "a/2", "ab", "b/1"] {
+        names.set(*k, "x").unwrap();
+    }
+    let keys: Vec<String> = names
+        .range_prefix("a/")
+        .unwrap()
+        .map(|item| item.unwrap().key::<&str>().unwrap().to_string())
+        .collect();
+    assert_eq!(keys, vec!["a/1", "a/2"]);
+    assert_eq!(names.range_prefix("c").unwrap().count(), 0);
+}
+
+#[test]
+fn test_iter_rev() {
+    let path = reset("iter_rev");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+
+    for i in 0..100 {
+        bucket.set(i, format!("{}", i)).unwrap();
+    }
+
+    let keys: Vec<u128> = bucket
+        .iter()
+        .rev()
+        .take(3)
+        .map(|item| item.unwrap().key().unwrap())
+        .collect();
+    assert_eq!(keys, vec![99, 98, 97]);
+
+    let keys: Vec<u128> = bucket
+        .range(10..20)
+        .unwrap()
+        .rev()
+        .map(|item| item.unwrap().key().unwrap())
+        .collect();
+    assert_eq!(keys, (10..20).rev().collect::<Vec<u128>>());
+}
+
+#[test]
+fn test_compare_and_swap() {
+    let path = reset("compare_and_swap");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    bucket
+        .compare_and_swap("key", None, Some("a".to_string()))
+        .unwrap()
+        .unwrap();
+    bucket
+        .compare_and_swap("key", Some("a".to_string()), Some("b".to_string()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(bucket.get("key").unwrap().unwrap(), "b");
+
+    let e = bucket
+        .compare_and_swap("key", Some("a".to_string()), Some("c".to_string()))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(e.current.unwrap(), "b");
+    assert_eq!(e.proposed.unwrap(), "c");
+    assert_eq!(bucket.get("key").unwrap().unwrap(), "b");
+
+    bucket
+        .compare_and_swap("key", Some("b".to_string()), None)
+        .unwrap()
+        .unwrap();
+    assert!(!bucket.contains("key").unwrap());
+}
+
+#[test]
+fn test_fetch_and_update() {
+    let path = reset("fetch_and_update");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    let prev = bucket
+        .fetch_and_update("key", |x| match x {
+            Some(x) => Some(format!("{}b", x)),
+            None => Some("a".to_string()),
+        })
+        .unwrap();
+    assert!(prev.is_none());
+
+    let prev = bucket
+        .fetch_and_update("key", |x| x.map(|x| format!("{}b", x)))
+        .unwrap();
+    assert_eq!(prev.unwrap(), "a");
+    assert_eq!(bucket.get("key").unwrap().unwrap(), "ab");
+
+    bucket.fetch_and_update("key", |_| None).unwrap();
+    assert!(!bucket.contains("key").unwrap());
+}
+
+#[test]
+fn test_merge() {
+    let path = reset("merge");
+
+    struct Append;
+
+    impl Merge<String> for Append {
+        fn merge(_key: &[u8], old: Option<String>, value: String) -> Option<String> {
+            match old {
+                Some(old) => Some(old + &value),
+                None => Some(value),
+            }
+        }
+    }
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+    bucket.set_merge_operator::<Append>();
+
+    bucket.merge("key", "a").unwrap();
+    bucket.merge("key", "b").unwrap();
+    let x = bucket.merge("key", "c").unwrap();
+    assert_eq!(x.unwrap(), "abc");
+    assert_eq!(bucket.get("key").unwrap().unwrap(), "abc");
+}
+
+#[test]
+fn test_ttl() {
+    let path = reset("ttl");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("ttl")).unwrap();
+
+    bucket
+        .set_with_ttl("a", "1", std::time::Duration::from_millis(0))
+        .unwrap();
+    bucket
+        .set_with_ttl("b", "2", std::time::Duration::from_secs(3600))
+        .unwrap();
+    bucket
+        .set_with_ttl("c", "3", std::time::Duration::from_millis(0))
+        .unwrap();
+    bucket
+        .set_with_ttl("d", "4", std::time::Duration::from_millis(0))
+        .unwrap();
+
+    // Setting a key without a TTL clears the deadline
+    bucket.set("d", "5").unwrap();
+
+    assert!(bucket.get("a").unwrap().is_none());
+    assert!(!bucket.contains("a").unwrap());
+    assert_eq!(bucket.get("b").unwrap().unwrap(), "2");
+    assert_eq!(bucket.get("d").unwrap().unwrap(), "5");
+
+    // "c" has expired but is only removed by `purge_expired`, every other read skips it
+    let values = |x: Option<Item<&str, String>>| x.map(|x| x.value::<String>().unwrap());
+    let all: Result<Vec<String>, Error> = bucket.values().collect();
+    assert_eq!(all.unwrap(), vec!["2", "5"]);
+    assert_eq!(bucket.iter().count(), 2);
+    assert_eq!(bucket.iter().rev().count(), 2);
+    assert_eq!(bucket.page(None, 10).unwrap().items.len(), 2);
+    assert_eq!(values(bucket.next_key("b").unwrap()).unwrap(), "5");
+    assert_eq!(values(bucket.prev_key("d").unwrap()).unwrap(), "2");
+    assert_eq!(bucket.len(), 2);
+    assert_eq!(store.purge_expired().unwrap(), 1);
+    assert_eq!(bucket.len(), 2);
+    assert!(!store.buckets().iter().any(|x| x.starts_with("__kv__")));
+
+    bucket
+        .set_with_ttl("e", "6", std::time::Duration::from_millis(0))
+        .unwrap();
+    assert_eq!(values(bucket.last().unwrap()).unwrap(), "5");
+    assert_eq!(values(bucket.pop_back().unwrap()).unwrap(), "5");
+    assert_eq!(values(bucket.pop_front().unwrap()).unwrap(), "2");
+    assert!(bucket.pop_front().unwrap().is_none());
+    assert!(bucket.is_empty());
+}
+
+#[test]
+fn test_ttl_overwrite() {
+    let path = reset("ttl_overwrite");
+
+    struct Append;
+
+    impl Merge<String> for Append {
+        fn merge(_key: &[u8], old: Option<String>, value: String) -> Option<String> {
+            match old {
+                Some(old) => Some(old + &value),
+                None => Some(value),
+            }
+        }
+    }
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("ttl")).unwrap();
+    bucket.set_merge_operator::<Append>();
+
+    let keys = ["batch", "txn", "cas", "update", "merge", "store_batch"];
+    for k in keys.iter() {
+        bucket
+            .set_with_ttl(*k, "old", std::time::Duration::from_millis(0))
+            .unwrap();
+    }
+    bucket
+        .set_with_ttl("s/scoped", "old", std::time::Duration::from_millis(0))
+        .unwrap();
+
+    // Every kind of write replaces the expired value and clears its deadline
+    let mut batch = Batch::new();
+    batch.set("batch", &"new".to_string()).unwrap();
+    bucket.batch(batch).unwrap();
+    bucket
+        .transaction(|t| {
+            t.set("txn", "new")?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    bucket
+        .compare_and_swap("cas", None, Some("new".to_string()))
+        .unwrap()
+        .unwrap();
+    assert!(bucket
+        .fetch_and_update("update", |x| {
+            assert!(x.is_none());
+            Some("new".to_string())
+        })
+        .unwrap()
+        .is_none());
+    assert_eq!(bucket.merge("merge", "new").unwrap().unwrap(), "new");
+    bucket.scoped("s/").set("scoped", "new").unwrap();
+    let mut batch = Batch::new();
+    batch.set("store_batch", &"new".to_string()).unwrap();
+    let mut store_batch = StoreBatch::new();
+    store_batch.add(&bucket, batch).unwrap();
+    store.batch(store_batch).unwrap();
+
+    assert_eq!(store.purge_expired().unwrap(), 0);
+    for k in keys.iter() {
+        assert_eq!(bucket.get(*k).unwrap().unwrap(), "new", "{}", k);
+    }
+    assert_eq!(bucket.scoped("s/").get("scoped").unwrap().unwrap(), "new");
+
+    // Purging deadlines left behind for a bucket that no longer exists doesn't create it again
+    let gone = store.bucket::<&str, String>(Some("gone")).unwrap();
+    gone.set_with_ttl("a", "1", std::time::Duration::from_millis(0))
+        .unwrap();
+    drop(gone);
+    store.db.drop_tree(b"gone").unwrap();
+    assert_eq!(store.purge_expired().unwrap(), 0);
+    assert!(!store.buckets().contains(&"gone".to_string()));
+}
+
+#[test]
+fn test_ttl_sweeper() {
+    let path = reset("ttl_sweeper");
+
+    let cfg = Config::new(path.clone()).purge_expired_every_ms(10);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    bucket
+        .set_with_ttl("a", "1", std::time::Duration::from_millis(0))
+        .unwrap();
+
+    for _ in 0..100 {
+        if bucket.is_empty() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(bucket.is_empty());
+
+    // The sweeper is stopped when the store is dropped, so it can be opened again
+    drop(bucket);
+    drop(store);
+    Store::new(Config::new(path)).unwrap();
+}
+
+#[test]
+fn test_composite_keys() {
+    let path = reset("composite_keys");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store
+        .bucket::<Composite<(i64, String)>, String>(None)
+        .unwrap();
+
+    let keys = vec![
+        (-300i64, "b".to_string()),
+        (-1, "".to_string()),
+        (0, "a".to_string()),
+        (0, "a\0".to_string()),
+        (0, "ab".to_string()),
+        (1, "a".to_string()),
+        (256, "a".to_string()),
+    ];
+
+    for k in keys.iter().rev() {
+        bucket.set(k.clone(), format!("{}{}", k.0, k.1)).unwrap();
+    }
+
+    let items: Vec<(i64, String)> = bucket
+        .iter()
+        .map(|item| {
+            let k: Composite<(i64, String)> = item.unwrap().key().unwrap();
+            k.into_inner()
+        })
+        .collect();
+    assert_eq!(items, keys);
+
+    let start = (0i64, String::new());
+    let end = (1i64, String::new());
+    assert_eq!(bucket.range(start..end).unwrap().count(), 3);
+    assert_eq!(
+        bucket.get((0i64, "ab".to_string())).unwrap().unwrap(),
+        "0ab"
+    );
+}
+
+#[test]
+fn test_signed_integer_keys() {
+    use std::convert::TryFrom;
+
+    let path = reset("signed_integer_keys");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<SignedInteger, String>(None).unwrap();
+
+    let keys = vec![i64::MIN, -1000, -1, 0, 1, 1000, i64::MAX];
+    for k in keys.iter().rev() {
+        bucket.set(*k, format!("{}", k)).unwrap();
+    }
+
+    let items: Vec<i64> = bucket
+        .iter()
+        .map(|item| i64::try_from(item.unwrap().key::<SignedInteger>().unwrap()).unwrap())
+        .collect();
+    assert_eq!(items, keys);
+    assert_eq!(bucket.get(-1000i64).unwrap().unwrap(), "-1000");
+    assert_eq!(bucket.get(-1000i32).unwrap().unwrap(), "-1000");
+
+    let bucket = store.bucket::<SignedInteger, String>(Some("i128")).unwrap();
+    let keys = vec![i128::MIN, -1, 0, i128::MAX];
+    for k in keys.iter().rev() {
+        bucket.set(*k, format!("{}", k)).unwrap();
+    }
+
+    let items: Vec<i128> = bucket
+        .iter()
+        .map(|item| item.unwrap().key().unwrap())
+        .collect();
+    assert_eq!(items, keys);
+    assert!(i32::try_from(SignedInteger::from(i64::MAX)).is_err());
+
+    // Integer keeps its unsigned encoding, so keys written using different integer types match
+    let bucket = store.bucket::<Integer, String>(Some("u128")).unwrap();
+    bucket.set(1, "one").unwrap();
+    assert_eq!(bucket.get(1u64).unwrap().unwrap(), "one");
+    assert_eq!(Integer::from(1).as_ref(), Integer::from(1u128).as_ref());
+    bucket.set(u128::MAX, "max").unwrap();
+    bucket.set(0u128, "min").unwrap();
+    let first: u128 = bucket.iter().next().unwrap().unwrap().key().unwrap();
+    assert_eq!(first, 0);
+
+    // Keys of the wrong length can't be decoded
+    assert!(Integer::from_raw_key(&Raw::from(&b"short"[..])).is_err());
+    assert!(SignedInteger::from_raw_key(&Raw::from(&[0u8; 17][..])).is_err());
+}
+
+#[test]
+fn test_transaction_multiple_buckets() {
+    let path = reset("transaction_multiple_buckets");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let a = store.bucket::<&str, String>(Some("a")).unwrap();
+    let b = store.bucket::<Integer, String>(Some("b")).unwrap();
+    let c = store.bucket::<&str, Raw>(Some("c")).unwrap();
+
+    a.set("x", "1").unwrap();
+
+    a.transaction2(&b, |a, b| {
+        let x = a.get("x")?.unwrap();
+        a.remove("x")?;
+        b.set(1, x)?;
+        Ok::<_, TransactionError<Error>>(())
+    })
+    .unwrap();
+    assert!(!a.contains("x").unwrap());
+    assert_eq!(b.get(1).unwrap().unwrap(), "1");
+
+    a.transaction3(&b, &c, |a, b, c| {
+        let x = b.get(1)?.unwrap();
+        a.set("y", x.as_str())?;
+        c.set("z", x.as_str())?;
+        Ok::<_, TransactionError<Error>>(())
+    })
+    .unwrap();
+    assert_eq!(a.get("y").unwrap().unwrap(), "1");
+    assert_eq!(c.get("z").unwrap().unwrap(), b"1");
+
+    // Aborting returns the error and discards all writes
+    let res = a.transaction3(&b, &c, |a, b, c| {
+        a.set("aborted", "1")?;
+        b.set(2, "1")?;
+        c.set("aborted", "1")?;
+        Err::<(), _>(abort(Error::Message("abort".into())))
+    });
+    match res {
+        Err(Error::Message(s)) => assert_eq!(s, "abort"),
+        _ => panic!("expected abort"),
+    }
+    assert!(!a.contains("aborted").unwrap());
+    assert!(!b.contains(2).unwrap());
+    assert!(!c.contains("aborted").unwrap());
+}
+
+#[test]
+fn test_backup() {
+    let path = reset("backup");
+    let restore_path = reset("backup_restore");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let a = store.bucket::<Integer, String>(Some("a")).unwrap();
+    let b = store.bucket::<&str, Raw>(None).unwrap();
+    for i in 0..100 {
+        a.set(i, format!("{}", i)).unwrap();
+    }
+    b.set("key", "value").unwrap();
+    b.set("empty", "").unwrap();
+
+    let mut buf = Vec::new();
+    store.export_to(&mut buf).unwrap();
+    assert_eq!(&buf[..8], b"KVBACKUP");
+
+    let restored = Store::new(Config::new(restore_path)).unwrap();
+    restored.import_from(buf.as_slice()).unwrap();
+
+    let a2 = restored.bucket::<Integer, String>(Some("a")).unwrap();
+    let b2 = restored.bucket::<&str, Raw>(None).unwrap();
+    assert_eq!(a2.len(), 100);
+    assert_eq!(a2.get(50).unwrap().unwrap(), "50");
+    assert_eq!(b2.get("key").unwrap().unwrap(), b"value");
+    assert_eq!(b2.get("empty").unwrap().unwrap(), b"");
+    assert_eq!(a.checksum().unwrap(), a2.checksum().unwrap());
+
+    assert!(restored.import_from(&buf[..buf.len() - 1]).is_err());
+    assert!(restored.import_from(&b"not a backup"[..]).is_err());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async() {
+    use crate::asynch;
+
+    let path = reset("async");
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let store = asynch::Store::new(Config::new(path)).await.unwrap();
+        let bucket = store.bucket::<Integer, String>(None).await.unwrap();
+
+        for i in 0..10 {
+            bucket.set(i, format!("{}", i)).await.unwrap();
+        }
+        assert_eq!(bucket.get(5).await.unwrap().unwrap(), "5");
+        assert!(bucket.contains(9).await.unwrap());
+
+        bucket.remove(9).await.unwrap();
+        assert!(!bucket.contains(9).await.unwrap());
+
+        let mut batch = Batch::new();
+        batch.set(10, &"10".to_string()).unwrap();
+        bucket.batch(batch).await.unwrap();
+        assert_eq!(bucket.len().await.unwrap(), 10);
+
+        let mut iter = bucket.iter();
+        let mut n = 0;
+        while let Some(item) = iter.next().await {
+            let item = item.unwrap();
+            let k: u64 = item.key::<Integer>().unwrap().into();
+            assert_eq!(item.value::<String>().unwrap(), format!("{}", k));
+            n += 1;
+        }
+        assert_eq!(n, 10);
+
+        let mut watch = bucket.watch_all().unwrap();
+        bucket.set(11, "11").await.unwrap();
+        let event = watch.next().await.unwrap().unwrap();
+        assert!(event.is_set());
+        assert_eq!(event.value().unwrap().unwrap(), "11");
+
+        let timeout = std::time::Duration::from_millis(50);
+        assert_eq!(bucket.wait_for(11, timeout).await.unwrap().unwrap(), "11");
+        assert!(bucket.wait_for(12, timeout).await.unwrap().is_none());
+
+        bucket.flush().await.unwrap();
+        store.flush().await.unwrap();
+    });
+}
+
+#[test]
+fn test_scoped() {
+    let path = reset("scoped");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+    let users = bucket.scoped("users/");
+    let sessions = bucket.scoped("sessions/");
+
+    users.set("a", "alice").unwrap();
+    users.set("b", "bob").unwrap();
+    sessions.set("a", "123").unwrap();
+
+    assert_eq!(users.get("a").unwrap().unwrap(), "alice");
+    assert_eq!(sessions.get("a").unwrap().unwrap(), "123");
+    assert_eq!(bucket.get("users/b").unwrap().unwrap(), "bob");
+    assert!(!users.contains("users/a").unwrap());
+
+    let keys: Vec<String> = users
+        .iter()
+        .map(|item| item.unwrap().key().unwrap())
+        .collect();
+    assert_eq!(keys, vec!["a", "b"]);
+
+    let mut batch = users.new_batch();
+    batch.set("c", &"carol".to_string()).unwrap();
+    batch.remove("a").unwrap();
+    users.batch(batch).unwrap();
+    assert!(users.get("a").unwrap().is_none());
+    assert_eq!(bucket.get("users/c").unwrap().unwrap(), "carol");
+    assert!(users.batch(Batch::new()).is_err());
+
+    // A scoped batch can't be applied to the bucket or another scope
+    let mut batch = users.new_batch();
+    batch.set("f", &"frank".to_string()).unwrap();
+    assert!(bucket.batch(batch).is_err());
+    let mut batch = users.new_batch();
+    batch.set("f", &"frank".to_string()).unwrap();
+    assert!(sessions.batch(batch).is_err());
+    let mut batch = users.new_batch();
+    batch.set("f", &"frank".to_string()).unwrap();
+    assert!(bucket
+        .transaction(|txn| -> Result<_, TransactionError<Error>> { txn.batch(batch.clone()) })
+        .is_err());
+    assert!(bucket.get("users/f").unwrap().is_none());
+
+    users
+        .transaction(|txn| {
+            let b = txn.get("b")?.unwrap();
+            txn.set("d", b)?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert_eq!(bucket.get("users/d").unwrap().unwrap(), "bob");
+
+    let admins = users.scoped("admins/");
+    admins.set("e", "eve").unwrap();
+    assert_eq!(admins.prefix(), b"users/admins/");
+    assert_eq!(users.get("admins/e").unwrap().unwrap(), "eve");
+
+    users.clear().unwrap();
+    assert!(users.is_empty());
+    assert_eq!(sessions.get("a").unwrap().unwrap(), "123");
+}
+
+#[test]
+fn test_index() {
+    let path = reset("index");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let users = store
+        .indexed::<&str, String>(Some("users"))
+        .unwrap()
+        .index("first", |v: &String| vec![v.as_bytes()[..1].into()])
+        .index("len", |v: &String| {
+            vec![(v.len() as u32).to_be_bytes().as_ref().into()]
+        });
+
+    users.set("1", "alice").unwrap();
+    users.set("2", "bob").unwrap();
+    users.set("3", "anna").unwrap();
+
+    let keys = |iter: IndexIter<&str, String>| -> Vec<String> {
+        iter.map(|item| item.unwrap().key().unwrap()).collect()
+    };
+
+    assert_eq!(
+        keys(users.get_by_index("first", "a").unwrap()),
+        vec!["1", "3"]
+    );
+    assert_eq!(keys(users.get_by_index("first", "b").unwrap()), vec!["2"]);
+    assert!(users.get_by_index("missing", "a").is_err());
+
+    users.set("1", "bea").unwrap();
+    assert_eq!(keys(users.get_by_index("first", "a").unwrap()), vec!["3"]);
+    assert_eq!(
+        keys(users.get_by_index("first", "b").unwrap()),
+        vec!["1", "2"]
+    );
+
+    let len = |n: u32| n.to_be_bytes().to_vec();
+    assert_eq!(
+        keys(users.index_range("len", len(3)..len(4)).unwrap()),
+        vec!["1", "2"]
+    );
+    assert_eq!(keys(users.index_range("len", len(4)..).unwrap()), vec!["3"]);
+    assert_eq!(
+        keys(users.index_range::<Vec<u8>, _>("len", ..).unwrap()).len(),
+        3
+    );
+
+    users.remove("2").unwrap();
+    assert_eq!(keys(users.get_by_index("first", "b").unwrap()), vec!["1"]);
+
+    users.bucket().set("4", "ben").unwrap();
+    assert_eq!(keys(users.get_by_index("first", "b").unwrap()), vec!["1"]);
+    users.reindex().unwrap();
+    assert_eq!(
+        keys(users.get_by_index("first", "b").unwrap()),
+        vec!["1", "4"]
+    );
+
+    assert!(!store.buckets().iter().any(|x| x.starts_with("__kv__")));
+    store.drop_bucket("users").unwrap();
+    let users = store
+        .indexed::<&str, String>(Some("users"))
+        .unwrap()
+        .index("first", |v: &String| vec![v.as_bytes()[..1].into()]);
+    assert!(users.get_by_index("first", "b").unwrap().next().is_none());
+
+    // Expired keys are skipped
+    users.set("1", "bea").unwrap();
+    users.set("2", "bob").unwrap();
+    users
+        .bucket()
+        .set_with_ttl("2", "bob", std::time::Duration::from_millis(1))
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    assert_eq!(keys(users.get_by_index("first", "b").unwrap()), vec!["1"]);
+
+    // Malformed entries are an error
+    let mut entry = 5u32.to_be_bytes().to_vec();
+    entry.extend_from_slice(b"users");
+    entry.extend_from_slice(&5u32.to_be_bytes());
+    entry.extend_from_slice(b"firstc");
+    let index = store.db.open_tree("__kv__index").unwrap();
+    index.insert(entry, &b""[..]).unwrap();
+    let mut iter = users.index_range::<Vec<u8>, _>("first", ..).unwrap();
+    assert!(iter.next().unwrap().is_ok());
+    assert!(iter.next().unwrap().is_err());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encryption() {
+    let path = reset("encryption");
+    let key = [7u8; 32];
+
+    let store = Store::new_encrypted(Config::new(path), &key).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("secret")).unwrap();
+    bucket.set("a", "hello").unwrap();
+    assert_eq!(bucket.get("a").unwrap().unwrap(), "hello");
+
+    let mut batch = Batch::new();
+    batch.set("b", &"world".to_string()).unwrap();
+    bucket.batch(batch).unwrap();
+
+    bucket
+        .transaction(|txn| {
+            let a = txn.get("a")?.unwrap();
+            txn.set("c", format!("{}!", a))?;
+            Ok::<_, TransactionError<Error>>(())
+        })
+        .unwrap();
+    assert_eq!(bucket.get("c").unwrap().unwrap(), "hello!");
+
+    let values: Vec<String> = bucket
+        .iter()
+        .map(|item| item.unwrap().value().unwrap())
+        .collect();
+    assert_eq!(values, vec!["hello", "world", "hello!"]);
+    assert!(bucket.merge("a", "x").is_err());
+
+    // Values are not readable without the key
+    let v = bucket.tree.get("a").unwrap().unwrap();
+    assert!(!v.windows(5).any(|x| x == b"hello"));
+
+    // Values can't be moved to another key
+    bucket.tree.insert("b", v).unwrap();
+    assert!(bucket.get("b").is_err());
+    assert_eq!(bucket.get("c").unwrap().unwrap(), "hello!");
+
+    let crypt = crypt::Crypt::new(&[8u8; 32]).bucket(b"secret");
+    let other = Bucket::<&str, String>::new(
+        bucket.tree.clone(),
+        bucket.ttl.clone(),
+        crypt,
+        bucket.observer.clone(),
+        None,
+        bucket.locks.clone(),
+    );
+    assert!(other.get("c").is_err());
+}
+
+#[cfg(all(feature = "zstd-value", feature = "lz4-value", feature = "json-value"))]
+#[test]
+fn test_compressed() {
+    let path = reset("compressed");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let blob: Vec<String> = (0..1000).map(|i| format!("item {}", i % 10)).collect();
+    let size = serde_json::to_vec(&blob).unwrap().len();
+
+    let zstd = store
+        .bucket::<&str, Compressed<Json<Vec<String>>, Zstd<19>>>(Some("zstd"))
+        .unwrap();
+    zstd.set("blob", Json(blob.clone())).unwrap();
+    let x = zstd.get("blob").unwrap().unwrap();
+    assert_eq!(x.as_ref().as_ref(), &blob);
+
+    let lz4 = store
+        .bucket::<&str, Compressed<Json<Vec<String>>, Lz4>>(Some("lz4"))
+        .unwrap();
+    lz4.set("blob", Json(blob.clone())).unwrap();
+    assert_eq!(lz4.get("blob").unwrap().unwrap().into_inner().0, blob);
+
+    let raw = store.bucket::<&str, Raw>(Some("zstd")).unwrap();
+    assert!(raw.get("blob").unwrap().unwrap().len() < size / 10);
+    let raw = store.bucket::<&str, Raw>(Some("lz4")).unwrap();
+    assert!(raw.get("blob").unwrap().unwrap().len() < size / 2);
+    raw.set("bad", "not lz4").unwrap();
+    assert!(lz4.get("bad").is_err());
+}
+
+#[test]
+fn test_migrations() {
+    let path = reset("migrations");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    assert_eq!(store.schema_version().unwrap(), 0);
+    let users = store.bucket::<&str, String>(Some("users")).unwrap();
+    users.set("a", "alice").unwrap();
+
+    let version = store
+        .migrations()
+        .add(2, |store| {
+            let users = store.bucket::<&str, String>(Some("users"))?;
+            for item in users.iter() {
+                let item = item?;
+                let key: String = item.key()?;
+                let value: String = item.value()?;
+                users.set(key.as_str(), value.to_uppercase())?;
+            }
+            Ok(())
+        })
+        .add(1, |store| {
+            store
+                .bucket::<&str, String>(Some("users"))?
+                .set("b", "bob")?;
+            Ok(())
+        })
+        .run()
+        .unwrap();
+    assert_eq!(version, 2);
+    assert_eq!(store.schema_version().unwrap(), 2);
+    assert_eq!(users.get("a").unwrap().unwrap(), "ALICE");
+    assert_eq!(users.get("b").unwrap().unwrap(), "BOB");
+    assert!(!store.buckets().iter().any(|x| x.starts_with("__kv__")));
+
+    // Migrations that have already run are skipped, failed migrations are retried
+    let res = store
+        .migrations()
+        .add(1, |_| panic!("already applied"))
+        .add(3, |store| {
+            store
+                .bucket::<&str, String>(Some("users"))?
+                .set("c", "carol")?;
+            Ok(())
+        })
+        .add(4, |_| Err(Error::Message("failed".into())))
+        .run();
+    assert!(res.is_err());
+    assert_eq!(store.schema_version().unwrap(), 3);
+
+    let version = store.migrations().add(4, |_| Ok(())).run().unwrap();
+    assert_eq!(version, 4);
+
+    assert!(store
+        .migrations()
+        .add(5, |_| Ok(()))
+        .add(5, |_| Ok(()))
+        .run()
+        .is_err());
+    assert_eq!(store.schema_version().unwrap(), 4);
+}
+
+#[cfg(feature = "json-value")]
+#[test]
+fn test_corrupt_values() {
+    let path = reset("corrupt_values");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Json<u32>>(None).unwrap();
+    bucket.set("a", Json(1)).unwrap();
+    bucket.set("c", Json(3)).unwrap();
+    let raw = store.bucket::<&str, Raw>(None).unwrap();
+    raw.set("b", "not json").unwrap();
+
+    assert!(bucket
+        .iter()
+        .any(|item| item.unwrap().value::<Json<u32>>().is_err()));
+
+    let values: Vec<u32> = bucket
+        .iter_lossy()
+        .map(|item| item.unwrap().value::<Json<u32>>().unwrap().0)
+        .collect();
+    assert_eq!(values, vec![1, 3]);
+
+    let item = bucket.iter().nth(1).unwrap().unwrap();
+    assert_eq!(item.raw_key(), b"b");
+    assert_eq!(item.raw_value(), b"not json");
+
+    assert_eq!(bucket.scan_corrupt().unwrap(), vec![Raw::from(b"b")]);
+    bucket.remove("b").unwrap();
+    assert!(bucket.scan_corrupt().unwrap().is_empty());
+}
+
+#[test]
+fn test_get_many() {
+    let path = reset("get_many");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+    for i in 0..10 {
+        bucket.set(i, format!("{}", i)).unwrap();
+    }
+
+    let values = bucket.get_many(vec![5, 20, 1]).unwrap();
+    assert_eq!(values, vec![Some("5".into()), None, Some("1".into())]);
+
+    let values = bucket
+        .transaction(|txn| -> Result<_, TransactionError<Error>> {
+            txn.set(20, "20")?;
+            txn.get_many(vec![20, 3])
+        })
+        .unwrap();
+    assert_eq!(values, vec![Some("20".into()), Some("3".into())]);
+}
+
+#[test]
+fn test_take_swap() {
+    let path = reset("take_swap");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    assert!(bucket.swap("a", "1").unwrap().is_none());
+    assert_eq!(bucket.swap("a", "2").unwrap().unwrap(), "1");
+    assert_eq!(bucket.take("a").unwrap().unwrap(), "2");
+    assert!(bucket.take("a").unwrap().is_none());
+    assert!(!bucket.contains("a").unwrap());
+
+    bucket
+        .set_with_ttl("b", "3", std::time::Duration::from_millis(0))
+        .unwrap();
+    assert!(bucket.take("b").unwrap().is_none());
+}
+
+#[test]
+fn test_queue() {
+    let path = reset("queue");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+
+    assert!(bucket.first().unwrap().is_none());
+    bucket.set(2, "b").unwrap();
+    bucket.set(1, "a").unwrap();
+    bucket.set(3, "c").unwrap();
+    assert_eq!(
+        bucket.first().unwrap().unwrap().value::<String>().unwrap(),
+        "a"
+    );
+    assert_eq!(
+        bucket.last().unwrap().unwrap().value::<String>().unwrap(),
+        "c"
+    );
+
+    let queue = bucket.queue();
+    assert_eq!(queue.len(), 3);
+    assert_eq!(
+        queue.peek().unwrap().unwrap().key::<Integer>().unwrap(),
+        Integer::from(1)
+    );
+    assert_eq!(
+        queue.pop().unwrap().unwrap().value::<String>().unwrap(),
+        "a"
+    );
+    assert_eq!(
+        queue
+            .pop_back()
+            .unwrap()
+            .unwrap()
+            .value::<String>()
+            .unwrap(),
+        "c"
+    );
+    queue.push(0, "z").unwrap();
+    assert_eq!(
+        queue.pop().unwrap().unwrap().value::<String>().unwrap(),
+        "z"
+    );
+    assert_eq!(
+        queue.pop().unwrap().unwrap().value::<String>().unwrap(),
+        "b"
+    );
+    assert!(queue.pop().unwrap().is_none());
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_push() {
+    let path = reset("push");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+
+    assert_eq!(bucket.push("a").unwrap(), Integer::from(0u64));
+    assert_eq!(bucket.push("b").unwrap(), Integer::from(1u64));
+    bucket.set(10u64, "c").unwrap();
+    assert_eq!(bucket.push("d").unwrap(), Integer::from(11u64));
+    assert_eq!(bucket.get(1u64).unwrap().unwrap(), "b");
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let bucket = bucket.clone();
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    bucket.push("x").unwrap();
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    assert_eq!(bucket.len(), 104);
+    assert_eq!(
+        bucket.last().unwrap().unwrap().key::<Integer>().unwrap(),
+        Integer::from(111u64)
+    );
+
+    // The last key can't be decoded or incremented
+    bucket.set(u128::MAX, "max").unwrap();
+    assert!(bucket.push("x").is_err());
+    let raw = store.bucket::<&str, String>(Some("push")).unwrap();
+    raw.set("short", "x").unwrap();
+    let bucket = store.bucket::<Integer, String>(Some("push")).unwrap();
+    assert!(bucket.push("x").is_err());
+}
+
+#[test]
+fn test_wait_for() {
+    let path = reset("wait_for");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    let timeout = std::time::Duration::from_millis(50);
+    assert!(bucket.wait_for("a", timeout).unwrap().is_none());
+
+    bucket.set("a", "1").unwrap();
+    assert_eq!(bucket.wait_for("a", timeout).unwrap().unwrap(), "1");
+
+    let producer = {
+        let bucket = bucket.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            bucket.set("ab", "ignored").unwrap();
+            bucket.set("b", "2").unwrap();
+        })
+    };
+    let timeout = std::time::Duration::from_secs(10);
+    assert_eq!(bucket.wait_for("b", timeout).unwrap().unwrap(), "2");
+    producer.join().unwrap();
+}
+
+#[test]
+fn test_transaction_retry() {
+    let path = reset("transaction_retry");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    let policy = RetryPolicy::new().max_attempts(3).backoff(
+        std::time::Duration::from_millis(1),
+        std::time::Duration::from_millis(2),
+    );
+
+    let res = bucket.transaction_with_retry(&policy, |txn: Transaction<&str, String>| {
+        txn.set("a", "1")?;
+        Ok(())
+    });
+    assert!(res.is_ok());
+    assert_eq!(bucket.get("a").unwrap().unwrap(), "1");
+
+    let res: Result<(), Error> =
+        bucket.transaction_with_retry(&policy, |_| Err(TransactionError::Conflict));
+    match res {
+        Err(Error::Conflict(3)) => (),
+        _ => panic!("expected conflict"),
+    }
+
+    let stats = policy.stats();
+    assert_eq!(stats.transactions, 2);
+    assert_eq!(stats.conflicts, 3);
+    assert_eq!(stats.exhausted, 1);
+}
+
+#[test]
+fn test_copy() {
+    let path = reset("copy");
+    let path2 = reset("copy2");
+
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    let other = Store::new(Config::new(path2.clone())).unwrap();
+    let bucket = store.bucket::<Integer, String>(Some("src")).unwrap();
+    for i in 0..3000 {
+        bucket.set(i, format!("{}", i)).unwrap();
+    }
+    bucket
+        .set_with_ttl(3000, "expired", std::time::Duration::from_millis(0))
+        .unwrap();
+
+    assert_eq!(
+        store
+            .copy_bucket_to(&other, Some("src"), Some("dst"))
+            .unwrap(),
+        3000
+    );
+    let dst = other.bucket::<Integer, String>(Some("dst")).unwrap();
+    assert_eq!(dst.len(), 3000);
+    assert_eq!(dst.get(1234).unwrap().unwrap(), "1234");
+    assert!(!dst.contains(3000).unwrap());
+
+    let copy = store.bucket::<Integer, String>(Some("copy")).unwrap();
+    assert_eq!(dst.copy_to(&copy).unwrap(), 3000);
+    assert_eq!(copy.get(2999).unwrap().unwrap(), "2999");
+
+    #[cfg(feature = "zstd-value")]
+    {
+        let compressed = other
+            .bucket::<Integer, Compressed<String, Zstd>>(Some("zstd"))
+            .unwrap();
+        assert_eq!(bucket.copy_to(&compressed).unwrap(), 3000);
+        assert_eq!(compressed.get(7).unwrap().unwrap().into_inner(), "7");
+    }
+}
+
+#[test]
+fn test_store_locked() {
+    let path = reset("store_locked");
+
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    match Store::try_new(Config::new(path.clone())) {
+        Err(Error::StoreLocked(p)) => assert_eq!(p, std::path::PathBuf::from(&path)),
+        _ => panic!("expected StoreLocked"),
+    }
+
+    let timeout = std::time::Duration::from_millis(200);
+    let start = std::time::Instant::now();
+    let cfg = Config::new(path.clone()).open_timeout(timeout);
+    assert!(matches!(Store::new(cfg), Err(Error::StoreLocked(_))));
+    assert!(start.elapsed() >= timeout);
+
+    // A replacement is only finished or undone by whoever holds the lock, so opening a store
+    // that's in use leaves a copy that may still be being made alone
+    let copy = format!("{}.compact", path);
+    let intent = format!("{}.replacing", path);
+    let _ = fs::remove_dir_all(&copy);
+    fs::create_dir(&copy).unwrap();
+    fs::write(&intent, format!("copy\n{}\n{}.old\nremove\n", copy, path)).unwrap();
+    assert!(matches!(
+        Store::try_new(Config::new(path.clone())),
+        Err(Error::StoreLocked(_))
+    ));
+    assert!(path::Path::new(&copy).exists());
+    drop(store);
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    assert!(!path::Path::new(&copy).exists());
+    assert!(!path::Path::new(&intent).exists());
+    drop(store);
+}
+
+#[test]
+fn test_verify_integrity() {
+    let path = reset("verify_integrity");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("strings")).unwrap();
+    bucket.set("a", "1").unwrap();
+    bucket.set("b", "2").unwrap();
+
+    let report = store.verify_integrity().unwrap();
+    assert!(report.is_ok());
+    let strings = report.buckets.iter().find(|x| x.name == "strings").unwrap();
+    assert_eq!(strings.entries, 2);
+    assert_eq!(strings.checksum, Some(bucket.checksum().unwrap()));
+
+    let raw = store.bucket::<&str, Raw>(Some("strings")).unwrap();
+    raw.set("c", Raw::from(&[0xff, 0xfe][..])).unwrap();
+    assert!(store.verify_integrity().unwrap().is_ok());
+
+    let report = store
+        .verify()
+        .decode::<String>(Some("strings"))
+        .run()
+        .unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].bucket, "strings");
+    assert_eq!(report.corrupt[0].key, Some(Raw::from(b"c")));
+
+    // Saved checksums are compared on the next run
+    let report = store.verify().save().unwrap();
+    assert!(report.is_ok());
+    let strings = report.buckets.iter().find(|x| x.name == "strings").unwrap();
+    assert_eq!(strings.expected, None);
+    let report = store.verify_integrity().unwrap();
+    assert!(report.is_ok());
+    let strings = report.buckets.iter().find(|x| x.name == "strings").unwrap();
+    assert_eq!(strings.expected, strings.checksum);
+
+    // Change the tree without going through the bucket
+    let tree = store.db.open_tree("strings").unwrap();
+    tree.insert("a", "3").unwrap();
+    let report = store.verify_integrity().unwrap();
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].bucket, "strings");
+    assert_eq!(report.corrupt[0].key, None);
+
+    store.verify().save().unwrap();
+    assert!(store.verify_integrity().unwrap().is_ok());
+}
+
+#[test]
+fn test_flush() {
+    let path = reset("flush");
+
+    let cfg = Config::new(path.clone()).flush_every_ms(60_000);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+
+    bucket.set_durable("a", "1").unwrap();
+    assert_eq!(bucket.get("a").unwrap().unwrap(), "1");
+    assert_eq!(store.flush().unwrap(), 0);
+
+    bucket.set("b", "2").unwrap();
+    assert!(store.flush().unwrap() > 0);
+}
+
+#[test]
+fn test_error_context() {
+    use std::error::Error as _;
+
+    let path = reset("error_context");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let raw = store.bucket::<&str, Raw>(Some("strings")).unwrap();
+    raw.set("a", Raw::from(&[0xff][..])).unwrap();
+
+    let bucket = store.bucket::<&str, String>(Some("strings")).unwrap();
+    let err = bucket.get("a").unwrap_err();
+    match &err {
+        Error::Decode {
+            bucket,
+            key,
+            codec,
+            source,
+        } => {
+            assert_eq!(bucket, "strings");
+            assert_eq!(key, b"a");
+            assert!(codec.contains("String"));
+            assert!(matches!(**source, Error::FromUtf8(_)));
+        }
+        _ => panic!("expected decode error"),
+    }
+    assert!(err.source().unwrap().source().is_some());
+    assert!(err.to_string().contains("strings"));
+    assert!(!err.to_string().contains(&err.source().unwrap().to_string()));
+
+    // Values read through iterators are wrapped the same way
+    let is_decode = |e: Error| matches!(e, Error::Decode { ref bucket, .. } if bucket == "strings");
+    let item = bucket.iter().next().unwrap().unwrap();
+    assert!(is_decode(item.value::<String>().unwrap_err()));
+    assert!(is_decode(bucket.values().next().unwrap().unwrap_err()));
+    assert_eq!(bucket.iter_lossy().count(), 0);
+
+    // And inside transactions
+    let err = bucket
+        .transaction(|t: Transaction<_, _>| t.get("a"))
+        .unwrap_err();
+    assert!(is_decode(err));
+
+    let err = Config::load_from(&b"path = 1"[..]).unwrap_err();
+    assert!(matches!(err, Error::ConfigDecode(_)));
+    assert!(err.source().is_some());
+}
+
+#[cfg(all(feature = "derive", feature = "json-value"))]
+#[test]
+fn test_derive() {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize, crate::Value)]
+    #[value(codec = "json")]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[derive(Debug, Clone, PartialEq, crate::KeyPart)]
+    struct UserId {
+        group: u16,
+        name: String,
+    }
+
+    let path = reset("derive");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Composite<UserId>, User>(None).unwrap();
+
+    let id = |group, name: &str| UserId {
+        group,
+        name: name.into(),
+    };
+    let user = |name: &str| User {
+        name: name.into(),
+        age: 30,
+    };
+    bucket.set(id(2, "a"), user("a")).unwrap();
+    bucket.set(id(1, "b"), user("b")).unwrap();
+    bucket.set(id(1, "a"), user("c")).unwrap();
+
+    assert_eq!(bucket.get(id(1, "b")).unwrap().unwrap(), user("b"));
+    let keys: Vec<UserId> = bucket
+        .iter()
+        .map(|item| {
+            item.unwrap()
+                .key::<Composite<UserId>>()
+                .unwrap()
+                .into_inner()
+        })
+        .collect();
+    assert_eq!(keys, vec![id(1, "a"), id(1, "b"), id(2, "a")]);
+
+    let raw = store.bucket::<Composite<UserId>, Raw>(None).unwrap();
+    let v = raw.get(id(2, "a")).unwrap().unwrap();
+    assert_eq!(v.as_ref(), br#"{"name":"a","age":30}"#);
+}
+
+#[cfg(feature = "json-value")]
+#[test]
+fn test_get_ref() {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Borrowed<'a> {
+        name: &'a str,
+    }
+
+    let path = reset("get_ref");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Json<serde_json::Value>>(None).unwrap();
+    bucket
+        .set("a", Json(serde_json::json!({"name": "alice"})))
+        .unwrap();
+
+    let v = bucket.get_ref("a").unwrap().unwrap();
+    let x: Borrowed = v.json().unwrap();
+    assert_eq!(x.name, "alice");
+    let p = x.name.as_ptr() as usize;
+    assert!(p >= v.as_ptr() as usize && p < v.as_ptr() as usize + v.len());
+    assert!(bucket.get_ref("b").unwrap().is_none());
+}
+
+#[test]
+fn test_cache_bucket() {
+    let path = reset("cache_bucket");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let cache = store
+        .cache_bucket::<&str, String>(Some("cache"), CacheLimit::Entries(2))
+        .unwrap();
+
+    cache.set("a", "1").unwrap();
+    cache.set("b", "2").unwrap();
+    assert_eq!(cache.get("a").unwrap().unwrap(), "1");
+    cache.set("c", "3").unwrap();
+    assert_eq!(cache.len().unwrap(), 2);
+    assert!(cache.contains("a").unwrap());
+    assert!(!cache.contains("b").unwrap());
+    assert!(cache.contains("c").unwrap());
+
+    cache.set("a", "10").unwrap();
+    cache.remove("c").unwrap();
+    assert_eq!(cache.len().unwrap(), 1);
+    assert_eq!(cache.size_in_bytes().unwrap(), 3);
+
+    let bytes = store
+        .cache_bucket::<&str, String>(Some("bytes"), CacheLimit::Bytes(10))
+        .unwrap();
+    bytes.set("a", "1234").unwrap();
+    bytes.set("b", "1234").unwrap();
+    bytes.set("c", "1234").unwrap();
+    assert!(!bytes.contains("a").unwrap());
+    assert_eq!(bytes.size_in_bytes().unwrap(), 10);
+
+    bytes.clear().unwrap();
+    assert!(bytes.is_empty().unwrap());
+    assert!(store.drop_bucket("cache").unwrap());
+    let cache = store
+        .cache_bucket::<&str, String>(Some("cache"), CacheLimit::Entries(2))
+        .unwrap();
+    assert_eq!(cache.len().unwrap(), 0);
+}
+
+#[test]
+fn test_cache_bucket_changefeed() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Counter(Arc<Mutex<Vec<String>>>);
+
+    impl StoreObserver for Counter {
+        fn operation(&self, bucket: &str, op: Operation, _elapsed: Duration) {
+            let mut x = self.0.lock().unwrap();
+            x.push(format!("{} {}", bucket, op.as_str()));
+        }
+    }
+
+    let path = reset("cache_bucket_changefeed");
+
+    let cfg = Config::new(path.clone()).changefeed(true);
+    let store = Store::new(cfg).unwrap();
+    let counter = Counter::default();
+    store.set_observer(counter.clone());
+    let cache = store
+        .cache_bucket::<&str, String>(Some("cache"), CacheLimit::Entries(1))
+        .unwrap();
+
+    // Evicting "a" is recorded as a removal
+    cache.set("a", "1").unwrap();
+    cache.set("b", "2").unwrap();
+    cache.remove("b").unwrap();
+    let changes: Vec<Change> = store
+        .changes("cache", 0)
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+    let keys: Vec<(&[u8], bool)> = changes
+        .iter()
+        .map(|x| (x.key.as_ref(), x.new.is_some()))
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            (&b"a"[..], true),
+            (&b"b"[..], true),
+            (&b"a"[..], false),
+            (&b"b"[..], false)
+        ]
+    );
+
+    assert_eq!(
+        *counter.0.lock().unwrap(),
+        vec!["cache set", "cache set", "cache remove", "cache remove"]
+    );
+}
+
+#[test]
+fn test_observer() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Counter(Arc<Mutex<Vec<String>>>);
+
+    impl StoreObserver for Counter {
+        fn operation(&self, bucket: &str, op: Operation, _elapsed: Duration) {
+            let mut x = self.0.lock().unwrap();
+            x.push(format!("{} {}", bucket, op.as_str()));
+        }
+
+        fn batch(&self, bucket: &str, size: usize) {
+            let mut x = self.0.lock().unwrap();
+            x.push(format!("{} batch {}", bucket, size));
+        }
+
+        fn flush(&self, _elapsed: Duration, _bytes: usize) {
+            self.0.lock().unwrap().push("flush".into());
+        }
+    }
+
+    let path = reset("observer");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+
+    // Buckets opened before the observer is set report to it too
+    let bucket = store.bucket::<&str, String>(Some("test")).unwrap();
+    let counter = Counter::default();
+    store.set_observer(counter.clone());
+
+    bucket.set("a", "1").unwrap();
+    bucket.get("a").unwrap();
+    bucket.contains("a").unwrap();
+    bucket.remove("a").unwrap();
+
+    let mut batch = Batch::new();
+    batch.set("b", &String::from("2")).unwrap();
+    batch.set("c", &String::from("3")).unwrap();
+    bucket.batch(batch).unwrap();
+
+    bucket
+        .transaction(|txn| -> Result<(), TransactionError<Error>> {
+            txn.set("d", "4")?;
+            Ok(())
+        })
+        .unwrap();
+    store.flush().unwrap();
+
+    assert_eq!(
+        *counter.0.lock().unwrap(),
+        vec![
+            "test set",
+            "test get",
+            "test contains",
+            "test remove",
+            "test batch 2",
+            "test batch",
+            "test transaction",
+            "flush",
+        ]
+    );
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_slow_operations() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::span;
+
+    // Counts warnings and records the name of each span and whether it has a bucket field
+    #[derive(Default)]
+    struct Slow(
+        Arc<AtomicUsize>,
+        Arc<std::sync::Mutex<Vec<(&'static str, bool)>>>,
+    );
+
+    impl tracing::Subscriber for Slow {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes) -> span::Id {
+            let meta = attrs.metadata();
+            let bucket = meta.fields().field("bucket").is_some();
+            self.1.lock().unwrap().push((meta.name(), bucket));
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &tracing::Event) {
+            if *event.metadata().level() == tracing::Level::WARN {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let path = reset("tracing_slow_operations");
+
+    let slow = Slow::default();
+    let count = slow.0.clone();
+    tracing::subscriber::with_default(slow, || {
+        let cfg = Config::new(path.clone()).slow_operation_threshold(Duration::from_millis(0));
+        let store = Store::new(cfg).unwrap();
+        let bucket = store.bucket::<&str, String>(None).unwrap();
+        bucket.set("a", "1").unwrap();
+        bucket.get("a").unwrap();
+    });
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // Every bucket operation and transaction is run inside a span with the bucket name
+    let slow = Slow::default();
+    let spans = slow.1.clone();
+    tracing::subscriber::with_default(slow, || {
+        let store = Store::new(Config::new(path.clone())).unwrap();
+        let bucket = store.bucket::<&str, String>(Some("spans")).unwrap();
+        bucket.swap("a", "1").unwrap();
+        bucket.take("a").unwrap();
+        bucket
+            .compare_and_swap("a", None, Some("2".into()))
+            .unwrap()
+            .unwrap();
+        bucket.fetch_and_update("a", |_| None).unwrap();
+        bucket.iter().count();
+        bucket
+            .transaction(|txn| -> Result<(), TransactionError<Error>> {
+                txn.set("b", "3")?;
+                txn.get("b")?;
+                txn.remove("b")?;
+                Ok(())
+            })
+            .unwrap();
+        let mut batch = StoreBatch::new();
+        batch.add(&bucket, Batch::new()).unwrap();
+        store.batch(batch).unwrap();
+    });
+    let spans = spans.lock().unwrap();
+    assert_eq!(spans.iter().filter(|x| x.0 == "kv").count(), 5);
+    for name in &["bucket", "iter", "set", "get", "remove", "batch"] {
+        assert!(spans.iter().any(|x| x.0 == *name), "missing span {}", name);
+    }
+    assert!(spans.iter().all(|x| x.1));
+
+    let cfg = Config::new(path.clone()).slow_operation_threshold(Duration::from_secs(60));
+    assert_eq!(cfg.slow_operation_threshold_ms, Some(60_000));
+}
+
+#[test]
+fn test_batch_conditions() {
+    let path = reset("batch_conditions");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+    bucket.set("a", "1").unwrap();
+
+    let mut batch = Batch::new();
+    batch.set("b", &String::from("2")).unwrap();
+    batch.remove("a").unwrap();
+    assert_eq!(batch.get("b").unwrap(), Some(Some(String::from("2"))));
+    assert_eq!(batch.get("a").unwrap(), Some(None));
+    assert_eq!(batch.get("c").unwrap(), None);
+    assert!(bucket.get_batched(&batch, "a").unwrap().is_none());
+    assert_eq!(bucket.get_batched(&batch, "b").unwrap().unwrap(), "2");
+    assert!(bucket.get("b").unwrap().is_none());
+
+    let mut batch = Batch::new();
+    batch.set("c", &String::from("3")).unwrap();
+    batch.set_if_absent("a", "x").unwrap();
+    match bucket.batch(batch) {
+        Err(Error::ConditionFailed(k)) => assert_eq!(k, "a"),
+        _ => panic!("expected condition failure"),
+    }
+    assert!(!bucket.contains("c").unwrap());
+    assert_eq!(bucket.get("a").unwrap().unwrap(), "1");
+
+    let mut batch = Batch::new();
+    batch.set_if_absent("c", "3").unwrap();
+    batch.remove_if_eq("a", "1").unwrap();
+    bucket.batch(batch).unwrap();
+    assert_eq!(bucket.get("c").unwrap().unwrap(), "3");
+    assert!(!bucket.contains("a").unwrap());
+
+    let mut batch = Batch::new();
+    batch.remove_if_eq("c", "4").unwrap();
+    assert!(bucket.batch(batch).is_err());
+    assert!(bucket.contains("c").unwrap());
+
+    // An expired key has no value
+    let ttl = std::time::Duration::from_millis(10);
+    bucket.set_with_ttl("d", "5", ttl).unwrap();
+    bucket.set_with_ttl("e", "6", ttl).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let mut batch = Batch::new();
+    batch.set_if_absent("d", "7").unwrap();
+    bucket.batch(batch).unwrap();
+    assert_eq!(bucket.get("d").unwrap().unwrap(), "7");
+    let mut batch = Batch::new();
+    batch.set_if_absent("e", "8").unwrap();
+    let mut store_batch = StoreBatch::new();
+    store_batch.add(&bucket, batch).unwrap();
+    store.batch(store_batch).unwrap();
+    assert_eq!(bucket.get("e").unwrap().unwrap(), "8");
+}
+
+#[test]
+fn test_store_batch() {
+    let path = reset("store_batch");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let data = store.bucket::<&str, String>(Some("data")).unwrap();
+    let index = store.bucket::<&str, String>(Some("index")).unwrap();
+    let other = store.bucket::<Integer, String>(Some("other")).unwrap();
+
+    let mut a = Batch::new();
+    a.set("x", &String::from("1")).unwrap();
+    let mut b = Batch::new();
+    b.set("1", &String::from("x")).unwrap();
+    let mut c = Batch::new();
+    c.set(1, &String::from("one")).unwrap();
+
+    let mut batch = StoreBatch::new();
+    batch.add(&data, a).unwrap();
+    batch.add(&index, b).unwrap();
+    batch.add(&other, c).unwrap();
+    assert_eq!(batch.len(), 3);
+    store.batch(batch).unwrap();
+    assert_eq!(data.get("x").unwrap().unwrap(), "1");
+    assert_eq!(index.get("1").unwrap().unwrap(), "x");
+    assert_eq!(other.get(1).unwrap().unwrap(), "one");
+
+    // A failed condition in one bucket prevents writes to all of them
+    let mut a = Batch::new();
+    a.set("y", &String::from("2")).unwrap();
+    let mut b = Batch::new();
+    b.set_if_absent("1", "y").unwrap();
+    let mut batch = StoreBatch::new();
+    batch.add(&data, a).unwrap();
+    batch.add(&index, b).unwrap();
+    match store.batch(batch) {
+        Err(Error::ConditionFailed(k)) => assert_eq!(k, "1"),
+        _ => panic!("expected condition failure"),
+    }
+    assert!(!data.contains("y").unwrap());
+    assert_eq!(index.get("1").unwrap().unwrap(), "x");
+
+    // One sled transaction holds every bucket and the expiration tree
+    let mut batch = StoreBatch::new();
+    for i in 0..69 {
+        let bucket = store
+            .bucket::<&str, String>(Some(&format!("many{}", i)))
+            .unwrap();
+        let mut b = Batch::new();
+        b.set("k", &i.to_string()).unwrap();
+        let res = batch.add(&bucket, b);
+        assert_eq!(res.is_ok(), i < 68);
+    }
+    store.batch(batch).unwrap();
+    let last = store.bucket::<&str, String>(Some("many67")).unwrap();
+    assert_eq!(last.get("k").unwrap().unwrap(), "67");
+
+    // Buckets from another store are rejected
+    let elsewhere = Store::new(Config::new(reset("store_batch-other"))).unwrap();
+    let foreign = elsewhere.bucket::<&str, String>(Some("data")).unwrap();
+    let mut batch = StoreBatch::new();
+    batch.add(&foreign, Batch::new()).unwrap();
+    assert!(store.batch(batch).is_err());
+    let mut batch = StoreBatch::new();
+    batch.add(&data, Batch::new()).unwrap();
+    assert!(batch.add(&foreign, Batch::new()).is_err());
+}
+
+#[test]
+fn test_page() {
+    let path = reset("page");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+    for i in 0..5 {
+        bucket.set(i, format!("{}", i)).unwrap();
+    }
+
+    let mut cursor: Option<Cursor> = None;
+    let mut pages = Vec::new();
+    loop {
+        let page = bucket.page(cursor.as_ref(), 2).unwrap();
+        let keys: Vec<String> = page.items.iter().map(|x| x.value().unwrap()).collect();
+        pages.push(keys);
+
+        // Cursors survive a round trip through a string
+        cursor = match page.next {
+            Some(c) => Some(c.to_string().parse().unwrap()),
+            None => break,
+        };
+    }
+    assert_eq!(pages, vec![vec!["0", "1"], vec!["2", "3"], vec!["4"]]);
+
+    let page = bucket.page(None, 5).unwrap();
+    assert_eq!(page.items.len(), 5);
+    assert!(page.next.is_none());
+    assert!("0g".parse::<Cursor>().is_err());
+    assert!(bucket.page(None, 0).is_err());
+}
+
+#[test]
+fn test_keys_values() {
+    let path = reset("keys_values");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+    bucket.set("a", "1").unwrap();
+    bucket.set("b", "2").unwrap();
+    bucket.set("c", "3").unwrap();
+
+    let keys: Vec<Raw> = bucket.keys().map(|x| x.unwrap()).collect();
+    assert_eq!(keys, vec!["a", "b", "c"]);
+    assert_eq!(<&str>::from_raw_key(&keys[1]).unwrap(), "b");
+
+    let values: Result<Vec<String>, _> = bucket.values().rev().collect();
+    assert_eq!(values.unwrap(), vec!["3", "2", "1"]);
+
+    // Keys are still listed when values can't be decoded
+    let raw = store.bucket::<&str, Raw>(None).unwrap();
+    raw.set("d", Raw::from(&[0xff][..])).unwrap();
+    assert_eq!(bucket.keys().count(), 4);
+    assert!(bucket.values().last().unwrap().is_err());
+
+    // Expired keys are skipped in both directions
+    raw.remove("d").unwrap();
+    let ttl = std::time::Duration::from_millis(10);
+    bucket.set_with_ttl("0", "0", ttl).unwrap();
+    bucket.set_with_ttl("e", "5", ttl).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let keys: Vec<Raw> = bucket.keys().map(|x| x.unwrap()).collect();
+    assert_eq!(keys, vec!["a", "b", "c"]);
+    assert_eq!(bucket.keys().next_back().unwrap().unwrap(), "c");
+    let values: Result<Vec<String>, _> = bucket.values().collect();
+    assert_eq!(values.unwrap(), vec!["1", "2", "3"]);
+    assert_eq!(bucket.values().next_back().unwrap().unwrap(), "3");
+}
+
+#[test]
+fn test_bulk_insert() {
+    let path = reset("bulk_insert");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+
+    let items = (0..2500u64).rev().map(|i| (i, format!("{}", i)));
+    assert_eq!(bucket.bulk_insert(items).unwrap(), 2500);
+    assert_eq!(bucket.len(), 2500);
+    assert_eq!(bucket.get(1234u64).unwrap().unwrap(), "1234");
+
+    // Later items replace earlier ones within a sorted batch
+    let opts = BulkInsert::new().batch_size(100).flush(false);
+    let items = vec![(1u64, "a"), (0, "b"), (1, "c")];
+    assert_eq!(bucket.bulk_insert_with(&opts, items).unwrap(), 3);
+    assert_eq!(bucket.get(1u64).unwrap().unwrap(), "c");
+    assert_eq!(bucket.get(0u64).unwrap().unwrap(), "b");
+}
+
+#[test]
+fn test_checked() {
+    let path = reset("checked");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Checked<String>>(None).unwrap();
+    bucket.set("a", String::from("hello")).unwrap();
+    assert_eq!(bucket.get("a").unwrap().unwrap().into_inner(), "hello");
+
+    // Flip a bit in the stored value
+    let mut v = bucket.tree.get("a").unwrap().unwrap().to_vec();
+    let n = v.len() - 1;
+    v[n] ^= 1;
+    bucket.tree.insert("a", v).unwrap();
+
+    match bucket.get("a") {
+        Err(Error::ChecksumMismatch { key }) => assert_eq!(key, "a"),
+        _ => panic!("expected checksum mismatch"),
+    }
+    let res = bucket.transaction(|txn| -> Result<_, TransactionError<Error>> {
+        Ok(txn.get("a")?.map(Checked::into_inner))
+    });
+    assert!(matches!(res, Err(Error::ChecksumMismatch { .. })));
+
+    // The key is included when the value is read through an iterator
+    let is_mismatch = |x: Result<Checked<String>, Error>| matches!(x, Err(Error::ChecksumMismatch { key }) if key == "a");
+    let item = bucket.iter().next().unwrap().unwrap();
+    assert!(is_mismatch(item.value()));
+    assert!(is_mismatch(bucket.values().next().unwrap()));
+    let item = bucket.first().unwrap().unwrap();
+    assert!(is_mismatch(item.value()));
+}
+
+#[test]
+fn test_changefeed() {
+    let path = reset("changefeed");
+
+    let cfg = Config::new(path.clone()).changefeed(true);
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+    assert!(!store.buckets().contains(&"__kv__changes/a".to_string()));
+
+    bucket.set("x", String::from("1")).unwrap();
+    bucket.set("x", String::from("2")).unwrap();
+    bucket.remove("x").unwrap();
+
+    let mut batch = Batch::new();
+    batch.set("y", &String::from("3")).unwrap();
+    batch.set("z", &String::from("4")).unwrap();
+    bucket.batch(batch).unwrap();
+
+    bucket
+        .transaction(|txn| -> Result<_, TransactionError<Error>> {
+            txn.remove("y")?;
+            Ok(())
+        })
+        .unwrap();
+
+    let changes: Vec<Change> = store.changes("a", 0).unwrap().map(|x| x.unwrap()).collect();
+    let seqs: Vec<u64> = changes.iter().map(|c| c.seq).collect();
+    assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6]);
+    assert!(changes.iter().all(|c| c.bucket == "a"));
+
+    let keys: Vec<&[u8]> = changes.iter().map(|c| c.key.as_ref()).collect();
+    assert_eq!(keys, vec![&b"x"[..], b"x", b"x", b"y", b"z", b"y"]);
+    assert_eq!(changes[0].old, None);
+    assert_eq!(changes[1].old, changes[0].new);
+    assert_eq!(changes[2].new, None);
+    assert_eq!(changes[5].old, changes[3].new);
+
+    // An aborted transaction records nothing
+    let res = bucket.transaction(|txn| -> Result<(), TransactionError<Error>> {
+        txn.set("w", String::from("5"))?;
+        Err(TransactionError::Abort(Error::Message("abort".into())))
+    });
+    assert!(res.is_err());
+    assert_eq!(store.changes("a", 7).unwrap().count(), 0);
+
+    // Tail from a sequence number
+    let tail: Vec<u64> = store
+        .changes("a", 5)
+        .unwrap()
+        .map(|x| x.unwrap().seq)
+        .collect();
+    assert_eq!(tail, vec![5, 6]);
+
+    assert_eq!(store.truncate_changes("a", 4).unwrap(), 3);
+    let first = store.changes("a", 0).unwrap().next().unwrap().unwrap();
+    assert_eq!(first.seq, 4);
+
+    // Sequence numbers continue after truncation
+    bucket.set("x", String::from("6")).unwrap();
+    let last = store.changes("a", 0).unwrap().last().unwrap().unwrap();
+    assert_eq!(last.seq, 7);
+
+    // Every other kind of write is recorded too, with sequence numbers kept per bucket
+    let b = store.bucket::<&str, String>(Some("b")).unwrap();
+    let log = |name: &str, seq: u64| -> Vec<(u64, String)> {
+        store
+            .changes(name, seq)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .map(|c| (c.seq, String::from_utf8(c.key.to_vec()).unwrap()))
+            .collect()
+    };
+    b.compare_and_swap("cas", None, Some(String::from("1")))
+        .unwrap()
+        .unwrap();
+    assert!(b
+        .compare_and_swap("cas", None, Some(String::from("2")))
+        .unwrap()
+        .is_err());
+    b.fetch_and_update("update", |_| Some(String::from("1")))
+        .unwrap();
+    bucket
+        .transaction2(&b, |x, y| -> Result<_, TransactionError<Error>> {
+            x.set("t", String::from("1"))?;
+            y.set("t", String::from("1"))?;
+            Ok(())
+        })
+        .unwrap();
+    let mut batch = Batch::new();
+    batch.set("store", &String::from("1")).unwrap();
+    let mut store_batch = StoreBatch::new();
+    store_batch.add(&b, batch).unwrap();
+    store.batch(store_batch).unwrap();
+    assert_eq!(
+        log("b", 0),
+        vec![
+            (1, "cas".to_string()),
+            (2, "update".to_string()),
+            (3, "t".to_string()),
+            (4, "store".to_string())
+        ]
+    );
+    assert_eq!(log("a", 8), vec![(8, "t".to_string())]);
+
+    // Merge can't be recorded, so it isn't allowed
+    assert!(b.merge("cas", String::from("2")).is_err());
+
+    b.set_with_ttl(
+        "ttl",
+        String::from("1"),
+        std::time::Duration::from_millis(0),
+    )
+    .unwrap();
+    assert_eq!(store.purge_expired().unwrap(), 1);
+    assert_eq!(log("b", 6), vec![(6, "ttl".to_string())]);
+
+    let users = store
+        .indexed::<&str, String>(Some("users"))
+        .unwrap()
+        .index("first", |v: &String| vec![v.as_bytes()[..1].into()]);
+    users.set("1", "alice").unwrap();
+    users.remove("1").unwrap();
+    assert_eq!(log("users", 0).len(), 2);
+
+    // Clearing and dropping a bucket record a removal for each key
+    b.clear().unwrap();
+    assert_eq!(log("b", 7).len(), 4);
+    assert!(store.drop_bucket("a").unwrap());
+    assert_eq!(log("a", 9).len(), 3);
+
+    let store = Store::new(Config::new(reset("changefeed-disabled"))).unwrap();
+    assert!(store.changes("a", 0).is_err());
+}
+
+#[test]
+fn test_replication() {
+    use crate::replication::Replicator;
+
+    let primary = Store::new(Config::new(reset("replication-primary")).changefeed(true)).unwrap();
+    let follower = Store::new(Config::new(reset("replication-follower"))).unwrap();
+    let a = primary.bucket::<&str, String>(Some("a")).unwrap();
+    a.set("x", String::from("1")).unwrap();
+    a.set("y", String::from("2")).unwrap();
+
+    // Initial snapshot
+    let mut r = Replicator::new(&primary).unwrap();
+    assert_eq!(r.sync(&follower).unwrap(), 2);
+    let b = follower.bucket::<&str, String>(Some("a")).unwrap();
+    assert_eq!(b.get("x").unwrap().unwrap(), "1");
+    let seqs = Replicator::follower_seqs(&follower).unwrap();
+    assert_eq!(seqs.get("a"), Some(&3));
+
+    a.set("x", String::from("3")).unwrap();
+    a.remove("y").unwrap();
+    assert_eq!(r.sync(&follower).unwrap(), 2);
+    assert_eq!(b.get("x").unwrap().unwrap(), "3");
+    assert!(b.get("y").unwrap().is_none());
+    assert_eq!(r.sync(&follower).unwrap(), 0);
+
+    // Over a transport, resuming from the follower's sequence number
+    let remote = Store::new(Config::new(reset("replication-remote"))).unwrap();
+    let mut buf = Vec::new();
+    Replicator::new(&primary).unwrap().send(&mut buf).unwrap();
+    assert_eq!(Replicator::receive(&remote, buf.as_slice()).unwrap(), 1);
+
+    a.set("z", String::from("4")).unwrap();
+    let seqs = Replicator::follower_seqs(&remote).unwrap();
+    let mut r = Replicator::resume(&primary, seqs).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(r.send(&mut buf).unwrap(), 1);
+    assert_eq!(Replicator::receive(&remote, buf.as_slice()).unwrap(), 1);
+    let c = remote.bucket::<&str, String>(Some("a")).unwrap();
+    assert_eq!(c.get("x").unwrap().unwrap(), "3");
+    assert_eq!(c.get("z").unwrap().unwrap(), "4");
+
+    // Truncated changes are replaced by a snapshot
+    a.set("w", String::from("5")).unwrap();
+    let seq = r.seqs()["a"];
+    primary.truncate_changes("a", seq + 1).unwrap();
+    let mut r = Replicator::resume(&primary, r.seqs().clone()).unwrap();
+    assert_eq!(r.sync(&follower).unwrap(), 3);
+    assert_eq!(b.get("w").unwrap().unwrap(), "5");
+
+    // Deadlines are replicated, so an expired key isn't readable on the follower either
+    a.set_with_ttl("e", String::from("6"), std::time::Duration::from_millis(0))
+        .unwrap();
+    a.set_with_ttl("f", String::from("7"), std::time::Duration::from_secs(3600))
+        .unwrap();
+    assert_eq!(r.sync(&follower).unwrap(), 2);
+    assert!(a.get("e").unwrap().is_none());
+    assert!(b.get("e").unwrap().is_none());
+    assert_eq!(b.get("f").unwrap().unwrap(), "7");
+    assert_eq!(r.sync(&follower).unwrap(), 1);
+    assert_eq!(b.iter().count(), a.iter().count());
+
+    // Dropped buckets and buckets that only exist on the follower are removed
+    follower.bucket::<&str, String>(Some("stale")).unwrap();
+    primary.bucket::<&str, String>(Some("d")).unwrap();
+    r.sync(&follower).unwrap();
+    assert!(!follower.bucket_exists("stale"));
+    assert!(follower.bucket_exists("d"));
+    assert!(primary.drop_bucket("d").unwrap());
+    r.sync(&follower).unwrap();
+    assert!(!follower.bucket_exists("d"));
+
+    // A corrupt length is rejected instead of being allocated
+    let mut buf = vec![1u8];
+    buf.extend_from_slice(&u32::MAX.to_be_bytes());
+    assert!(Replicator::receive(&remote, buf.as_slice()).is_err());
+
+    let store = Store::new(Config::new(reset("replication-disabled"))).unwrap();
+    assert!(Replicator::new(&store).is_err());
+
+    // Blob chunks are stored in an internal tree that isn't replicated
+    let blobs = primary.blob_bucket::<&str>(Some("blobs"), 4).unwrap();
+    blobs.set("x", b"too long for one chunk").unwrap();
+    assert!(r.sync(&follower).is_err());
+    assert!(Replicator::new(&primary).is_err());
+    blobs.remove("x").unwrap();
+    assert!(r.sync(&follower).is_ok());
+}
+
+#[test]
+fn test_transaction_abort() {
+    #[derive(Debug)]
+    enum WithdrawError {
+        Insufficient(u64),
+        Kv(Error),
+    }
+
+    impl From<Error> for WithdrawError {
+        fn from(e: Error) -> WithdrawError {
+            WithdrawError::Kv(e)
+        }
+    }
+
+    let path = reset("transaction_abort");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, String>(None).unwrap();
+    bucket.set("balance", String::from("5")).unwrap();
+
+    let withdraw = |n: u64| {
+        bucket.transaction(|txn: Transaction<_, _, WithdrawError>| {
+            let balance: u64 = txn.get("balance")?.unwrap().parse().unwrap();
+            if balance < n {
+                return Err(txn.abort(WithdrawError::Insufficient(balance)));
+            }
+            txn.set("balance", (balance - n).to_string())?;
+            Ok(())
+        })
+    };
+
+    withdraw(2).unwrap();
+    assert!(matches!(withdraw(4), Err(WithdrawError::Insufficient(3))));
+    assert_eq!(bucket.get("balance").unwrap().unwrap(), "3");
+
+    // Errors from kv are converted into the abort type
+    let raw = store.bucket::<&str, Raw>(None).unwrap();
+    raw.set("invalid", &[0xff][..]).unwrap();
+    let res = bucket.transaction(|txn: Transaction<_, _, WithdrawError>| txn.get("invalid"));
+    assert!(matches!(res, Err(WithdrawError::Kv(Error::Decode { .. }))));
+}
+
+#[cfg(feature = "json-value")]
+#[test]
+fn test_serde_entry() {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct RawPair {
+        #[serde(with = "crate::serde_raw")]
+        text: Raw,
+        #[serde(with = "crate::serde_raw")]
+        bytes: Raw,
+    }
+
+    let path = reset("serde_entry");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Json<Vec<u32>>>(None).unwrap();
+    bucket.set("a", Json(vec![1, 2])).unwrap();
+
+    let entries: Vec<Entry<String, Json<Vec<u32>>>> = bucket
+        .iter()
+        .map(|item| item.unwrap().entry().unwrap())
+        .collect();
+    let s = serde_json::to_string(&entries).unwrap();
+    assert_eq!(s, r#"[{"key":"a","value":[1,2]}]"#);
+
+    let entries: Vec<Entry<String, Json<Vec<u32>>>> = serde_json::from_str(&s).unwrap();
+    assert_eq!(entries[0].key, "a");
+    assert_eq!(entries[0].value.0, vec![1, 2]);
+
+    let pair = RawPair {
+        text: Raw::from("abc"),
+        bytes: Raw::from(&[0xff, 0][..]),
+    };
+    let s = serde_json::to_string(&pair).unwrap();
+    assert_eq!(s, r#"{"text":"abc","bytes":[255,0]}"#);
+    let pair: RawPair = serde_json::from_str(&s).unwrap();
+    assert_eq!(pair.text, "abc");
+    assert_eq!(pair.bytes, &[0xff, 0][..]);
+}
+
+#[test]
+fn test_tagged() {
+    #[derive(Debug, PartialEq)]
+    enum Record {
+        Name(String),
+        Count(Raw),
+    }
+
+    tagged!(Record { Name, Count });
+
+    let path = reset("tagged");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    let bucket = store.bucket::<&str, Tagged<Record>>(None).unwrap();
+    bucket
+        .set("a", Tagged(Record::Name("hello".into())))
+        .unwrap();
+    bucket
+        .set("b", Tagged(Record::Count(Raw::from(&[1, 2][..]))))
+        .unwrap();
+
+    let a = bucket.get("a").unwrap().unwrap().into_inner();
+    assert_eq!(a, Record::Name("hello".into()));
+    let b = bucket.get("b").unwrap().unwrap().into_inner();
+    assert_eq!(b, Record::Count(Raw::from(&[1, 2][..])));
+    assert_eq!(
+        bucket.tree.get("a").unwrap().unwrap(),
+        &b"\x04Namehello"[..]
+    );
+
+    // Unknown or missing tags fail to decode
+    bucket.tree.insert("c", &b"\x05Otherhello"[..]).unwrap();
+    match bucket.get("c") {
+        Err(Error::Decode { source, .. }) => match *source {
+            Error::UnknownTag { tag, .. } => assert_eq!(tag, "Other"),
+            e => panic!("unexpected error: {:?}", e),
+        },
+        _ => panic!("expected unknown tag"),
+    }
+    bucket.tree.insert("d", &b""[..]).unwrap();
+    assert!(bucket.get("d").is_err());
+}
+
+#[test]
+fn test_compact() {
+    let path = reset("compact");
+
+    let cfg = Config::new(path.clone());
+    let store = Store::new(cfg).unwrap();
+    {
+        let bucket = store.bucket::<Integer, String>(None).unwrap();
+        for i in 0..2000 {
+            bucket.set(i, "x".repeat(100)).unwrap();
+        }
+        for i in 10..2000 {
+            bucket.remove(i).unwrap();
+        }
+        bucket.flush().unwrap();
+    }
+
+    let usage = store.space_usage().unwrap();
+    assert!(usage.live_bytes > 1000);
+    assert!(usage.reclaimable() > 0);
+    assert_eq!(usage.reclaimable(), usage.size_on_disk - usage.live_bytes);
+
+    let store = store.compact().unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+    assert_eq!(bucket.len(), 10);
+    assert_eq!(bucket.get(5).unwrap().unwrap(), "x".repeat(100));
+    assert!(store.space_usage().unwrap().size_on_disk <= usage.size_on_disk);
+    assert!(!path::Path::new(&format!("{}.compact", path)).exists());
+
+    // Compacting with a bucket or lock still open would lose writes made through it
+    assert!(store.compact().is_err());
+    assert_eq!(bucket.len(), 10);
+    drop(bucket);
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    let lock = store.lock("x", std::time::Duration::from_secs(60)).unwrap();
+    assert!(store.compact().is_err());
+    drop(lock);
+
+    // An interrupted compaction is finished when the store is next opened, or undone if the
+    // copy wasn't complete
+    let copy = format!("{}.compact", path);
+    let old = format!("{}.old", path);
+    let intent = format!("{}.replacing", path);
+    let _ = fs::remove_dir_all(&copy);
+    let _ = fs::remove_dir_all(&old);
+    {
+        let store = Store::new(Config::new(copy.clone())).unwrap();
+        let bucket = store.bucket::<Integer, String>(None).unwrap();
+        bucket.set(1, "copy").unwrap();
+        store.flush().unwrap();
+    }
+    fs::rename(&path, &old).unwrap();
+    fs::write(&intent, format!("swap\n{}\n{}\nremove\n", copy, old)).unwrap();
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    let bucket = store.bucket::<Integer, String>(None).unwrap();
+    assert_eq!(bucket.get(1).unwrap().unwrap(), "copy");
+    assert!(!path::Path::new(&old).exists());
+    assert!(!path::Path::new(&intent).exists());
+    drop(bucket);
+    drop(store);
+
+    fs::create_dir(&copy).unwrap();
+    fs::write(&intent, format!("copy\n{}\n{}\nremove\n", copy, old)).unwrap();
+    let store = Store::new(Config::new(path.clone())).unwrap();
+    assert!(!path::Path::new(&copy).exists());
+    assert!(!path::Path::new(&intent).exists());
+    assert_eq!(store.bucket::<Integer, String>(None).unwrap().len(), 1);
+
+    let store = Store::new(Config::new(reset("compact-temporary")).temporary(true)).unwrap();
+    assert!(store.compact().is_err());
+}
+
+#[test]
+fn test_tiered() {
+    let top = Store::new(Config::new(reset("tiered-top")).temporary(true)).unwrap();
+    let bottom = Store::new(Config::new(reset("tiered-bottom"))).unwrap();
+    let t = top.bucket::<&str, String>(None).unwrap();
+    let b = bottom.bucket::<&str, String>(None).unwrap();
+    b.set("a", String::from("1")).unwrap();
+
+    // Write-through
+    let tiered = TieredBucket::new(t, b, WritePolicy::Through);
+    assert_eq!(tiered.get("a").unwrap().unwrap(), "1");
+    assert_eq!(tiered.top().get("a").unwrap().unwrap(), "1");
+    tiered.set("b", String::from("2")).unwrap();
+    assert_eq!(tiered.bottom().get("b").unwrap().unwrap(), "2");
+    tiered.remove("a").unwrap();
+    assert!(tiered.top().get("a").unwrap().is_none());
+    assert!(tiered.bottom().get("a").unwrap().is_none());
+
+    // Invalidation picks up changes made to the bottom tier
+    tiered.bottom().set("b", String::from("3")).unwrap();
+    assert_eq!(tiered.get("b").unwrap().unwrap(), "2");
+    tiered.invalidate("b").unwrap();
+    assert_eq!(tiered.get("b").unwrap().unwrap(), "3");
+
+    // Write-back
+    let t = top.bucket::<&str, String>(Some("back")).unwrap();
+    let b = bottom.bucket::<&str, String>(Some("back")).unwrap();
+    b.set("x", String::from("old")).unwrap();
+    let tiered = TieredBucket::new(t, b, WritePolicy::Back(3));
+    tiered.set("y", String::from("1")).unwrap();
+    tiered.remove("x").unwrap();
+    assert_eq!(tiered.pending().unwrap(), 2);
+    assert!(tiered.get("x").unwrap().is_none());
+    assert_eq!(tiered.get("y").unwrap().unwrap(), "1");
+    assert_eq!(tiered.bottom().get("x").unwrap().unwrap(), "old");
+    assert!(tiered.bottom().get("y").unwrap().is_none());
+
+    tiered.set("z", String::from("2")).unwrap();
+    assert_eq!(tiered.pending().unwrap(), 0);
+    assert!(tiered.bottom().get("x").unwrap().is_none());
+    assert_eq!(tiered.bottom().get("z").unwrap().unwrap(), "2");
+
+    tiered.set("w", String::from("3")).unwrap();
+    drop(tiered);
+    let b = bottom.bucket::<&str, String>(Some("back")).unwrap();
+    assert_eq!(b.get("w").unwrap().unwrap(), "3");
+}
+
+#[cfg(feature = "test-utils")]
+#[test]
+fn test_test_utils() {
+    use crate::test_utils::*;
+
+    let mut g = Gen::new(1);
+    check_keys::<Raw>(&mut g, 100);
+    check_keys::<String>(&mut g, 100);
+    check_keys::<Integer>(&mut g, 100);
+    check_keys::<SignedInteger>(&mut g, 100);
+    check_keys::<Composite<(u32, String, i64)>>(&mut g, 100);
+    check_values::<String>(&mut g, 100);
+    check_values::<Checked<Vec<u8>>>(&mut g, 100);
+
+    check_order::<String, String, _>(&mut g, 100, |x| x.clone());
+    check_order::<u64, Integer, _>(&mut g, 100, |x| Integer::from(*x));
+    check_order::<i64, SignedInteger, _>(&mut g, 100, |x| SignedInteger::from(*x));
+    check_order::<(i32, Vec<u8>, bool), _, _>(&mut g, 200, |x| Composite::new(x.clone()));
+
+    for _ in 0..100 {
+        let data = g.bytes();
+        fuzz_key::<Composite<(u8, String)>>(&data);
+        fuzz_key::<Integer>(&data);
+        fuzz_key::<SignedInteger>(&data);
+        fuzz_value::<Checked<String>>(&data);
+    }
+
+    // Seeds are reproducible
+    assert_eq!(Gen::new(7).bytes(), Gen::new(7).bytes());
+
+    // Encodings that don't preserve order are caught
+    let res = std::panic::catch_unwind(|| {
+        assert_order_preserved(&-1i64, &1i64, |x| Integer::from(*x as u64))
+    });
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_lock() {
+    let store = Store::new(Config::new(reset("lock")).temporary(true)).unwrap();
+    let lease = std::time::Duration::from_millis(100);
+
+    let lock = store.try_lock("a", lease).unwrap().unwrap();
+    assert!(lock.is_held().unwrap());
+    assert!(store.try_lock("a", lease).unwrap().is_none());
+    assert!(store.try_lock("b", lease).unwrap().is_some());
+    assert!(lock.release().unwrap());
+    let mut lock = store.lock("a", lease).unwrap();
+
+    // An expired lease can be taken over and the old holder can't renew it
+    std::thread::sleep(std::time::Duration::from_millis(150));
+    assert!(!lock.is_held().unwrap());
+    let other = store.try_lock("a", lease).unwrap().unwrap();
+    assert!(!lock.renew(lease).unwrap());
+    assert!(!lock.release().unwrap());
+    assert!(other.is_held().unwrap());
+    drop(other);
+
+    let mut lock = store.lock("a", lease).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    assert!(lock.renew(lease).unwrap());
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    assert!(lock.is_held().unwrap());
+
+    let bucket = store.bucket::<&str, String>(Some("lock")).unwrap();
+    let other = store.bucket::<&str, String>(Some("other")).unwrap();
+    let key = bucket.try_lock_key("a", lease).unwrap().unwrap();
+    assert!(bucket.try_lock_key("a", lease).unwrap().is_none());
+    assert!(other.try_lock_key("a", lease).unwrap().is_some());
+    drop(key);
+    assert!(bucket.lock_key("a", lease).unwrap().is_held().unwrap());
+
+    // Waiting gives up once the timeout passes while the lock is held
+    let timeout = std::time::Duration::from_millis(30);
+    let held = store.try_lock("c", lease).unwrap().unwrap();
+    assert!(store.lock_timeout("c", lease, timeout).unwrap().is_none());
+    drop(held);
+    assert!(store.lock_timeout("c", lease, timeout).unwrap().is_some());
+    let held = bucket.try_lock_key("b", lease).unwrap().unwrap();
+    assert!(bucket
+        .lock_key_timeout("b", lease, timeout)
+        .unwrap()
+        .is_none());
+    drop(held);
+    assert!(bucket
+        .lock_key_timeout("b", lease, timeout)
+        .unwrap()
+        .is_some());
+
+    // Store locks don't share keys with locks on keys in the bucket with an empty name
+    let empty = store.bucket::<&str, String>(Some("")).unwrap();
+    let held = store.try_lock("d", lease).unwrap().unwrap();
+    assert!(empty.try_lock_key("d", lease).unwrap().is_some());
+    drop(held);
+
+    // A malformed entry doesn't hold the lock forever
+    let locks = store.db.open_tree(crate::lock::TREE_NAME).unwrap();
+    locks
+        .insert(crate::lock::store_key("e"), &b"x"[..])
+        .unwrap();
+    assert!(store.try_lock("e", lease).unwrap().is_some());
+}
+
+#[test]
+fn test_blob() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let store = Store::new(Config::new(reset("blob")).temporary(true)).unwrap();
+    let chunks = store.db.open_tree("__kv__blobs").unwrap();
+    let blobs = store.blob_bucket::<&str>(Some("blob"), 4).unwrap();
+
+    // Values that fit in one chunk are stored inline
+    assert_eq!(blobs.set("small", b"abcd").unwrap(), 4);
+    assert_eq!(blobs.get("small").unwrap().unwrap(), b"abcd");
+    assert_eq!(chunks.len(), 0);
+
+    let data: Vec<u8> = (0..10u8).collect();
+    let mut w = blobs.writer("big");
+    w.write_all(&data[..3]).unwrap();
+    w.write_all(&data[3..]).unwrap();
+    assert!(blobs.get("big").unwrap().is_none());
+    assert_eq!(w.finish().unwrap(), 10);
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(blobs.len("big").unwrap(), Some(10));
+    assert_eq!(blobs.get("big").unwrap().unwrap(), data);
+
+    let mut r = blobs.reader("big").unwrap().unwrap();
+    let mut buf = [0u8; 3];
+    r.seek(SeekFrom::Start(3)).unwrap();
+    r.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [3, 4, 5]);
+    r.seek(SeekFrom::End(-2)).unwrap();
+    let mut rest = Vec::new();
+    r.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, [8, 9]);
+
+    // Replacing a value removes its chunks, so a reader from before fails
+    let mut r = blobs.reader("big").unwrap().unwrap();
+    blobs.set("big", &data[..6]).unwrap();
+    assert_eq!(chunks.len(), 2);
+    assert!(r.read_to_end(&mut Vec::new()).is_err());
+    assert_eq!(blobs.get("big").unwrap().unwrap(), &data[..6]);
+
+    // Unfinished writers are discarded
+    let mut w = blobs.writer("big");
+    w.write_all(&data).unwrap();
+    drop(w);
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(blobs.get("big").unwrap().unwrap(), &data[..6]);
+
+    assert!(blobs.remove("big").unwrap());
+    assert!(!blobs.remove("big").unwrap());
+    assert_eq!(chunks.len(), 0);
+
+    // Chunks left behind by a crash are removed, chunks of unfinished writers are kept
+    blobs.set("big", &data).unwrap();
+    let orphan = [0xffu8; 16];
+    let mut key = 4u32.to_be_bytes().to_vec();
+    key.extend_from_slice(b"blob");
+    key.extend_from_slice(&orphan);
+    chunks.insert(key, &b"abcd"[..]).unwrap();
+    let mut w = blobs.writer("other");
+    w.write_all(&data).unwrap();
+    assert_eq!(chunks.len(), 6);
+    assert_eq!(blobs.purge_orphaned().unwrap(), 1);
+    assert_eq!(blobs.purge_orphaned().unwrap(), 0);
+    w.finish().unwrap();
+    assert_eq!(blobs.get("big").unwrap().unwrap(), data);
+    assert_eq!(blobs.get("other").unwrap().unwrap(), data);
+    assert_eq!(blobs.purge_orphaned().unwrap(), 0);
+
+    store.drop_bucket("blob").unwrap();
+    assert_eq!(chunks.len(), 0);
+}
+
+#[test]
+fn test_time_series() {
+    use std::time::Duration;
+
+    let store = Store::new(Config::new(reset("time_series")).temporary(true)).unwrap();
+    let series = store
+        .time_series::<String>("metrics", Duration::from_secs(1))
+        .unwrap();
+    for t in [500, 1500, 1999, 2000, 3200, 1500].iter() {
+        series.push(*t, format!("{}", t)).unwrap();
+    }
+    assert_eq!(series.partitions(), vec![0, 1000, 2000, 3000]);
+    assert_eq!(series.len().unwrap(), 6);
+    assert!(store.buckets().iter().all(|x| !x.contains("metrics")));
+
+    let points: Vec<u64> = series
+        .range(1500, 3200)
+        .unwrap()
+        .map(|p| p.unwrap().timestamp)
+        .collect();
+    assert_eq!(points, vec![1500, 1500, 1999, 2000]);
+    let first = series.iter().unwrap().next().unwrap().unwrap();
+    assert_eq!(first.value, "500");
+
+    assert!(store
+        .time_series::<String>("metrics", Duration::from_secs(2))
+        .is_err());
+
+    assert_eq!(series.drop_before(2500).unwrap(), 2);
+    assert_eq!(series.partitions(), vec![2000, 3000]);
+
+    // Starting a new partition drops the expired ones
+    let series = store
+        .time_series::<String>("metrics", Duration::from_secs(1))
+        .unwrap()
+        .retention(Duration::from_secs(60));
+    series.push_now("now").unwrap();
+    assert_eq!(series.len().unwrap(), 1);
+    assert_eq!(series.clear().unwrap(), 1);
+    assert!(series.is_empty().unwrap());
+
+    // Partitions added through another handle are still found
+    let other = store
+        .time_series::<String>("metrics", Duration::from_secs(1))
+        .unwrap();
+    other.push(5000, "5000").unwrap();
+    assert_eq!(series.partitions(), vec![5000]);
+    assert_eq!(series.len().unwrap(), 1);
+
+    // `/` would let one series read the partitions of another
+    assert!(store
+        .time_series::<String>("metrics/0", Duration::from_secs(1))
+        .is_err());
+}
+
+#[test]
+fn test_open_with_recovery() {
+    let path = reset("recovery");
+    {
+        let store = Store::new(Config::new(path.clone())).unwrap();
+        let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+        bucket.set("x", String::from("1")).unwrap();
+        bucket.set("y", String::from("2")).unwrap();
+        store.flush().unwrap();
+    }
+
+    let (store, report) =
+        Store::open_with_recovery(Config::new(path.clone()), RecoveryPolicy::Salvage).unwrap();
+    assert!(report.is_ok());
+    let a = report.buckets.iter().find(|x| x.name == "a").unwrap();
+    assert_eq!(a.recovered, 2);
+    assert!(a.complete);
+    let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+    assert_eq!(bucket.get("y").unwrap().unwrap(), "2");
+    drop(bucket);
+    drop(store);
+
+    // A damaged settings file stops sled from opening the store, the entries are recovered
+    // from the log
+    fs::write(format!("{}/conf", path), b"not a setting\n\0\0\0\0").unwrap();
+    let (store, report) =
+        Store::open_with_recovery(Config::new(path.clone()), RecoveryPolicy::Salvage).unwrap();
+    let damaged = report.damaged.clone().unwrap();
+    assert!(damaged.join("conf").exists());
+    assert_eq!(
+        report
+            .buckets
+            .iter()
+            .find(|x| x.name == "a")
+            .unwrap()
+            .recovered,
+        2
+    );
+    let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+    assert_eq!(bucket.get("x").unwrap().unwrap(), "1");
+
+    // A locked store isn't damaged, so it's never reset
+    assert!(matches!(
+        Store::open_with_recovery(Config::new(path.clone()), RecoveryPolicy::Reset),
+        Err(Error::StoreLocked(_))
+    ));
+    drop(bucket);
+    drop(store);
+
+    // Without its snapshots sled skips an unreadable log, so nothing is recovered but the store
+    // isn't reset, and the earlier damaged copy is kept
+    fs::write(format!("{}/db", path), vec![7u8; 1 << 20]).unwrap();
+    let (store, report) =
+        Store::open_with_recovery(Config::new(path.clone()), RecoveryPolicy::Reset).unwrap();
+    assert!(!report.is_ok());
+    assert_ne!(report.damaged, Some(damaged.clone()));
+    assert!(damaged.join("conf").exists());
+    assert!(!store.bucket_exists("a"));
+    drop(store);
+
+    // Replacing a damaged store is finished if it stopped between moving the damaged store
+    // aside and moving the recovered copy into place
+    let copy = format!("{}.recovered", path);
+    let _ = fs::remove_dir_all(&copy);
+    {
+        let store = Store::new(Config::new(copy.clone())).unwrap();
+        let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+        bucket.set("z", String::from("3")).unwrap();
+        store.flush().unwrap();
+    }
+    let moved = format!("{}.moved", path);
+    let _ = fs::remove_dir_all(&moved);
+    fs::rename(&path, &moved).unwrap();
+    fs::write(
+        format!("{}.replacing", path),
+        format!("swap\n{}\n{}\nkeep\n", copy, moved),
+    )
+    .unwrap();
+    let (store, report) =
+        Store::open_with_recovery(Config::new(path.clone()), RecoveryPolicy::Salvage).unwrap();
+    assert_eq!(report.damaged, Some(path::PathBuf::from(&moved)));
+    let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+    assert_eq!(bucket.get("z").unwrap().unwrap(), "3");
+    assert!(!path::Path::new(&copy).exists());
+    assert!(!path::Path::new(&format!("{}.replacing", path)).exists());
+
+    #[cfg(feature = "encryption")]
+    {
+        let path = reset("recovery-encrypted");
+        let key = [3u8; 32];
+        {
+            let store = Store::new_encrypted(Config::new(path.clone()), &key).unwrap();
+            let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+            bucket.set("x", String::from("1")).unwrap();
+            store.flush().unwrap();
+        }
+
+        // sled's background threads can hold the lock briefly after the store is dropped
+        let cfg = Config::new(path).open_timeout(std::time::Duration::from_secs(5));
+        let (store, _) =
+            Store::open_encrypted_with_recovery(cfg, &key, RecoveryPolicy::Salvage).unwrap();
+        let bucket = store.bucket::<&str, String>(Some("a")).unwrap();
+        assert_eq!(bucket.get("x").unwrap().unwrap(), "1");
+    }
+
+    assert!(Store::open_with_recovery(
+        Config::new(reset("recovery-temporary")).temporary(true),
+        RecoveryPolicy::Salvage
+    )
+    .is_err());
+}
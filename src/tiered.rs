@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{Batch, Bucket, Error, Key, Raw, Value};
+
+/// Controls when writes to a `TieredBucket` reach the bottom tier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    /// Write to the bottom tier and then the top tier before returning
+    Through,
+
+    /// Write to the top tier and queue the write for the bottom tier, queued writes are applied
+    /// as a single batch once this many are pending or when `TieredBucket::flush` is called
+    Back(usize),
+}
+
+/// Bucket that layers a fast bucket, usually from a temporary store, over a persistent one
+///
+/// Reads are served from the top tier when possible, values that are only found in the bottom
+/// tier are copied to the top tier. Writes go to both tiers according to the `WritePolicy`.
+///
+/// All writes to either bucket must go through the `TieredBucket`, otherwise the top tier will
+/// return stale values until they are invalidated. Queued writes are flushed when the
+/// `TieredBucket` is dropped, but errors are ignored, call `flush` to check for them.
+pub struct TieredBucket<'a, K: Key<'a>, V: Value> {
+    top: Bucket<'a, K, V>,
+    bottom: Bucket<'a, K, V>,
+    policy: WritePolicy,
+    pending: Mutex<BTreeMap<Raw, Option<Raw>>>,
+}
+
+impl<'a, K: Key<'a>, V: Value> TieredBucket<'a, K, V> {
+    /// Layer `top` over `bottom`
+    pub fn new(
+        top: Bucket<'a, K, V>,
+        bottom: Bucket<'a, K, V>,
+        policy: WritePolicy,
+    ) -> TieredBucket<'a, K, V> {
+        TieredBucket {
+            top,
+            bottom,
+            policy,
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Get the top tier
+    pub fn top(&self) -> &Bucket<'a, K, V> {
+        &self.top
+    }
+
+    /// Get the bottom tier
+    pub fn bottom(&self) -> &Bucket<'a, K, V> {
+        &self.bottom
+    }
+
+    /// Get the value associated with the specified key, copying it to the top tier if it is
+    /// only found in the bottom tier
+    pub fn get<X: Into<K>>(&self, key: X) -> Result<Option<V>, Error> {
+        let key = key.into().to_raw_key()?;
+        if let Some(None) = self.pending.lock()?.get(&key) {
+            return Ok(None);
+        }
+        if let Some(v) = self.top.get_raw(&key)? {
+            return Ok(Some(v));
+        }
+
+        let v = self.bottom.get_raw(&key)?;
+        if let Some(v) = &v {
+            self.top.set_raw(&key, v.to_raw_value()?)?;
+        }
+        Ok(v)
+    }
+
+    /// Returns true if the bucket contains the given key
+    pub fn contains<X: Into<K>>(&self, key: X) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Set the value associated with the specified key to the provided value
+    pub fn set<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        let v = value.into().to_raw_value()?;
+        self.write(key, Some(v))
+    }
+
+    /// Remove the value associated with the specified key from both tiers
+    pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        self.write(key, None)
+    }
+
+    fn write(&self, key: Raw, value: Option<Raw>) -> Result<(), Error> {
+        let max = match self.policy {
+            WritePolicy::Through => {
+                write(&self.bottom, &key, value.clone())?;
+                return write(&self.top, &key, value);
+            }
+            WritePolicy::Back(max) => max,
+        };
+
+        // The pending write is queued before the top tier is changed so reads never see a
+        // removed value from the bottom tier
+        let mut pending = self.pending.lock()?;
+        pending.insert(key.clone(), value.clone());
+        write(&self.top, &key, value)?;
+        if pending.len() >= max {
+            self.apply(&mut pending)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, pending: &mut BTreeMap<Raw, Option<Raw>>) -> Result<(), Error> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut batch = Batch::new();
+        batch
+            .0
+            .extend(pending.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.bottom.batch(batch)?;
+        pending.clear();
+        Ok(())
+    }
+
+    /// Returns the number of writes queued for the bottom tier
+    pub fn pending(&self) -> Result<usize, Error> {
+        Ok(self.pending.lock()?.len())
+    }
+
+    /// Apply all queued writes to the bottom tier and flush it to disk, returning the number of
+    /// bytes flushed
+    pub fn flush(&self) -> Result<usize, Error> {
+        self.apply(&mut *self.pending.lock()?)?;
+        self.bottom.flush()
+    }
+
+    /// Remove the specified key from the top tier, the next read copies it from the bottom tier
+    /// again. Keys with a queued write are written to the bottom tier first.
+    pub fn invalidate<X: Into<K>>(&self, key: X) -> Result<(), Error> {
+        let key = key.into().to_raw_key()?;
+        let mut pending = self.pending.lock()?;
+        if pending.contains_key(&key) {
+            self.apply(&mut pending)?;
+        }
+        self.top.remove_raw(&key)
+    }
+
+    /// Remove every key from the top tier after applying all queued writes
+    pub fn invalidate_all(&self) -> Result<(), Error> {
+        self.apply(&mut *self.pending.lock()?)?;
+        self.top.clear()
+    }
+}
+
+fn write<'a, K: Key<'a>, V: Value>(
+    bucket: &Bucket<'a, K, V>,
+    key: &Raw,
+    value: Option<Raw>,
+) -> Result<(), Error> {
+    match value {
+        Some(v) => bucket.set_raw(key, v),
+        None => bucket.remove_raw(key),
+    }
+}
+
+impl<'a, K: Key<'a>, V: Value> Drop for TieredBucket<'a, K, V> {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            let _ = self.apply(&mut pending);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::{ttl, Bucket, Error, Iter, Raw, Value};
+
+// Each series has a tree holding its settings, partitions are named after it followed by the
+// zero-padded start of the period they hold so they sort in time order
+pub(crate) const TREE_PREFIX: &str = "__kv__ts/";
+const PERIOD_KEY: &[u8] = b"period";
+
+/// Series names can't contain `/`, which separates the name from the start of each partition
+pub(crate) fn check_name(name: &str) -> Result<(), Error> {
+    if name.contains('/') {
+        return Err(Error::Message(format!(
+            "Invalid time series name {:?}, names can't contain '/'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn millis(x: Duration) -> u64 {
+    x.as_millis().min(u64::MAX as u128) as u64
+}
+
+/// A value in a `TimeSeriesBucket`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point<V> {
+    /// Time of the point, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// Value
+    pub value: V,
+}
+
+/// A bucket of timestamped values partitioned into separate trees by period, so old data can
+/// be dropped a whole partition at a time
+///
+/// Created using `Store::time_series`. Points are ordered by timestamp and several points can
+/// share a timestamp. Partitions are internal trees, so they aren't returned by `Store::buckets`.
+pub struct TimeSeriesBucket<V: Value> {
+    meta: Bucket<'static, Raw, V>,
+    db: sled::Db,
+    name: String,
+    period: u64,
+    retention: Option<u64>,
+    // Partitions known to exist, so `push` doesn't list every tree in the store. Partitions
+    // created by another handle are picked up by `partitions`.
+    known: RwLock<BTreeSet<u64>>,
+}
+
+impl<V: Value> TimeSeriesBucket<V> {
+    pub(crate) fn new(
+        meta: Bucket<'static, Raw, V>,
+        db: sled::Db,
+        name: &str,
+        period: Duration,
+    ) -> Result<TimeSeriesBucket<V>, Error> {
+        let period = millis(period).max(1);
+        let stored = meta.tree.compare_and_swap(
+            PERIOD_KEY,
+            None as Option<Raw>,
+            Some(&period.to_be_bytes()[..]),
+        )?;
+
+        // Partitions written with another period wouldn't line up with the new one
+        if let Err(e) = stored {
+            if e.current.as_deref() != Some(&period.to_be_bytes()[..]) {
+                return Err(Error::InvalidConfiguration(format!(
+                    "time series {:?} was created with a different period",
+                    name
+                )));
+            }
+        }
+
+        let series = TimeSeriesBucket {
+            meta,
+            db,
+            name: name.to_string(),
+            period,
+            retention: None,
+            known: RwLock::new(BTreeSet::new()),
+        };
+        series.partitions();
+        Ok(series)
+    }
+
+    /// Drop partitions once all of their points are older than `retention`, see `purge_expired`
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(millis(retention));
+        self
+    }
+
+    /// Get the name of the series
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the length of time covered by each partition
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(self.period)
+    }
+
+    fn partition_name(&self, start: u64) -> String {
+        format!("{}{}/{:020}", TREE_PREFIX, self.name, start)
+    }
+
+    fn partition(&self, start: u64) -> Result<Bucket<'static, Raw, V>, Error> {
+        let t = self.db.open_tree(self.partition_name(start))?;
+        let crypt = self.meta.crypt.bucket(&t.name());
+        Ok(Bucket::new(
+            t,
+            self.meta.ttl.clone(),
+            crypt,
+            self.meta.observer.clone(),
+            None,
+            self.meta.locks.clone(),
+        ))
+    }
+
+    /// Get the start time of every partition in order, in milliseconds since the Unix epoch
+    pub fn partitions(&self) -> Vec<u64> {
+        let prefix = format!("{}{}/", TREE_PREFIX, self.name);
+        let mut starts: Vec<u64> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|x| x.strip_prefix(prefix.as_bytes()))
+            .filter_map(|x| std::str::from_utf8(x).ok()?.parse().ok())
+            .collect();
+        starts.sort_unstable();
+        if let Ok(mut known) = self.known.write() {
+            *known = starts.iter().copied().collect();
+        }
+        starts
+    }
+
+    /// Add a point, `timestamp` is in milliseconds since the Unix epoch
+    pub fn push<Y: Into<V>>(&self, timestamp: u64, value: Y) -> Result<(), Error> {
+        let start = timestamp - timestamp % self.period;
+        let exists = self.known.read()?.contains(&start);
+        if !exists {
+            self.known.write()?.insert(start);
+        }
+
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&timestamp.to_be_bytes());
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        self.partition(start)?.set(key, value)?;
+
+        // Only check retention when a new period starts, older partitions can't have expired
+        // in between
+        if !exists {
+            self.purge_expired()?;
+        }
+        Ok(())
+    }
+
+    /// Add a point with the current time
+    pub fn push_now<Y: Into<V>>(&self, value: Y) -> Result<(), Error> {
+        self.push(ttl::now()?, value)
+    }
+
+    /// Get an iterator over points with timestamps from `start` up to but not including `end`
+    pub fn range(&self, start: u64, end: u64) -> Result<Points<V>, Error> {
+        let mut partitions = VecDeque::new();
+        for p in self.partitions() {
+            if p < end && p.saturating_add(self.period) > start {
+                partitions.push_back(self.partition(p)?);
+            }
+        }
+        Ok(Points {
+            partitions,
+            iter: None,
+            start: start.to_be_bytes(),
+            end: end.to_be_bytes(),
+        })
+    }
+
+    /// Get an iterator over all points
+    pub fn iter(&self) -> Result<Points<V>, Error> {
+        self.range(0, u64::MAX)
+    }
+
+    /// Get the number of points
+    pub fn len(&self) -> Result<usize, Error> {
+        let mut n = 0;
+        for p in self.partitions() {
+            n += self.partition(p)?.len();
+        }
+        Ok(n)
+    }
+
+    /// Returns true when there are no points
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Drop every partition that only holds points older than `timestamp`, returns the number of
+    /// partitions dropped
+    pub fn drop_before(&self, timestamp: u64) -> Result<usize, Error> {
+        let mut n = 0;
+        for p in self.partitions() {
+            if p.saturating_add(self.period) > timestamp {
+                break;
+            }
+            self.known.write()?.remove(&p);
+            if self.db.drop_tree(self.partition_name(p).as_bytes())? {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Drop partitions that are entirely older than the retention period, returns the number of
+    /// partitions dropped
+    ///
+    /// This is called automatically when a point starts a new partition, it does nothing if no
+    /// retention period was set
+    pub fn purge_expired(&self) -> Result<usize, Error> {
+        match self.retention {
+            Some(retention) => self.drop_before(ttl::now()?.saturating_sub(retention)),
+            None => Ok(0),
+        }
+    }
+
+    /// Drop all partitions
+    pub fn clear(&self) -> Result<usize, Error> {
+        self.drop_before(u64::MAX)
+    }
+}
+
+/// Iterator over points in a `TimeSeriesBucket`, in timestamp order
+pub struct Points<V: Value> {
+    partitions: VecDeque<Bucket<'static, Raw, V>>,
+    iter: Option<Iter<Raw, V>>,
+    start: [u8; 8],
+    end: [u8; 8],
+}
+
+impl<V: Value> Iterator for Points<V> {
+    type Item = Result<Point<V>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(iter) = &mut self.iter {
+                match iter.next() {
+                    Some(Ok(item)) => {
+                        let point = item.key::<Raw>().and_then(|k| {
+                            let mut timestamp = [0u8; 8];
+                            timestamp.copy_from_slice(&k[..8]);
+                            Ok(Point {
+                                timestamp: u64::from_be_bytes(timestamp),
+                                value: item.value()?,
+                            })
+                        });
+                        return Some(point);
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => (),
+                }
+            }
+
+            let partition = self.partitions.pop_front()?;
+            self.iter = Some(partition.iter_range(&self.start[..], &self.end[..]));
+        }
+    }
+}
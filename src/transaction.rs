@@ -1,61 +1,402 @@
+use std::cell::RefCell;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::{Batch, Error, Key, Value};
+use sled::Transactional;
+
+use crate::bucket::decode;
+use crate::changes;
+use crate::crypt::Crypt;
+use crate::scoped::prefixed;
+use crate::{Batch, Error, Key, Raw, Value};
 
 /// Transaction error
 pub type TransactionError<E> = sled::ConflictableTransactionError<E>;
 
+// sled only supports `()` as the abort type for transactions over multiple trees, so the abort
+// value is stashed in `error` and returned once the transaction is finished
+pub(crate) fn stash_abort<A, E>(
+    error: &RefCell<Option<E>>,
+    res: Result<A, TransactionError<E>>,
+) -> Result<A, TransactionError<()>> {
+    match res {
+        Ok(x) => Ok(x),
+        Err(TransactionError::Abort(e)) => {
+            *error.borrow_mut() = Some(e);
+            Err(TransactionError::Abort(()))
+        }
+        Err(TransactionError::Conflict) => Err(TransactionError::Conflict),
+        Err(TransactionError::Storage(e)) => Err(TransactionError::Storage(e)),
+    }
+}
+
+pub(crate) fn unstash_abort<A, E: From<Error>>(
+    error: RefCell<Option<E>>,
+    res: sled::TransactionResult<A, ()>,
+) -> Result<A, E> {
+    match res {
+        Ok(x) => Ok(x),
+        Err(sled::TransactionError::Abort(())) => match error.into_inner() {
+            Some(e) => Err(e),
+            None => {
+                Err(Error::from(sled::Error::ReportableBug("transaction aborted".into())).into())
+            }
+        },
+        Err(sled::TransactionError::Storage(e)) => Err(Error::from(e).into()),
+    }
+}
+
+/// Maximum number of trees that can be used in a transaction by `run_on`
+pub(crate) const MAX_TREES: usize = 69;
+
+// sled only implements `Transactional` for tuples of trees, this builds a tuple with one element
+// for each tree by adding the indices one at a time
+macro_rules! tuple_transaction {
+    ($trees:ident, $f:ident; [$($done:tt)*]) => {};
+    ($trees:ident, $f:ident; [$($done:tt)*] $i:tt $($rest:tt)*) => {
+        if $trees.len() == [$($done,)* $i].len() {
+            return ($($trees[$done],)* $trees[$i]).transaction(|t| $f(&[$(&t.$done,)* &t.$i]));
+        }
+        tuple_transaction!($trees, $f; [$($done)* $i] $($rest)*);
+    };
+}
+
+// Callback used by `run_on_dyn`, called with one transactional tree for each tree
+type TreesFn<'a> = dyn Fn(&[&sled::TransactionalTree]) -> Result<(), TransactionError<()>> + 'a;
+
+fn run_on_dyn(trees: &[&sled::Tree], f: &TreesFn) -> sled::TransactionResult<(), ()> {
+    if trees.len() == 1 {
+        return trees[0].transaction(|t| f(&[t]));
+    }
+    tuple_transaction!(trees, f; [0] 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64 65 66 67 68);
+    Err(sled::TransactionError::Storage(sled::Error::Unsupported(
+        format!("A transaction can use at most {} trees", MAX_TREES),
+    )))
+}
+
+/// Run `f` in a transaction over every tree in `trees`, the abort type is `()` since sled
+/// doesn't support anything else for transactions over several trees
+pub(crate) fn run_on<A, F: Fn(&[&sled::TransactionalTree]) -> Result<A, TransactionError<()>>>(
+    trees: &[&sled::Tree],
+    f: F,
+) -> sled::TransactionResult<A, ()> {
+    let result = RefCell::new(None);
+    run_on_dyn(trees, &|t| {
+        *result.borrow_mut() = Some(f(t)?);
+        Ok(())
+    })?;
+    match result.into_inner() {
+        Some(x) => Ok(x),
+        None => Err(sled::TransactionError::Storage(sled::Error::ReportableBug(
+            "transaction committed without a result".into(),
+        ))),
+    }
+}
+
+// Abort a transaction with an error from kv, converted into the transaction's error type
+fn abort_with<E: From<Error>>(e: Error) -> TransactionError<E> {
+    TransactionError::Abort(e.into())
+}
+
+// Convert the error of an internal operation into the transaction's error type
+fn lift<E: From<Error>>(e: TransactionError<Error>) -> TransactionError<E> {
+    match e {
+        TransactionError::Abort(e) => TransactionError::Abort(e.into()),
+        TransactionError::Conflict => TransactionError::Conflict,
+        TransactionError::Storage(e) => TransactionError::Storage(e),
+    }
+}
+
 /// Transaction
-#[derive(Clone)]
-pub struct Transaction<'a, 'b, K: Key<'a>, V: Value>(
-    &'b sled::TransactionalTree,
-    PhantomData<K>,
-    PhantomData<V>,
-    PhantomData<&'a ()>,
-);
+///
+/// sled transactions only support reading and writing individual keys, so keys can't be
+/// iterated over inside a transaction. `E` is the error type the transaction can be aborted with,
+/// errors from kv are converted into it so it must implement `From<Error>`.
+pub struct Transaction<'a, 'b, K: Key<'a>, V: Value, E = Error> {
+    tree: &'b sled::TransactionalTree,
+    prefix: Raw,
+    crypt: Crypt,
+    changefeed: Option<&'b sled::TransactionalTree>,
+    written: Option<&'b RefCell<Vec<Raw>>>,
+    bucket: Raw,
+    _marker: PhantomData<(K, V, &'a (), E)>,
+}
 
-impl<'a, 'b, K: Key<'a>, V: Value> Transaction<'a, 'b, K, V> {
-    pub(crate) fn new(t: &'b sled::TransactionalTree) -> Self {
-        Transaction(t, PhantomData, PhantomData, PhantomData)
+// Implemented by hand since deriving it would require `E: Clone`
+impl<'a, 'b, K: Key<'a>, V: Value, E> Clone for Transaction<'a, 'b, K, V, E> {
+    fn clone(&self) -> Self {
+        Transaction {
+            tree: self.tree,
+            prefix: self.prefix.clone(),
+            crypt: self.crypt.clone(),
+            changefeed: self.changefeed,
+            written: self.written,
+            bucket: self.bucket.clone(),
+            _marker: PhantomData,
+        }
     }
+}
 
-    /// Get the value associated with the specified key
-    pub fn get<X: Into<K>>(&'a self, key: X) -> Result<Option<V>, TransactionError<Error>> {
-        let v = self
-            .0
-            .get(key.into().to_raw_key().map_err(TransactionError::Abort)?)?;
+impl<'a, 'b, K: Key<'a>, V: Value, E: From<Error>> Transaction<'a, 'b, K, V, E> {
+    pub(crate) fn new(t: &'b sled::TransactionalTree, bucket: Raw, crypt: Crypt) -> Self {
+        Transaction::scoped(t, bucket, Raw::default(), crypt)
+    }
+
+    pub(crate) fn scoped(
+        tree: &'b sled::TransactionalTree,
+        bucket: Raw,
+        prefix: Raw,
+        crypt: Crypt,
+    ) -> Self {
+        Transaction {
+            tree,
+            prefix,
+            crypt,
+            changefeed: None,
+            written: None,
+            bucket,
+            _marker: PhantomData,
+        }
+    }
+
+    // Record every change made using this transaction in the changefeed
+    pub(crate) fn logged(mut self, log: &'b sled::TransactionalTree) -> Self {
+        self.changefeed = Some(log);
+        self
+    }
+
+    // Collect the keys written using this transaction in `keys`, so their expiration times can
+    // be cleared once it commits. The expiration tree isn't part of the transaction since sled
+    // holds a lock on every tree in a transaction until it finishes, which would stop
+    // transactions on different buckets from being nested.
+    pub(crate) fn tracked(mut self, keys: &'b RefCell<Vec<Raw>>) -> Self {
+        keys.borrow_mut().clear();
+        self.written = Some(keys);
+        self
+    }
+
+    fn track(&self, key: &Raw) {
+        if let Some(keys) = self.written {
+            keys.borrow_mut().push(key.clone());
+        }
+    }
+
+    // Get the stored value of an encoded key
+    pub(crate) fn current(&self, key: &[u8]) -> Result<Option<Raw>, TransactionError<E>> {
+        Ok(self.tree.get(key)?)
+    }
+
+    // Set or remove an encoded key, returning the previous value
+    pub(crate) fn write(
+        &self,
+        key: &Raw,
+        value: Option<Raw>,
+    ) -> Result<Option<Raw>, TransactionError<E>> {
+        let old = match &value {
+            Some(v) => self.tree.insert(key, v)?,
+            None => self.tree.remove(key)?,
+        };
+        self.track(key);
+        if let Some(log) = self.changefeed {
+            changes::record(log, &self.bucket, key, old.as_deref(), value.as_deref())
+                .map_err(lift)?;
+        }
+        Ok(old)
+    }
 
-        match v {
+    pub(crate) fn apply(&self, batch: &Batch<K, V>) -> Result<(), TransactionError<E>> {
+        if batch.1 != self.prefix {
+            return Err(abort_with(Error::Message(
+                "Batch prefix does not match transaction prefix".into(),
+            )));
+        }
+        batch.check(self.tree, &self.crypt).map_err(lift)?;
+        if self.changefeed.is_none() {
+            let b = batch.to_sled(&self.crypt).map_err(abort_with)?;
+            self.tree.apply_batch(b)?;
+            for (k, _) in &batch.0 {
+                self.track(k);
+            }
+            return Ok(());
+        }
+
+        // Each change is recorded with the previous value, so the batch is applied one key at a
+        // time
+        for (k, v) in &batch.0 {
+            let v = match v {
+                Some(v) => Some(self.crypt.encrypt(k, v.clone()).map_err(abort_with)?),
+                None => None,
+            };
+            self.write(k, v)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.bucket)
+    }
+
+    fn key<X: Into<K>>(&self, key: X) -> Result<Raw, TransactionError<E>> {
+        let key = key.into().to_raw_key().map_err(abort_with)?;
+        Ok(prefixed(&self.prefix, key))
+    }
+
+    /// Get the value associated with the specified key
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(bucket = %self.name()))
+    )]
+    pub fn get<X: Into<K>>(&'a self, key: X) -> Result<Option<V>, TransactionError<E>> {
+        let key = self.key(key)?;
+        match self.tree.get(&key)? {
             None => Ok(None),
-            Some(x) => Ok(Some(V::from_raw_value(x).map_err(TransactionError::Abort)?)),
+            Some(x) => decode(&self.crypt, &self.bucket, &key, x)
+                .map(Some)
+                .map_err(abort_with),
         }
     }
 
+    /// Get the values associated with each of the specified keys, in the same order as `keys`
+    pub fn get_many<X: Into<K>, I: IntoIterator<Item = X>>(
+        &'a self,
+        keys: I,
+    ) -> Result<Vec<Option<V>>, TransactionError<E>> {
+        keys.into_iter().map(|k| self.get(k)).collect()
+    }
+
     /// Set the value associated with the specified key to the provided value
-    pub fn set<X: Into<K>, Y: Into<V>>(
-        &self,
-        key: X,
-        value: Y,
-    ) -> Result<(), TransactionError<Error>> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(bucket = %self.name()))
+    )]
+    pub fn set<X: Into<K>, Y: Into<V>>(&self, key: X, value: Y) -> Result<(), TransactionError<E>> {
+        let key = self.key(key)?;
         let v = value
             .into()
             .to_raw_value()
-            .map_err(TransactionError::Abort)?;
-        self.0
-            .insert(key.into().to_raw_key().map_err(TransactionError::Abort)?, v)?;
+            .and_then(|v| self.crypt.encrypt(&key, v))
+            .map_err(abort_with)?;
+        self.write(&key, Some(v))?;
         Ok(())
     }
 
     /// Remove the value associated with the specified key from the database
-    pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), TransactionError<Error>> {
-        self.0
-            .remove(key.into().to_raw_key().map_err(TransactionError::Abort)?)?;
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(bucket = %self.name()))
+    )]
+    pub fn remove<X: Into<K>>(&self, key: X) -> Result<(), TransactionError<E>> {
+        self.write(&self.key(key)?, None)?;
         Ok(())
     }
 
+    /// Create an error that aborts the transaction with `error`, which is returned unchanged
+    /// from the transaction
+    pub fn abort(&self, error: E) -> TransactionError<E> {
+        TransactionError::Abort(error)
+    }
+
     /// Apply batch update
-    pub fn batch(&self, batch: Batch<K, V>) -> Result<(), TransactionError<Error>> {
-        self.0.apply_batch(batch.0)?;
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(bucket = %self.name()))
+    )]
+    pub fn batch(&self, batch: Batch<K, V>) -> Result<(), TransactionError<E>> {
+        self.apply(&batch)
+    }
+}
+
+/// Transaction counters for a `RetryPolicy`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TransactionStats {
+    /// Number of transactions started
+    pub transactions: u64,
+
+    /// Number of attempts that were retried because of a conflict
+    pub conflicts: u64,
+
+    /// Number of transactions that failed with `Error::Conflict` after too many attempts
+    pub exhausted: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    transactions: AtomicU64,
+    conflicts: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+/// Controls how transactions are retried when they conflict, used with
+/// `Bucket::transaction_with_retry`
+///
+/// By default transactions are retried immediately with no limit on the number of attempts,
+/// which is the same as `Bucket::transaction`. Clones of a policy share the same counters.
+#[derive(Clone, Default)]
+pub struct RetryPolicy {
+    max_attempts: Option<usize>,
+    backoff: Duration,
+    max_backoff: Duration,
+    counters: Arc<Counters>,
+}
+
+impl RetryPolicy {
+    /// Create a new policy with no limit on the number of attempts and no backoff
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Give up with `Error::Conflict` after `n` attempts
+    pub fn max_attempts(mut self, n: usize) -> RetryPolicy {
+        self.max_attempts = Some(n);
+        self
+    }
+
+    /// Wait before retrying, starting at `initial` and doubling after each conflict up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Get the current counters
+    pub fn stats(&self) -> TransactionStats {
+        TransactionStats {
+            transactions: self.counters.transactions.load(Ordering::Relaxed),
+            conflicts: self.counters.conflicts.load(Ordering::Relaxed),
+            exhausted: self.counters.exhausted.load(Ordering::Relaxed),
+        }
+    }
+
+    fn delay(&self, n: usize) -> Duration {
+        let n = (n - 1).min(31) as u32;
+        match self.backoff.checked_mul(1 << n) {
+            Some(d) => d.min(self.max_backoff),
+            None => self.max_backoff,
+        }
+    }
+
+    // Called before each attempt, `n` is the number of previous attempts
+    pub(crate) fn attempt<E: From<Error>>(&self, n: usize) -> Result<(), TransactionError<E>> {
+        if n == 0 {
+            self.counters.transactions.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        self.counters.conflicts.fetch_add(1, Ordering::Relaxed);
+        if let Some(max) = self.max_attempts {
+            if n >= max {
+                self.counters.exhausted.fetch_add(1, Ordering::Relaxed);
+                return Err(TransactionError::Abort(Error::Conflict(n).into()));
+            }
+        }
+
+        let d = self.delay(n);
+        if d > Duration::from_secs(0) {
+            std::thread::sleep(d);
+        }
         Ok(())
     }
 }
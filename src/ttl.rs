@@ -0,0 +1,260 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use sled::Transactional;
+
+use crate::changes;
+use crate::transaction::{stash_abort, unstash_abort};
+use crate::{Error, Raw};
+
+/// Name of the tree used to track expiration deadlines
+pub(crate) const TREE_NAME: &str = "__kv__ttl";
+
+/// Expiration keys are the length of the bucket name, the bucket name and then the key
+pub(crate) fn key(bucket: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(4 + bucket.len() + key.len());
+    dst.extend_from_slice(&(bucket.len() as u32).to_be_bytes());
+    dst.extend_from_slice(bucket);
+    dst.extend_from_slice(key);
+    dst
+}
+
+fn split_key(k: &[u8]) -> Option<(&[u8], &[u8])> {
+    if k.len() < 4 {
+        return None;
+    }
+    let mut n = [0u8; 4];
+    n.copy_from_slice(&k[..4]);
+    let n = u32::from_be_bytes(n) as usize;
+    if k.len() < 4 + n {
+        return None;
+    }
+    Some((&k[4..4 + n], &k[4 + n..]))
+}
+
+/// Skips items whose deadline has passed when iterating over a bucket
+pub(crate) struct Live {
+    ttl: sled::Tree,
+    bucket: Raw,
+}
+
+impl Live {
+    pub(crate) fn new(ttl: &sled::Tree, bucket: Raw) -> Live {
+        Live {
+            ttl: ttl.clone(),
+            bucket,
+        }
+    }
+
+    /// Call `next` until it returns an item that hasn't expired
+    pub(crate) fn next<F: FnMut() -> Option<sled::Result<(Raw, Raw)>>>(
+        &self,
+        mut next: F,
+    ) -> Option<Result<(Raw, Raw), Error>> {
+        loop {
+            let (k, v) = match next()? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.is_expired(&k) {
+                Ok(true) => (),
+                Ok(false) => return Some(Ok((k, v))),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Returns true if `k` has a deadline that has already passed
+    pub(crate) fn is_expired(&self, k: &[u8]) -> Result<bool, Error> {
+        if self.ttl.is_empty() {
+            return Ok(false);
+        }
+        match self.ttl.get(key(&self.bucket, k))? {
+            Some(d) => Ok(is_expired(&d, now()?)),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Current time in milliseconds from the Unix epoch
+pub(crate) fn now() -> Result<u64, Error> {
+    let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    Ok(ts.as_millis() as u64)
+}
+
+/// Encode the deadline for a value set now with the given TTL
+pub(crate) fn deadline(ttl: Duration) -> Result<Raw, Error> {
+    let d = now()?.saturating_add(ttl.as_millis() as u64);
+    Ok(d.to_be_bytes().as_ref().into())
+}
+
+pub(crate) fn is_expired(deadline: &[u8], now: u64) -> bool {
+    if deadline.len() != 8 {
+        return false;
+    }
+    let mut d = [0u8; 8];
+    d.copy_from_slice(deadline);
+    u64::from_be_bytes(d) <= now
+}
+
+/// Returns true if `key` has a deadline that has already passed
+pub(crate) fn check(tree: &sled::Tree, ttl: &sled::Tree, key: &[u8]) -> Result<bool, Error> {
+    if ttl.is_empty() {
+        return Ok(false);
+    }
+
+    match ttl.get(self::key(&tree.name(), key))? {
+        Some(d) => Ok(is_expired(&d, now()?)),
+        None => Ok(false),
+    }
+}
+
+/// Remove `key` and its deadline if the deadline has passed, returns true if the key was removed
+///
+/// The removal is recorded in `log`, the bucket's changefeed, when it's given
+pub(crate) fn expire(
+    tree: &sled::Tree,
+    ttl: &sled::Tree,
+    log: Option<&sled::Tree>,
+    key: &[u8],
+    now: u64,
+) -> Result<bool, Error> {
+    let name = tree.name();
+    let k = self::key(&name, key);
+    let log = match log {
+        Some(log) => log,
+        None => {
+            let res: sled::TransactionResult<bool, ()> =
+                (tree, ttl).transaction(|(tree, ttl)| match ttl.get(&k)? {
+                    Some(d) if is_expired(&d, now) => {
+                        tree.remove(key)?;
+                        ttl.remove(k.as_slice())?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                });
+
+            return match res {
+                Ok(x) => Ok(x),
+                Err(sled::TransactionError::Abort(())) => Ok(false),
+                Err(sled::TransactionError::Storage(e)) => Err(e.into()),
+            };
+        }
+    };
+
+    let error = RefCell::new(None);
+    let res = (tree, ttl, log).transaction(|(tree, ttl, log)| match ttl.get(&k)? {
+        Some(d) if is_expired(&d, now) => {
+            let old = tree.remove(key)?;
+            ttl.remove(k.as_slice())?;
+            let res = changes::record(log, &name, key, old.as_deref(), None);
+            stash_abort(&error, res.map(|()| true))
+        }
+        _ => Ok(false),
+    });
+    unstash_abort(error, res)
+}
+
+/// Remove the deadline associated with `key`, if there is one
+pub(crate) fn remove(tree: &sled::Tree, ttl: &sled::Tree, key: &[u8]) -> Result<(), Error> {
+    if !ttl.is_empty() {
+        ttl.remove(self::key(&tree.name(), key))?;
+    }
+    Ok(())
+}
+
+/// Remove all deadlines for the named bucket
+pub(crate) fn clear(ttl: &sled::Tree, bucket: &[u8]) -> Result<(), Error> {
+    for k in ttl.scan_prefix(key(bucket, b"")).keys() {
+        ttl.remove(k?)?;
+    }
+    Ok(())
+}
+
+/// Remove all expired keys, returns the number of keys removed
+///
+/// Removals are recorded in the changefeed of each bucket when `changefeed` is true
+pub(crate) fn purge(db: &sled::Db, ttl: &sled::Tree, changefeed: bool) -> Result<usize, Error> {
+    let now = now()?;
+    let mut count = 0;
+    let mut names = db.tree_names();
+
+    for item in ttl.iter() {
+        let (k, d) = item?;
+        if !is_expired(&d, now) {
+            continue;
+        }
+
+        let (bucket, key) = match split_key(&k) {
+            Some(x) => x,
+            None => {
+                ttl.remove(&k)?;
+                continue;
+            }
+        };
+
+        // Opening a bucket that was dropped would create it again, buckets created since the
+        // names were read are checked again before the deadline is discarded
+        if !names.iter().any(|x| x == bucket) {
+            names = db.tree_names();
+            if !names.iter().any(|x| x == bucket) {
+                ttl.remove(&k)?;
+                continue;
+            }
+        }
+
+        let tree = db.open_tree(bucket)?;
+        let log = match changefeed {
+            true => changes::open(db, bucket)?,
+            false => None,
+        };
+        if expire(&tree, ttl, log.as_ref(), key, now)? {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Background thread that periodically purges expired keys
+pub(crate) struct Sweeper {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub(crate) fn spawn(
+        db: sled::Db,
+        ttl: sled::Tree,
+        changefeed: bool,
+        interval: Duration,
+    ) -> Sweeper {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = stop.clone();
+        let handle = thread::spawn(move || loop {
+            thread::park_timeout(interval);
+            if stop2.load(Ordering::SeqCst) {
+                break;
+            }
+            let _ = purge(&db, &ttl, changefeed);
+        });
+
+        Sweeper {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::mem;
 use std::time::SystemTime;
 
@@ -46,10 +47,28 @@ impl<'a> Key<'a> for String {
 
 impl<'a> Key<'a> for Integer {
     fn from_raw_key(x: &Raw) -> Result<Integer, Error> {
-        Ok(Integer::from(x.as_ref()))
+        Integer::try_from(x.as_ref())
     }
 }
 
+impl<'a> Key<'a> for SignedInteger {
+    fn from_raw_key(x: &Raw) -> Result<SignedInteger, Error> {
+        SignedInteger::try_from(x.as_ref())
+    }
+}
+
+fn integer_bytes(buf: &[u8]) -> Result<[u8; 16], Error> {
+    let mut dst = [0u8; 16];
+    if buf.len() != dst.len() {
+        return Err(Error::Message(format!(
+            "Invalid integer key length {}, expected 16",
+            buf.len()
+        )));
+    }
+    dst.copy_from_slice(buf);
+    Ok(dst)
+}
+
 /// Integer key type
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Integer([u8; 16]);
@@ -120,11 +139,78 @@ impl AsRef<[u8]> for Integer {
     }
 }
 
-impl<'a> From<&'a [u8]> for Integer {
-    fn from(buf: &'a [u8]) -> Integer {
-        let mut dst = Integer::from(0u128);
-        dst.0[..16].clone_from_slice(&buf[..16]);
-        dst
+impl<'a> TryFrom<&'a [u8]> for Integer {
+    type Error = Error;
+
+    fn try_from(buf: &'a [u8]) -> Result<Integer, Error> {
+        Ok(Integer(integer_bytes(buf)?))
+    }
+}
+
+/// Signed integer key type
+///
+/// Values are stored as big-endian `i128` values with the sign bit flipped, so negative keys sort
+/// before positive ones. This isn't the same encoding as `Integer`, a bucket should only use one
+/// of them.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct SignedInteger([u8; 16]);
+
+// Flipping the sign bit of signed values makes negative numbers sort before positive ones
+const SIGN: u128 = 1 << 127;
+
+impl From<i128> for SignedInteger {
+    fn from(i: i128) -> SignedInteger {
+        SignedInteger(((i as u128) ^ SIGN).to_be_bytes())
+    }
+}
+
+impl From<i64> for SignedInteger {
+    fn from(i: i64) -> SignedInteger {
+        let i = i as i128;
+        i.into()
+    }
+}
+
+impl From<i32> for SignedInteger {
+    fn from(i: i32) -> SignedInteger {
+        let i = i as i128;
+        i.into()
+    }
+}
+
+impl From<SignedInteger> for i128 {
+    fn from(i: SignedInteger) -> i128 {
+        (u128::from_be_bytes(i.0) ^ SIGN) as i128
+    }
+}
+
+impl TryFrom<SignedInteger> for i64 {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(i: SignedInteger) -> Result<i64, Self::Error> {
+        i64::try_from(i128::from(i))
+    }
+}
+
+impl TryFrom<SignedInteger> for i32 {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(i: SignedInteger) -> Result<i32, Self::Error> {
+        i32::try_from(i128::from(i))
+    }
+}
+
+impl AsRef<[u8]> for SignedInteger {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for SignedInteger {
+    type Error = Error;
+
+    fn try_from(buf: &'a [u8]) -> Result<SignedInteger, Error> {
+        Ok(SignedInteger(integer_bytes(buf)?))
     }
 }
 
@@ -151,6 +237,40 @@ pub trait Value: Sized {
     fn from_raw_value(r: Raw) -> Result<Self, Error>;
 }
 
+/// A typed merge operator, see `Bucket::set_merge_operator`
+pub trait Merge<V: Value> {
+    /// Combine the existing value, if any, with the merged value. Returning `None` removes the
+    /// key
+    fn merge(key: &[u8], old: Option<V>, value: V) -> Option<V>;
+}
+
+pub(crate) fn merge_operator<V: Value, M: Merge<V>>(
+    key: &[u8],
+    old: Option<&[u8]>,
+    value: &[u8],
+) -> Option<Vec<u8>> {
+    // Values that can't be decoded or encoded are left unchanged
+    let unchanged = || old.map(|x| x.to_vec());
+    let prev = match old {
+        Some(x) => match V::from_raw_value(x.into()) {
+            Ok(x) => Some(x),
+            Err(_) => return unchanged(),
+        },
+        None => None,
+    };
+    let value = match V::from_raw_value(value.into()) {
+        Ok(x) => x,
+        Err(_) => return unchanged(),
+    };
+    match M::merge(key, prev, value) {
+        Some(x) => match x.to_raw_value() {
+            Ok(x) => Some(x.to_vec()),
+            Err(_) => unchanged(),
+        },
+        None => None,
+    }
+}
+
 /// Raw is an alias for `sled::IVec`
 pub type Raw = sled::IVec;
 
@@ -0,0 +1,205 @@
+use crate::crypt::Crypt;
+use crate::migrate;
+use crate::{Error, Raw, Store, Value};
+
+// Checksums saved by `Verify::save` are stored in the meta tree under this prefix followed by
+// the bucket name
+const CHECKSUM_PREFIX: &[u8] = b"checksum/";
+
+fn checksum_key(name: &[u8]) -> Vec<u8> {
+    let mut dst = CHECKSUM_PREFIX.to_vec();
+    dst.extend_from_slice(name);
+    dst
+}
+
+type Check = fn(&Crypt, &[u8], Raw) -> Result<(), Error>;
+
+fn check<V: Value>(crypt: &Crypt, key: &[u8], value: Raw) -> Result<(), Error> {
+    V::from_raw_value(crypt.decrypt(key, value)?)?;
+    Ok(())
+}
+
+/// Summary of a single bucket checked by `Verify`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketReport {
+    /// Bucket name, internal trees used by `kv` are included
+    pub name: String,
+
+    /// Number of entries read
+    pub entries: u64,
+
+    /// CRC32 checksum of all keys and values, `None` if the bucket couldn't be read completely
+    pub checksum: Option<u32>,
+
+    /// Checksum saved by the last call to `Verify::save`, `None` if it hasn't been saved
+    pub expected: Option<u32>,
+}
+
+/// An entry that couldn't be read or decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptEntry {
+    /// Bucket name
+    pub bucket: String,
+
+    /// Key of the entry, `None` when the bucket couldn't be read
+    pub key: Option<Raw>,
+
+    /// Description of the error
+    pub error: String,
+}
+
+/// Result of checking a store, returned by `Verify::run`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// Buckets that were checked
+    pub buckets: Vec<BucketReport>,
+
+    /// Entries that couldn't be read or decoded
+    pub corrupt: Vec<CorruptEntry>,
+}
+
+impl IntegrityReport {
+    /// Returns true if no corrupt entries were found
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+/// Integrity check for a store, created using `Store::verify`
+///
+/// Every key and value in every bucket is read, values in buckets registered using `decode` are
+/// also checked to make sure they can be decoded. The checksum of each bucket is compared to the
+/// one saved by `save`, which is only valid until the bucket is written again, so checksums
+/// should be saved once writes have stopped, for example before the store is closed, and checked
+/// before anything is written when it's opened again.
+pub struct Verify<'a> {
+    store: &'a Store,
+    checks: Vec<(String, Check)>,
+}
+
+impl<'a> Verify<'a> {
+    pub(crate) fn new(store: &'a Store) -> Verify<'a> {
+        Verify {
+            store,
+            checks: Vec::new(),
+        }
+    }
+
+    /// Check that all values in the named bucket can be decoded as `V`
+    pub fn decode<V: Value>(mut self, name: Option<&str>) -> Verify<'a> {
+        let name = name.unwrap_or("__sled__default").to_string();
+        self.checks.push((name, check::<V>));
+        self
+    }
+
+    /// Check every bucket in the store
+    ///
+    /// Errors reading a bucket and checksums that don't match the saved ones are included in the
+    /// report, an error is only returned if the list of buckets or the saved checksums can't be
+    /// read
+    pub fn run(self) -> Result<IntegrityReport, Error> {
+        let meta = self.store.db.open_tree(migrate::TREE_NAME)?;
+        let mut report = IntegrityReport::default();
+        for name in self.store.db.tree_names() {
+            let tree = self.store.db.open_tree(&name)?;
+            let expected = match meta.get(checksum_key(&name))? {
+                Some(x) if x.len() == 4 => Some(u32::from_be_bytes([x[0], x[1], x[2], x[3]])),
+                Some(_) => return Err(Error::Message("Invalid saved checksum".into())),
+                None => None,
+            };
+            let name = String::from_utf8_lossy(&name).into_owned();
+            let crypt = self.store.crypt.bucket(&tree.name());
+            let checks: Vec<Check> = self
+                .checks
+                .iter()
+                .filter(|x| x.0 == name)
+                .map(|x| x.1)
+                .collect();
+
+            let corrupt = |key, error: Error| CorruptEntry {
+                bucket: name.clone(),
+                key,
+                error: error.to_string(),
+            };
+
+            let mut entries = 0;
+            let mut complete = true;
+            for item in tree.iter() {
+                let (k, v) = match item {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // sled iterators can't continue past an error
+                        report.corrupt.push(corrupt(None, e.into()));
+                        complete = false;
+                        break;
+                    }
+                };
+                entries += 1;
+                for f in &checks {
+                    if let Err(e) = f(&crypt, &k, v.clone()) {
+                        report.corrupt.push(corrupt(Some(k.clone()), e));
+                    }
+                }
+            }
+
+            let checksum = if complete {
+                match tree.checksum() {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        report.corrupt.push(corrupt(None, e.into()));
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            if let (Some(a), Some(b)) = (expected, checksum) {
+                if a != b {
+                    report.corrupt.push(CorruptEntry {
+                        bucket: name.clone(),
+                        key: None,
+                        error: format!("Checksum is {:08x}, expected {:08x}", b, a),
+                    });
+                }
+            }
+
+            report.buckets.push(BucketReport {
+                name,
+                entries,
+                checksum,
+                expected,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Check every bucket like `run`, then save the checksum of every bucket that could be read
+    /// completely
+    ///
+    /// Saved checksums replace the ones from the previous call, the returned report is compared
+    /// against the previous ones. The meta tree isn't included since saving the checksums
+    /// changes it.
+    pub fn save(self) -> Result<IntegrityReport, Error> {
+        let db = self.store.db.clone();
+        let report = self.run()?;
+
+        let meta = db.open_tree(migrate::TREE_NAME)?;
+        let mut batch = sled::Batch::default();
+        for k in meta.scan_prefix(CHECKSUM_PREFIX).keys() {
+            batch.remove(k?);
+        }
+        for bucket in report
+            .buckets
+            .iter()
+            .filter(|x| x.name != migrate::TREE_NAME)
+        {
+            if let Some(x) = bucket.checksum {
+                batch.insert(checksum_key(bucket.name.as_bytes()), &x.to_be_bytes());
+            }
+        }
+        meta.apply_batch(batch)?;
+        meta.flush()?;
+        Ok(report)
+    }
+}